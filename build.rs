@@ -1,4 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/raptorboost.proto")?;
+    tonic_build::configure()
+        // chunk data is sliced out of the client's read buffer without copying
+        .bytes([".raptorboost.FileData.data"])
+        .compile_protos(
+            &["proto/raptorboost.proto", "proto/health.proto"],
+            &["proto"],
+        )?;
     Ok(())
 }
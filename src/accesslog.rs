@@ -0,0 +1,189 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Status};
+use tracing::warn;
+
+/// A log of every RPC clients make, one line each, for log tools rather than
+/// people. Separate from the tracing output, which is for debugging. Lines
+/// follow Apache's common log format as far as it fits:
+///
+/// `<peer> - - [<time>] "<rpc> <sha256sum>" <status> <bytes> <microseconds>`
+///
+/// where the checksum is `-` for RPCs that aren't about one file, and the
+/// status is the gRPC code's name, or how the file went for an upload.
+pub struct AccessLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    // the open log, with how much is in it
+    file: Mutex<(File, u64)>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            path: path.to_path_buf(),
+            max_size: None,
+            file: Mutex::new(open_append(path)?),
+        })
+    }
+
+    /// Once the log would grow past `max_size`, move it aside to `<path>.1`,
+    /// replacing whatever was there, and start a new one.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Start writing to whatever's at the log's path now, for after
+    /// something like logrotate has moved it.
+    pub fn reopen(&self) -> io::Result<()> {
+        *self.file.lock().unwrap() = open_append(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Some(max_size) = self.max_size
+            && file.1 > 0
+            && file.1 + line.len() as u64 > max_size
+        {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            match fs::rename(&self.path, &rotated).and_then(|()| open_append(&self.path)) {
+                Ok(reopened) => *file = reopened,
+                Err(e) => warn!("couldn't rotate {}: {}", self.path.display(), e),
+            }
+        }
+        match file.0.write_all(line.as_bytes()) {
+            Ok(()) => file.1 += line.len() as u64,
+            Err(e) => warn!("couldn't write to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
+
+/// One line of the access log, written when it's dropped. Until a status is
+/// set it's logged as cancelled, which is what happened if the RPC was
+/// dropped before it finished. Without a log this does nothing.
+pub struct Access {
+    log: Option<Arc<AccessLog>>,
+    rpc: &'static str,
+    peer: Option<SocketAddr>,
+    time: DateTime<Local>,
+    started: Instant,
+    sha256sum: Option<String>,
+    bytes: u64,
+    status: String,
+    failed: bool,
+}
+
+impl Access {
+    pub fn new(log: Option<Arc<AccessLog>>, rpc: &'static str, peer: Option<SocketAddr>) -> Access {
+        Access {
+            log,
+            rpc,
+            peer,
+            time: Local::now(),
+            started: Instant::now(),
+            sha256sum: None,
+            bytes: 0,
+            status: format!("{:?}", Code::Cancelled),
+            failed: false,
+        }
+    }
+
+    /// A line of its own for one of the files this RPC carries, logged as
+    /// incomplete unless it's given another status.
+    pub fn file(&self, sha256sum: &str) -> Access {
+        let mut access = Access::new(self.log.clone(), self.rpc, self.peer);
+        access.set_sha256sum(sha256sum);
+        access.set_status("Incomplete");
+        access
+    }
+
+    pub fn set_sha256sum(&mut self, sha256sum: &str) {
+        self.sha256sum = Some(sha256sum.to_string());
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    pub fn set_status(&mut self, status: impl Into<String>) {
+        self.status = status.into();
+    }
+
+    /// Log how `result` went and hand it back.
+    pub fn finish<T>(mut self, result: Result<T, Status>) -> Result<T, Status> {
+        self.record(result.as_ref().err());
+        result
+    }
+
+    /// Log a response stream once it's been sent: as OK if it all went out,
+    /// or with the first error in it.
+    pub fn finish_stream<T, S>(mut self, stream: S) -> impl Stream<Item = Result<T, Status>>
+    where
+        S: Stream<Item = Result<T, Status>>,
+    {
+        stream
+            .map(Some)
+            .chain(tokio_stream::once(None))
+            .filter_map(move |item| match item {
+                Some(result) => {
+                    if let Err(status) = &result {
+                        self.record(Some(status));
+                    }
+                    Some(result)
+                }
+                None => {
+                    self.record(None);
+                    None
+                }
+            })
+    }
+
+    // only the first error is kept, as later ones tend to follow from it
+    fn record(&mut self, error: Option<&Status>) {
+        if self.failed {
+            return;
+        }
+        self.failed = error.is_some();
+        let code = error.map_or(Code::Ok, Status::code);
+        self.set_status(format!("{:?}", code));
+    }
+}
+
+impl Drop for Access {
+    fn drop(&mut self) {
+        let Some(log) = &self.log else {
+            return;
+        };
+        // a unix socket has no peer address
+        let peer = match self.peer {
+            Some(peer) => peer.ip().to_string(),
+            None => "-".to_string(),
+        };
+        log.write(&format!(
+            "{} - - [{}] \"{} {}\" {} {} {}\n",
+            peer,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.rpc,
+            self.sha256sum.as_deref().unwrap_or("-"),
+            self.status,
+            self.bytes,
+            self.started.elapsed().as_micros()
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::MultiGzDecoder;
+use tar::EntryType;
+
+/// What a tar entry is. Anything that isn't a regular file, directory or
+/// link keeps its type flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    HardLink,
+    Symlink,
+    Other(u8),
+}
+
+/// What the client needs of an entry's header, with any GNU long name or pax
+/// path, link path and size already applied.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Seconds since the unix epoch.
+    pub mtime: i64,
+    /// The target of a hard or symbolic link.
+    pub link: Option<String>,
+}
+
+impl Entry {
+    pub fn new<R: Read>(entry: &tar::Entry<'_, R>) -> io::Result<Entry> {
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Link => EntryKind::HardLink,
+            EntryType::Symlink => EntryKind::Symlink,
+            EntryType::Directory => EntryKind::Dir,
+            other => EntryKind::Other(other.as_byte()),
+        };
+        Ok(Entry {
+            path: entry.path()?.to_string_lossy().into_owned(),
+            kind,
+            size: entry.size(),
+            mode: header.mode()?,
+            mtime: header.mtime()? as i64,
+            link: entry
+                .link_name()?
+                .map(|link| link.to_string_lossy().into_owned()),
+        })
+    }
+
+    /// The mtime as the filesystem would report it once extracted.
+    pub fn modified(&self) -> SystemTime {
+        match u64::try_from(self.mtime) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(self.mtime.unsigned_abs()),
+        }
+    }
+}
+
+/// Open a tar archive, decompressing it first if it starts like a gzip or
+/// zstd stream. Its entries are read in order with `entries()`, each one
+/// readable for its data.
+pub fn open(reader: impl Read + Send + 'static) -> io::Result<tar::Archive<Box<dyn Read + Send>>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    let reader: Box<dyn Read + Send> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Cursor, Write};
+
+    use tar::{Builder, Header};
+
+    /// Every entry of `archive`, with its data.
+    fn entries(archive: Vec<u8>) -> Vec<(Entry, Vec<u8>)> {
+        let mut archive = open(Cursor::new(archive)).unwrap();
+        archive
+            .entries()
+            .unwrap()
+            .map(|data| {
+                let mut data = data.unwrap();
+                let entry = Entry::new(&data).unwrap();
+                let mut contents = Vec::new();
+                data.read_to_end(&mut contents).unwrap();
+                (entry, contents)
+            })
+            .collect()
+    }
+
+    fn header(kind: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(size);
+        header.set_mode(0o640);
+        header.set_mtime(1_700_000_000);
+        header
+    }
+
+    #[test]
+    fn reads_files_dirs_and_links() {
+        let long_name = format!("{}/file", "d".repeat(150));
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(&mut header(EntryType::Directory, 0), "dir/", io::empty())
+            .unwrap();
+        builder
+            .append_data(&mut header(EntryType::Regular, 5), "dir/a", &b"hello"[..])
+            .unwrap();
+        // past the 100 bytes a plain header has room for
+        builder
+            .append_data(&mut header(EntryType::Regular, 3), &long_name, &b"abc"[..])
+            .unwrap();
+        builder
+            .append_link(&mut header(EntryType::Symlink, 0), "dir/s", "a")
+            .unwrap();
+        builder
+            .append_link(&mut header(EntryType::Link, 0), "dir/h", "dir/a")
+            .unwrap();
+        let entries = entries(builder.into_inner().unwrap());
+
+        let summary: Vec<(&str, EntryKind, Option<&str>, &[u8])> = entries
+            .iter()
+            .map(|(e, data)| (e.path.as_str(), e.kind, e.link.as_deref(), &data[..]))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("dir/", EntryKind::Dir, None, &b""[..]),
+                ("dir/a", EntryKind::File, None, b"hello"),
+                (long_name.as_str(), EntryKind::File, None, b"abc"),
+                ("dir/s", EntryKind::Symlink, Some("a"), b""),
+                ("dir/h", EntryKind::HardLink, Some("dir/a"), b""),
+            ]
+        );
+        let (a, _) = &entries[1];
+        assert_eq!((a.size, a.mode, a.mtime), (5, 0o640, 1_700_000_000));
+    }
+
+    #[test]
+    fn pax_records_override_the_header() {
+        // as written for members too big for the header's size field or
+        // with paths too long for its name
+        let path = format!("{}/file", "p".repeat(200));
+        let data = b"contents past a zero size";
+        let mut records = Vec::new();
+        for (key, value) in [("path", path.clone()), ("size", data.len().to_string())] {
+            // the length counts itself, the space, the `=` and the newline
+            let rest = key.len() + value.len() + 3;
+            let len = (rest + 1..)
+                .find(|n| n.to_string().len() + rest == *n)
+                .unwrap();
+            records.extend_from_slice(format!("{} {}={}\n", len, key, value).as_bytes());
+        }
+        let mut builder = Builder::new(Vec::new());
+        let mut pax = Header::new_ustar();
+        pax.set_entry_type(EntryType::XHeader);
+        pax.set_size(records.len() as u64);
+        pax.set_cksum();
+        builder.append(&pax, &records[..]).unwrap();
+        let mut header = header(EntryType::Regular, 0);
+        header.set_path("placeholder").unwrap();
+        header.set_cksum();
+        builder.append(&header, &b""[..]).unwrap();
+        let mut archive = builder.into_inner().unwrap();
+        // the data the pax size says is there, padded to a whole block
+        archive.truncate(archive.len() - 1024);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(512) + 1024, 0);
+
+        let entries = entries(archive);
+        assert_eq!(entries.len(), 1);
+        let (entry, contents) = &entries[0];
+        assert_eq!(entry.path, path);
+        assert_eq!(entry.size, data.len() as u64);
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn compressed_archives_are_detected() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(&mut header(EntryType::Regular, 5), "a", &b"hello"[..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&tar).unwrap();
+        let zstd = zstd::encode_all(&tar[..], 1).unwrap();
+
+        for archive in [tar.clone(), gzip.finish().unwrap(), zstd] {
+            let entries = entries(archive);
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].0.path, "a");
+            assert_eq!(entries[0].1, b"hello");
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Pick the token from `token` or else the first line of `token_file`, so it
+/// doesn't have to appear on the command line.
+pub fn read_token(token: Option<String>, token_file: Option<&Path>) -> io::Result<Option<String>> {
+    if token.is_some() {
+        return Ok(token);
+    }
+    let Some(path) = token_file else {
+        return Ok(None);
+    };
+    let contents = fs::read_to_string(path)?;
+    let token = contents.lines().next().unwrap_or("").trim();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is empty", path.display()),
+        ));
+    }
+    Ok(Some(token.to_string()))
+}
+
+/// Rejects requests whose `authorization` header isn't `Bearer <token>`.
+/// Without a token every request is let through.
+#[derive(Clone)]
+pub struct TokenAuth {
+    token: Option<Arc<str>>,
+}
+
+impl TokenAuth {
+    pub fn new(token: Option<String>) -> TokenAuth {
+        TokenAuth {
+            token: token.map(Arc::from),
+        }
+    }
+}
+
+impl Interceptor for TokenAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+                Ok(request)
+            }
+            Some(_) => Err(Status::unauthenticated("invalid token")),
+            None => Err(Status::unauthenticated("missing token")),
+        }
+    }
+}
+
+// don't leak how much of the token matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,2419 @@
+#![allow(clippy::result_large_err)]
+
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::Ipv6Addr;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, TimeZone};
+use clap::{Parser, ValueEnum};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
+use raptorboost::archive::{self, EntryKind};
+use raptorboost::auth::read_token;
+use raptorboost::client::{
+    CheckedFile, Client, FilenameWithState, GRPC_MAX_MESSAGE_SIZE, HashedFile, SendEvent,
+    SendFileError, SendOptions, file_attributes, hash_file, is_compatible_version, parse_byte_size,
+};
+use raptorboost::hash::HashAlgo;
+use raptorboost::hashcache::{HashCache, default_cache_dir};
+use raptorboost::manifest::{self, ManifestFormat};
+use raptorboost::plan::{self, Plan, PlannedFile};
+use raptorboost::progress::{self, ProgressMode};
+use raptorboost::proto::raptor_boost_client::RaptorBoostClient;
+use raptorboost::proto::{
+    AssignNameStatus, FileAttributes, GetStatsResponse, HistoryEntry, ListedFile, Symlink,
+};
+use raptorboost::ratelimit::{ByteCap, RateLimiter};
+use raptorboost::walk::WalkFilter;
+use raptorboost::{logging, units};
+use rayon::prelude::*;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tonic::Code;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::http::uri::{PathAndQuery, Scheme};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use tracing::{debug, warn};
+
+/// `println!`, but only when the output is meant for people rather than
+/// for parsing.
+macro_rules! say {
+    ($human:expr) => {
+        if $human {
+            println!()
+        }
+    };
+    ($human:expr, $($arg:tt)*) => {
+        if $human {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// What rbc exits with when a transfer is stopped with ctrl-c, as a shell
+/// would report a process killed by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How many of the files the server has asked for may wait on the upload
+/// before the check stops reading its answers.
+const CHECKED_AHEAD: usize = 1024;
+
+fn print_file_list(files: &[ListedFile], json: bool) {
+    if json {
+        for f in files {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "sha256sum": f.sha256sum,
+                    "size": f.size,
+                    "names": f.names,
+                })
+            );
+        }
+        return;
+    }
+
+    println!("{:<64}  {:>14}  NAMES", "SHA256SUM", "SIZE");
+    for f in files {
+        let names = if f.names.is_empty() {
+            "-".to_string()
+        } else {
+            f.names.join(", ")
+        };
+        println!("{:<64}  {:>14}  {}", f.sha256sum, f.size, names);
+    }
+}
+
+fn print_history(entries: &[HistoryEntry], json: bool) {
+    if json {
+        for e in entries {
+            let mut entry = serde_json::json!({
+                "time": e.time,
+                "sha256sum": e.sha256sum,
+                "size": e.size,
+            });
+            if let Some(transfer) = &e.transfer {
+                entry["transfer"] = serde_json::json!(transfer);
+                entry["names"] = serde_json::json!(e.names);
+            }
+            println!("{}", entry);
+        }
+        return;
+    }
+
+    println!("{:<19}  {:<64}  {:>14}  EVENT", "TIME", "SHA256SUM", "SIZE");
+    for e in entries {
+        let time = Local
+            .timestamp_opt(e.time, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| e.time.to_string());
+        let event = match &e.transfer {
+            None => "received".to_string(),
+            Some(transfer) => format!("named in {}: {}", transfer, e.names.join(", ")),
+        };
+        println!(
+            "{:<19}  {:<64}  {:>14}  {}",
+            time, e.sha256sum, e.size, event
+        );
+    }
+}
+
+fn print_stats(stats: &GetStatsResponse, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "complete_free_bytes": stats.complete_free_bytes,
+                "partial_free_bytes": stats.partial_free_bytes,
+                "complete_files": stats.complete_files,
+                "complete_bytes": stats.complete_bytes,
+                "active_locks": stats.active_locks,
+            })
+        );
+        return;
+    }
+
+    let free = |bytes: Option<u64>| match bytes {
+        Some(bytes) => HumanBytes(bytes).to_string(),
+        None => "unknown".to_string(),
+    };
+    println!(
+        "stored:          {} files, {}",
+        stats.complete_files,
+        HumanBytes(stats.complete_bytes)
+    );
+    println!("free (complete): {}", free(stats.complete_free_bytes));
+    println!("free (partial):  {}", free(stats.partial_free_bytes));
+    println!("active locks:    {}", stats.active_locks);
+}
+
+/// Read a list of paths separated by `delim` from `path`, or stdin if it's `-`.
+fn read_file_list(path: &str, delim: u8) -> Result<Vec<String>, MainError> {
+    let mut buf = Vec::new();
+    let res = if path == "-" {
+        io::stdin().read_to_end(&mut buf)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_end(&mut buf))
+    };
+    res.map_err(|e| MainError(format!("couldn't read file list '{}': {}", path, e)))?;
+
+    buf.split(|&b| b == delim)
+        .map(|line| match delim {
+            b'\n' => line.strip_suffix(b"\r").unwrap_or(line),
+            _ => line,
+        })
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            String::from_utf8(line.to_vec()).map_err(|_| {
+                MainError(format!(
+                    "file list '{}' contains a path that isn't valid utf-8",
+                    path
+                ))
+            })
+        })
+        .collect()
+}
+
+fn print_plan(to_send: &[FilenameWithState], total_to_send: u64, num_files_up_to_date: u64) {
+    println!();
+    for file in to_send {
+        if !file.partial {
+            println!("  send    {}", file.filename);
+        } else {
+            println!("  resume  {} (from byte {})", file.filename, file.offset);
+        }
+    }
+    println!(
+        "{} files would be transferred ({} bytes)",
+        to_send.len(),
+        total_to_send
+    );
+    println!("{} files are already up to date", num_files_up_to_date);
+}
+
+fn send_event_json(event: &SendEvent) -> serde_json::Value {
+    match event {
+        SendEvent::FileStarted {
+            filename,
+            sha256sum,
+            offset,
+            size,
+        } => serde_json::json!({
+            "event": "file_started",
+            "filename": filename,
+            "sha256sum": sha256sum,
+            "offset": offset,
+            "size": size,
+        }),
+        SendEvent::BytesSent {
+            filename,
+            bytes,
+            position,
+        } => serde_json::json!({
+            "event": "bytes_sent",
+            "filename": filename,
+            "bytes": bytes,
+            "position": position,
+        }),
+        SendEvent::FileSent {
+            filename,
+            sha256sum,
+        } => serde_json::json!({
+            "event": "file_sent",
+            "filename": filename,
+            "sha256sum": sha256sum,
+        }),
+    }
+}
+
+fn confirm(prompt: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct MainError(String);
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, MainError> {
+    std::fs::read(path).map_err(|e| MainError(format!("couldn't read '{}': {}", path.display(), e)))
+}
+
+/// Dial the server over a unix socket. The endpoint's uri is only used for
+/// request headers; every connection goes to `path`.
+#[cfg(unix)]
+async fn connect_unix(endpoint: Endpoint, path: PathBuf) -> Result<Channel, MainError> {
+    use hyper_util::rt::TokioIo;
+    use tokio::net::UnixStream;
+
+    endpoint
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            async move { Ok::<_, io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+        }))
+        .await
+        .map_err(|e| MainError(format!("couldn't connect: {}", e)))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_endpoint: Endpoint, _path: PathBuf) -> Result<Channel, MainError> {
+    Err(MainError(
+        "unix sockets aren't supported on this platform".to_string(),
+    ))
+}
+
+/// A server to send to: the one named by the positional host (or
+/// --unix-socket, or --endpoint) and --port, or one added with --to.
+#[derive(Clone)]
+struct Server {
+    host: Option<String>,
+    port: u16,
+    unix_socket: Option<PathBuf>,
+    endpoint: Option<Uri>,
+    origin: Option<Uri>,
+}
+
+impl Server {
+    fn primary(args: &Args) -> Server {
+        Server {
+            host: args.host.clone(),
+            port: args.port,
+            unix_socket: args.unix_socket.clone(),
+            endpoint: args.endpoint.clone(),
+            origin: args.origin.clone(),
+        }
+    }
+
+    /// Parse a --to address: `host`, `host:port`, `[v6 address]:port` or a
+    /// bare v6 address. Without a port it's `default_port`.
+    fn parse(addr: &str, default_port: u16) -> Result<Server, MainError> {
+        let bad = || MainError(format!("bad server address '{}'", addr));
+        let (host, port) = match addr.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']').ok_or_else(bad)? {
+                (host, "") => (host, None),
+                (host, port) => (host, Some(port.strip_prefix(':').ok_or_else(bad)?)),
+            },
+            // a bare v6 address has colons of its own
+            None if addr.parse::<Ipv6Addr>().is_ok() => (addr, None),
+            None => match addr.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (addr, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(bad());
+        }
+        Ok(Server {
+            host: Some(host.to_string()),
+            port: match port {
+                Some(port) => port.parse().map_err(|_| bad())?,
+                None => default_port,
+            },
+            unix_socket: None,
+            endpoint: None,
+            origin: None,
+        })
+    }
+}
+
+/// Parse an --endpoint uri, which has to say how to reach the server on its
+/// own: http or https, a host, and maybe a path the server's behind.
+fn parse_endpoint(endpoint: &str) -> Result<Uri, String> {
+    let uri: Uri = endpoint
+        .parse()
+        .map_err(|e| format!("{} (expected e.g. https://proxy.example/raptorboost)", e))?;
+    match uri.scheme_str() {
+        Some("http" | "https") => {}
+        Some(scheme) => {
+            return Err(format!(
+                "unsupported scheme '{}', use http or https",
+                scheme
+            ));
+        }
+        None => {
+            return Err(format!(
+                "missing scheme, did you mean https://{}?",
+                endpoint
+            ));
+        }
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err("missing host".to_string());
+    }
+    if uri.query().is_some() {
+        return Err("a query string can't be sent with grpc requests".to_string());
+    }
+    // requests are sent to the path followed by /<service>/<method>, so a
+    // trailing slash would double up
+    let path = uri.path().trim_end_matches('/').to_string();
+    let mut parts = uri.into_parts();
+    parts.path_and_query = Some(
+        if path.is_empty() { "/" } else { &path }
+            .parse()
+            .expect("a prefix of a valid path is valid"),
+    );
+    Ok(Uri::from_parts(parts).expect("only the path changed"))
+}
+
+/// Parse an --origin: a `host[:port]`, or a uri with a scheme and no path.
+fn parse_origin(origin: &str) -> Result<Uri, String> {
+    let uri: Uri = origin.parse().map_err(|e| format!("{}", e))?;
+    if uri.host().is_none_or(str::is_empty) {
+        return Err("missing host".to_string());
+    }
+    if uri.path_and_query().is_some_and(|p| p != "/") {
+        return Err("an origin can't have a path, put it in --endpoint".to_string());
+    }
+    Ok(uri)
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(endpoint) = &self.endpoint {
+            return write!(f, "{}", endpoint);
+        }
+        match (&self.unix_socket, &self.host) {
+            (Some(path), _) => write!(f, "{}", path.display()),
+            (None, Some(host)) if host.parse::<Ipv6Addr>().is_ok() => {
+                write!(f, "[{}]:{}", host, self.port)
+            }
+            (None, host) => write!(
+                f,
+                "{}:{}",
+                host.as_deref().unwrap_or("localhost"),
+                self.port
+            ),
+        }
+    }
+}
+
+async fn connect(args: &Args, server: &Server) -> Result<Client, MainError> {
+    let scheme = if args.tls { "https" } else { "http" };
+    let host = match (&server.unix_socket, &server.host) {
+        (Some(_), _) | (None, None) => "localhost".to_string(),
+        // a bare ipv6 address needs brackets to be used in a uri
+        (None, Some(host)) if host.parse::<Ipv6Addr>().is_ok() => format!("[{}]", host),
+        (None, Some(host)) => host.clone(),
+    };
+    let mut endpoint = match &server.endpoint {
+        Some(uri) => Endpoint::from(uri.clone()),
+        None => Endpoint::from_shared(format!("{}://{}:{}", scheme, host, server.port))
+            .map_err(|e| MainError(format!("invalid server address: {}", e)))?,
+    }
+    .connect_timeout(args.connect_timeout);
+    // what a reverse proxy in front of the server sees as the :authority
+    // (Host) of each request, when it routes on something other than the
+    // address it's reached at
+    if let Some(origin) = &server.origin {
+        let mut parts = origin.clone().into_parts();
+        if parts.scheme.is_none() {
+            parts.scheme = Some(endpoint.uri().scheme().cloned().unwrap_or(Scheme::HTTP));
+        }
+        parts.path_and_query = Some(PathAndQuery::from_static("/"));
+        endpoint = endpoint.origin(
+            Uri::from_parts(parts)
+                .map_err(|e| MainError(format!("invalid origin {}: {}", origin, e)))?,
+        );
+    }
+    if !args.keepalive_interval.is_zero() {
+        endpoint = endpoint
+            .http2_keep_alive_interval(args.keepalive_interval)
+            .keep_alive_timeout(args.keepalive_timeout)
+            .keep_alive_while_idle(true);
+    }
+
+    if args.tls {
+        let mut tls_config = ClientTlsConfig::new();
+        tls_config = match &args.ca_cert {
+            Some(ca_cert) => tls_config.ca_certificate(Certificate::from_pem(read_pem(ca_cert)?)),
+            None => tls_config.with_native_roots(),
+        };
+        // a client certificate turns this into mutual tls
+        if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+            tls_config = tls_config.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        }
+        endpoint = endpoint
+            .tls_config(tls_config)
+            .map_err(|e| MainError(format!("invalid tls config: {}", e)))?;
+    }
+
+    let channel = match &server.unix_socket {
+        Some(path) => connect_unix(endpoint, path.clone()).await?,
+        None => endpoint
+            .connect()
+            .await
+            .map_err(|e| MainError(format!("couldn't connect: {}", e)))?,
+    };
+
+    // the channel only keeps the endpoint's scheme and host, so a path the
+    // server's behind has to be given to the client to put before each method
+    let client = match &server.endpoint {
+        Some(uri) => RaptorBoostClient::with_origin(channel, uri.clone()),
+        None => RaptorBoostClient::new(channel),
+    };
+    let with_auth = |client| -> Result<Client, MainError> {
+        let mut client = Client::new(client);
+        if let Some(timeout) = args.request_timeout {
+            client = client.with_timeout(timeout);
+        }
+        match &args.token {
+            Some(token) => client
+                .with_token(token)
+                .map_err(|_| MainError("token contains invalid characters".to_string())),
+            None => Ok(client),
+        }
+    };
+
+    let (client, server_version) = match args.compress {
+        None => {
+            let mut client = with_auth(client)?;
+            let version = client.get_version().await;
+            (client, version)
+        }
+        Some(compress) => {
+            let encoding = match compress {
+                Compression::Gzip => CompressionEncoding::Gzip,
+                Compression::Zstd => CompressionEncoding::Zstd,
+            };
+
+            // servers without support reject compressed requests outright, so
+            // probe with a cheap call first and fall back to sending uncompressed
+            let mut compressed = with_auth(client.clone().send_compressed(encoding))?;
+            match compressed.get_version().await {
+                Err(e) if e.code() == Code::Unimplemented => {
+                    warn!(
+                        "server doesn't support {} compression, sending uncompressed",
+                        compress
+                    );
+                    let mut client = with_auth(client)?;
+                    let version = client.get_version().await;
+                    (client, version)
+                }
+                version => (compressed, version),
+            }
+        }
+    };
+
+    let server_version =
+        server_version.map_err(|e| MainError(format!("couldn't connect: {}", e.message())))?;
+    let client_version = env!("CARGO_PKG_VERSION");
+    match is_compatible_version(client_version, &server_version) {
+        Ok(true) => {}
+        Ok(false) | Err(_) if args.ignore_version => warn!(
+            "server version {} may not be compatible with client version {}",
+            server_version, client_version
+        ),
+        Ok(false) => {
+            return Err(MainError(format!(
+                "server version {} is incompatible with client version {} (use --ignore-version to try anyway)",
+                server_version, client_version
+            )));
+        }
+        Err(e) => {
+            return Err(MainError(format!(
+                "couldn't parse server version '{}': {} (use --ignore-version to try anyway)",
+                server_version, e
+            )));
+        }
+    }
+
+    Ok(client)
+}
+
+/// Connect to every server at once, returning how each went in the order
+/// they're given.
+async fn connect_all(args: &Arc<Args>, servers: &[Server]) -> Vec<Result<Client, MainError>> {
+    let mut connecting = tokio::task::JoinSet::new();
+    for (i, server) in servers.iter().enumerate() {
+        let args = args.clone();
+        let server = server.clone();
+        connecting.spawn(async move { (i, connect(&args, &server).await) });
+    }
+    let mut clients: Vec<Option<Result<Client, MainError>>> =
+        servers.iter().map(|_| None).collect();
+    while let Some(joined) = connecting.join_next().await {
+        match joined {
+            Ok((i, client)) => clients[i] = Some(client),
+            Err(e) => warn!("connect task failed: {}", e),
+        }
+    }
+    clients
+        .into_iter()
+        .map(|c| c.unwrap_or_else(|| Err(MainError("couldn't connect".to_string()))))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    #[arg(long, short, default_value = "7272")]
+    port: u16,
+    #[arg(short, long)]
+    name: Option<String>,
+    #[arg(long, action, help = "don't sort files by size")]
+    no_sort: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with_all = ["no_sort", "from_tar"],
+        help = "send the smallest files first, so one huge file doesn't hold up all the others"
+    )]
+    small_first: bool,
+    #[arg(long, action)]
+    force_unlock: bool,
+    #[arg(long, action, default_value = "false")]
+    force_name: bool,
+    #[arg(
+        long,
+        action,
+        help = "don't send file permissions and modification times to the server"
+    )]
+    no_perms: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "write every name assigned and its checksum to this file, for checking the received tree with e.g. sha256sum -c"
+    )]
+    manifest_out: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ManifestFormat::Text,
+        requires = "manifest_out",
+        help = "format of --manifest-out"
+    )]
+    manifest_format: ManifestFormat,
+    #[arg(
+        long,
+        action,
+        help = "have the server write a manifest of the names next to the transfer dir too"
+    )]
+    manifest_on_server: bool,
+    #[arg(
+        long,
+        action,
+        help = "abort the whole run on the first file that fails to send"
+    )]
+    fail_fast: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "dry_run",
+        help = "after sending, ask the server again about every file sent and fail any it doesn't have complete"
+    )]
+    verify_remote: bool,
+    #[arg(
+        long,
+        action,
+        help = "abort on the first file or directory that can't be read instead of skipping it"
+    )]
+    strict: bool,
+    #[arg(long, default_value = "8192", value_parser = parse_byte_size, help = "size of each data chunk sent (e.g. 64K, 1M)")]
+    chunk_size: usize,
+    #[arg(long, value_name = "RATE", value_parser = parse_byte_size, help = "limit upload bandwidth in bytes per second (e.g. 500K, 5M)")]
+    bwlimit: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "stop starting new files once this much has been sent, across all servers (e.g. 10G); the file in flight is finished and a rerun resumes the rest"
+    )]
+    max_bytes: Option<usize>,
+    #[arg(
+        short,
+        long,
+        help = "number of files to checksum in parallel [default: number of cpus]"
+    )]
+    jobs: Option<NonZeroUsize>,
+    #[arg(long, default_value = "10s", value_parser = units::parse_duration, help = "give up connecting to the server after this long")]
+    connect_timeout: Duration,
+    #[arg(long, value_parser = units::parse_duration, help = "give up on a request the server hasn't answered in this long; uploads time out after this long without progress")]
+    request_timeout: Option<Duration>,
+    #[arg(long, default_value = "30s", value_parser = units::parse_duration, help = "ping the server this often so idle connections aren't dropped by nat or firewalls (0 disables)")]
+    keepalive_interval: Duration,
+    #[arg(long, default_value = "20s", value_parser = units::parse_duration, help = "consider the connection dead when a ping goes unanswered this long")]
+    keepalive_timeout: Duration,
+    #[arg(
+        long,
+        help = "where hashes of unchanged files are remembered between runs [default: ~/.cache/raptorboost]"
+    )]
+    cache_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "cache_dir",
+        help = "rehash every file instead of trusting cached hashes"
+    )]
+    no_cache: bool,
+    #[arg(
+        long,
+        action,
+        help = "memory-map large files to hash them instead of reading them; a file found shorter \
+                is read instead, but one truncated while it's being hashed still kills rbc with SIGBUS"
+    )]
+    mmap: bool,
+    #[arg(long, action, help = "connect to the server using tls")]
+    tls: bool,
+    #[arg(
+        long,
+        requires = "tls",
+        help = "ca certificate to verify the server with [default: system roots]"
+    )]
+    ca_cert: Option<PathBuf>,
+    #[arg(long, requires_all = ["tls", "key"], help = "client certificate for mutual tls")]
+    cert: Option<PathBuf>,
+    #[arg(long, requires_all = ["tls", "cert"], help = "client private key for mutual tls")]
+    key: Option<PathBuf>,
+    #[arg(
+        long,
+        action,
+        help = "delete files from the server that aren't part of this transfer"
+    )]
+    delete: bool,
+    #[arg(
+        long,
+        action,
+        requires = "delete",
+        help = "don't ask for confirmation before deleting"
+    )]
+    force: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "files",
+        help = "list the files stored on the server"
+    )]
+    list: bool,
+    #[arg(
+        long,
+        value_name = "SHA256SUM",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["files", "list"],
+        help = "show when files were received and what they were named, optionally for one file (--history=SHA256SUM)"
+    )]
+    history: Option<Option<String>>,
+    #[arg(
+        long,
+        action,
+        conflicts_with_all = ["files", "list", "history"],
+        help = "show how full the server's store is and how much free space it has"
+    )]
+    stats: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "dry_run",
+        help = "print machine-readable json: newline-delimited events and a final summary when uploading"
+    )]
+    json: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "list",
+        help = "show what would be transferred without sending anything"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "skip files and directories matching this pattern (repeatable)"
+    )]
+    exclude: Vec<String>,
+    #[arg(
+        long,
+        action,
+        help = "skip files ignored by .gitignore files found while walking directories"
+    )]
+    use_gitignore: bool,
+    #[arg(
+        long,
+        action,
+        help = "send the targets of symlinks instead of skipping them"
+    )]
+    follow_symlinks: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "follow_symlinks",
+        help = "recreate symlinks on the server as links to the same relative targets, instead of skipping them; targets outside the transfer are refused"
+    )]
+    preserve_symlinks: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "descend at most this many directories below each one given; directories at the limit are sent empty"
+    )]
+    max_depth: Option<NonZeroUsize>,
+    #[arg(
+        long,
+        short = 'x',
+        action,
+        help = "don't descend into directories on other filesystems than the one given, such as mounts under it"
+    )]
+    one_file_system: bool,
+    #[arg(
+        long,
+        default_value = "3",
+        help = "number of times to reconnect and resume after a dropped connection"
+    )]
+    retries: u32,
+    #[arg(long, default_value = "1s", value_parser = units::parse_duration, help = "delay before the first retry, doubled after each attempt")]
+    retry_delay: Duration,
+    #[arg(long, value_enum, help = "compress file data on the wire")]
+    compress: Option<Compression>,
+    #[arg(long, value_enum, default_value_t = HashAlgo::Sha256, help = "hash algorithm (must match the server)")]
+    hash: HashAlgo,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "read newline-separated paths to send from a file (- for stdin)"
+    )]
+    files_from: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "files_from",
+        help = "like --files-from, but paths are separated by NUL (e.g. find -print0)"
+    )]
+    files_from0: Option<String>,
+    #[arg(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with_all = ["files", "files_from", "files_from0", "list", "history", "stats"],
+        help = "send the files in a tar archive (optionally gzip or zstd compressed) instead of from the filesystem"
+    )]
+    from_tar: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "from_tar",
+        help = "save the checksummed file list here, so a run that's killed and restarted can resume without redoing it"
+    )]
+    state_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "from_tar",
+        help = "name files on the server by their path relative to this directory instead of as given"
+    )]
+    base_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        action,
+        help = "connect even if the server's version looks incompatible"
+    )]
+    ignore_version: bool,
+    #[arg(
+        long,
+        env = "RAPTORBOOST_TOKEN",
+        hide_env_values = true,
+        help = "authentication token expected by the server"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "token",
+        help = "read the authentication token from a file"
+    )]
+    token_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProgressMode::Auto,
+        help = "progress bars: auto prints plain periodic lines when stderr isn't a terminal"
+    )]
+    progress: ProgressMode,
+    #[arg(long, default_value = "50ms", value_parser = units::parse_duration, help = "redraw progress bars at most this often (between 4ms and 1s)")]
+    progress_interval: Duration,
+    #[arg(
+        long,
+        help = "log filter, e.g. debug or raptorboost=trace [default: $RUST_LOG or warn]"
+    )]
+    log_level: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "tls",
+        help = "connect to a server listening on this unix socket instead of over tcp"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "URI",
+        value_parser = parse_endpoint,
+        conflicts_with_all = ["unix_socket", "port"],
+        help = "connect to the server at this uri instead of host and port, e.g. https://proxy.example/raptorboost behind a reverse proxy; https needs --tls"
+    )]
+    endpoint: Option<Uri>,
+    #[arg(
+        long,
+        value_name = "[SCHEME://]HOST[:PORT]",
+        value_parser = parse_origin,
+        help = "send requests to the first server as if to this origin, for a reverse proxy that routes on the :authority (Host) header rather than the address it's reached at"
+    )]
+    origin: Option<Uri>,
+    #[arg(index = 1, required_unless_present_any = ["unix_socket", "endpoint"])]
+    host: Option<String>,
+    #[arg(
+        long,
+        value_name = "HOST[:PORT]",
+        conflicts_with_all = ["list", "history", "stats"],
+        help = "also send to this server, alongside the first; can be repeated, and each server is sent to independently and at the same time [default port: --port]"
+    )]
+    to: Vec<String>,
+    #[arg(trailing_var_arg = true, index = 2)]
+    files: Vec<String>,
+}
+
+/// The files to send, as found by `collect_files` or `collect_archive`.
+struct Collected {
+    hashed_files: HashMap<String, Vec<HashedFile>>,
+    sha256_to_filenames: HashMap<String, Vec<String>>,
+    sorted_sha256es: Vec<String>,
+    all_files: Vec<(String, String)>,
+    empty_dirs: Vec<String>,
+    /// Symlinks to recreate as they are, with their targets.
+    symlinks: Vec<(String, String)>,
+    /// The mode and mtime of each name, when they don't come from the
+    /// filesystem.
+    attributes: Option<HashMap<String, FileAttributes>>,
+    /// Paths that were skipped because they couldn't be read, and why.
+    unreadable: Vec<(String, String)>,
+    /// The directories given to send, as they were given.
+    roots: Vec<String>,
+}
+
+impl From<Plan> for Collected {
+    fn from(plan: Plan) -> Collected {
+        let mut hashed_files: HashMap<String, Vec<HashedFile>> = HashMap::new();
+        let mut sha256_to_filenames: HashMap<String, Vec<String>> = HashMap::new();
+        let mut sorted_sha256es: Vec<String> = Vec::new();
+        let mut all_files: Vec<(String, String)> = Vec::new();
+        for file in plan.files {
+            hashed_files
+                .entry(file.sha256sum.clone())
+                .or_default()
+                .push(HashedFile {
+                    filename: file.filename.clone(),
+                    size: file.size,
+                    modified: file.modified,
+                });
+            sorted_sha256es.push(file.sha256sum.clone());
+            all_files.push((file.filename.clone(), file.sha256sum.clone()));
+            sha256_to_filenames
+                .entry(file.sha256sum)
+                .or_default()
+                .push(file.filename);
+        }
+
+        Collected {
+            hashed_files,
+            sha256_to_filenames,
+            sorted_sha256es,
+            all_files,
+            empty_dirs: plan.empty_dirs,
+            symlinks: plan.symlinks,
+            attributes: None,
+            unreadable: plan.unreadable,
+            roots: plan.roots,
+        }
+    }
+}
+
+/// The paths given to send, on the command line and in file lists.
+fn input_files(args: &Args) -> Result<Vec<String>, MainError> {
+    let mut files = args.files.clone();
+    if let Some(path) = &args.files_from {
+        files.extend(read_file_list(path, b'\n')?);
+    }
+    if let Some(path) = &args.files_from0 {
+        files.extend(read_file_list(path, b'\0')?);
+    }
+
+    if files.is_empty() {
+        return Err(MainError("no file(s) specified".to_string()));
+    }
+    Ok(files)
+}
+
+/// Names the plan of a run, so a rerun with the same arguments from the same
+/// directory finds it.
+fn plan_key(args: &Args, files: &[String]) -> String {
+    let run = serde_json::json!({
+        "cwd": std::env::current_dir().ok(),
+        "host": args.host,
+        "port": args.port,
+        "unix_socket": args.unix_socket,
+        "endpoint": args.endpoint.as_ref().map(Uri::to_string),
+        "name": args.name,
+        "hash": args.hash.to_string(),
+        "files": files,
+        "exclude": args.exclude,
+        "use_gitignore": args.use_gitignore,
+        "follow_symlinks": args.follow_symlinks,
+        "preserve_symlinks": args.preserve_symlinks,
+        "max_depth": args.max_depth,
+        "one_file_system": args.one_file_system,
+        "no_sort": args.no_sort,
+        "strict": args.strict,
+        "small_first": args.small_first,
+    });
+    let mut hasher = HashAlgo::Sha256.hasher();
+    hasher.update(run.to_string().as_bytes());
+    hasher.finish()[..16].to_string()
+}
+
+/// The name of `path`: relative to the directory it was found in if that
+/// was given with a trailing slash, as rsync does, or `path` as it is.
+fn name_in_roots(roots: &[String], path: &str) -> String {
+    roots
+        .iter()
+        .filter(|root| root.ends_with('/') || root.ends_with(std::path::MAIN_SEPARATOR))
+        .find_map(|root| Path::new(path).strip_prefix(root).ok())
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// `path` made absolute and with `.` and `..` resolved, without following
+/// symlinks.
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::ParentDir => _ = resolved.pop(),
+            Component::CurDir => {}
+            c => resolved.push(c),
+        }
+    }
+    Ok(resolved)
+}
+
+/// The name of `path` relative to `base`, which is absolute, or `None` if
+/// it lies outside it.
+fn name_relative_to(base: &Path, path: &str) -> Option<String> {
+    let relative = absolute_path(Path::new(path)).ok()?;
+    let relative = relative.strip_prefix(base).ok()?;
+    Some(relative.to_string_lossy().into_owned())
+}
+
+/// The device and inode of a file, which every path to it shares.
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Steps 1-3 of sending files from the filesystem: find them, sort them
+/// and checksum them. Nothing in `store_dirs`, where a server on this host
+/// keeps its files, is sent.
+fn collect_files(
+    args: &Args,
+    files: &[String],
+    store_dirs: &[PathBuf],
+    human: bool,
+    multibar: &MultiProgress,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let walk_filter = WalkFilter::new(&args.exclude, args.use_gitignore)
+        .map_err(MainError)?
+        .with_follow_symlinks(args.follow_symlinks)
+        .with_preserve_symlinks(args.preserve_symlinks)
+        .with_max_depth(args.max_depth.map(NonZeroUsize::get))
+        .with_one_file_system(args.one_file_system)
+        .with_store_dirs(store_dirs.to_vec());
+    // in the order they were given or found, so runs over the same files
+    // send and report them in the same order
+    let mut deduped_filenames: Vec<String> = Vec::new();
+    let mut seen_filenames: HashSet<String> = HashSet::new();
+    let mut empty_dirs: Vec<String> = Vec::new();
+    let mut symlink_paths: Vec<String> = Vec::new();
+    let mut roots: Vec<String> = Vec::new();
+    let mut unreadable: Vec<(String, String)> = Vec::new();
+    // canonical paths already added, so a target reached through several
+    // symlinks is only sent once
+    let mut seen_targets: HashSet<PathBuf> = HashSet::new();
+    let mut add_file = |filename: String| {
+        // a followed symlink can lead anywhere
+        if args.follow_symlinks
+            && let Some(dir) = walk_filter.store_dir(Path::new(&filename))
+        {
+            warn!(
+                "skipping '{}': it's in a server's store ({})",
+                filename,
+                dir.display()
+            );
+            return;
+        }
+        if args.follow_symlinks
+            && let Ok(target) = std::fs::canonicalize(&filename)
+            && !seen_targets.insert(target)
+        {
+            return;
+        }
+        if seen_filenames.insert(filename.clone()) {
+            deduped_filenames.push(filename);
+        }
+    };
+
+    // 1: dedup files
+    for f in files {
+        if let Some(dir) = walk_filter.store_dir(Path::new(f)) {
+            warn!(
+                "skipping '{}': it's in a server's store ({})",
+                f,
+                dir.display()
+            );
+            continue;
+        }
+        if args.preserve_symlinks
+            && std::fs::symlink_metadata(f).is_ok_and(|m| m.file_type().is_symlink())
+        {
+            symlink_paths.push(f.to_owned());
+            continue;
+        }
+        let fd = match File::open(f) {
+            Ok(fd) => fd,
+            Err(e) if args.strict => {
+                return Err(MainError(format!("couldn't open '{}': {}", f, e)).into());
+            }
+            Err(e) => {
+                warn!("skipping '{}': {}", f, e);
+                unreadable.push((f.to_owned(), e.to_string()));
+                continue;
+            }
+        };
+        if fd.metadata()?.is_dir() {
+            roots.push(f.to_owned());
+            walk_filter.walk_with_empty_dirs(
+                f,
+                |entry| {
+                    let path = entry.path().to_string_lossy().into_owned();
+                    match entry.file_type().is_symlink() && args.preserve_symlinks {
+                        true => symlink_paths.push(path),
+                        false => add_file(path),
+                    }
+                },
+                |dir| empty_dirs.push(dir.to_string_lossy().into_owned()),
+                |path, e| {
+                    if !args.strict {
+                        warn!("skipping '{}': {}", path.display(), e);
+                    }
+                    unreadable.push((path.to_string_lossy().into_owned(), e.to_string()));
+                },
+            );
+            if args.strict
+                && let Some((path, e)) = unreadable.first()
+            {
+                return Err(MainError(format!("couldn't read '{}': {}", path, e)).into());
+            }
+        } else {
+            add_file(f.to_owned());
+        }
+    }
+
+    let mut symlinks: Vec<(String, String)> = Vec::with_capacity(symlink_paths.len());
+    for link in symlink_paths {
+        match std::fs::read_link(&link) {
+            Ok(target) => symlinks.push((link, target.to_string_lossy().into_owned())),
+            Err(e) if args.strict => {
+                return Err(MainError(format!("couldn't read link '{}': {}", link, e)).into());
+            }
+            Err(e) => {
+                warn!("skipping '{}': {}", link, e);
+                unreadable.push((link, e.to_string()));
+            }
+        }
+    }
+
+    if deduped_filenames.is_empty() && empty_dirs.is_empty() && symlinks.is_empty() {
+        return Err(MainError("no files found".to_string()).into());
+    }
+
+    // 2: sort files, stat'ing each one once up front
+    let mut sorted_files: Vec<(&String, std::fs::Metadata)> =
+        Vec::with_capacity(deduped_filenames.len());
+    for filename in &deduped_filenames {
+        match std::fs::metadata(filename) {
+            Ok(metadata) => sorted_files.push((filename, metadata)),
+            Err(e) if args.strict => {
+                return Err(MainError(format!("couldn't stat '{}': {}", filename, e)).into());
+            }
+            Err(e) => {
+                warn!("skipping '{}': {}", filename, e);
+                unreadable.push((filename.clone(), e.to_string()));
+            }
+        }
+    }
+
+    if !args.no_sort {
+        say!(human, "[+] sorting files...");
+        // a stable sort, so files of the same size stay in the order found
+        match args.small_first {
+            true => sorted_files.sort_by_key(|(_, metadata)| metadata.len()),
+            false => sorted_files.sort_by_key(|(_, metadata)| Reverse(metadata.len())),
+        }
+    }
+
+    // 3: calculate checksums
+    let mut planned_files: Vec<PlannedFile> = Vec::with_capacity(sorted_files.len());
+
+    // a file reachable by several paths (hardlinks, or the same file given
+    // twice) is only hashed once, but named under every path
+    let mut first_path: HashMap<(u64, u64), &String> = HashMap::new();
+    let mut same_file: HashMap<&String, &String> = HashMap::new();
+    for &(filename, ref metadata) in &sorted_files {
+        if let Some(id) = file_id(metadata) {
+            match first_path.entry(id) {
+                Entry::Occupied(first) => _ = same_file.insert(filename, first.get()),
+                Entry::Vacant(first) => _ = first.insert(filename),
+            }
+        }
+    }
+    let to_hash: Vec<&String> = sorted_files
+        .iter()
+        .map(|&(filename, _)| filename)
+        .filter(|f| !same_file.contains_key(f))
+        .collect();
+
+    say!(human, "[+] calculating checksums...");
+    let bar = multibar.add(ProgressBar::new(to_hash.len().try_into().unwrap()));
+    bar.tick(); // show the bar even if the first file takes a while to checksum
+
+    let jobs = args
+        .jobs
+        .map(NonZeroUsize::get)
+        .unwrap_or_else(|| available_parallelism().map(NonZeroUsize::get).unwrap_or(1));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| MainError(format!("couldn't create checksum thread pool: {}", e)))?;
+
+    let mut cache = match args.no_cache {
+        true => None,
+        false => args
+            .cache_dir
+            .clone()
+            .or_else(default_cache_dir)
+            .map(|dir| HashCache::load(&dir, args.hash)),
+    };
+
+    // par_iter().collect() preserves input order, so the results below line up
+    // with to_hash no matter which order the workers finish in. The
+    // metadata is read before hashing, so a file that changes mid-hash won't
+    // match its cache entry next time, and is skipped rather than sent
+    let sha256es: Vec<io::Result<(String, bool, std::fs::Metadata)>> = pool.install(|| {
+        to_hash
+            .par_iter()
+            .map(|filename| {
+                let sha256sum = std::fs::metadata(filename).and_then(|metadata| {
+                    let cached = cache
+                        .as_ref()
+                        .and_then(|cache| cache.get(Path::new(filename), &metadata));
+                    match cached {
+                        Some(sha256sum) => Ok((sha256sum.to_string(), false, metadata)),
+                        None => hash_file(filename, args.chunk_size, args.hash, args.mmap)
+                            .map(|s| (s, true, metadata)),
+                    }
+                });
+                bar.inc(1);
+                sha256sum
+            })
+            .collect()
+    });
+
+    let sha256es: HashMap<&String, Result<(String, bool, std::fs::Metadata), String>> = to_hash
+        .into_iter()
+        .zip(sha256es)
+        .map(|(filename, sha256sum)| (filename, sha256sum.map_err(|e| e.to_string())))
+        .collect();
+
+    for (filename, _) in sorted_files {
+        let hashed_as = same_file.get(filename).copied().unwrap_or(filename);
+        let (sha256sum, fresh, metadata) = match sha256es[hashed_as].clone() {
+            Ok(hashed) => hashed,
+            Err(e) if args.strict => {
+                return Err(MainError(format!("error reading `{}`: {}", filename, e)).into());
+            }
+            Err(e) => {
+                warn!("skipping '{}': {}", filename, e);
+                unreadable.push((filename.clone(), e));
+                continue;
+            }
+        };
+        if let Some(cache) = &mut cache
+            && fresh
+        {
+            cache.insert(Path::new(filename), &metadata, sha256sum.clone());
+        }
+        planned_files.push(PlannedFile::new(filename.clone(), sha256sum, &metadata));
+    }
+
+    drop(bar);
+
+    if let Some(cache) = &cache
+        && let Err(e) = cache.save()
+    {
+        warn!("couldn't save hash cache: {}", e);
+    }
+
+    Ok(Plan {
+        files: planned_files,
+        empty_dirs,
+        symlinks,
+        roots,
+        unreadable,
+    })
+}
+
+/// Steps 1-3 of sending the files in a tar archive: a single pass that
+/// checksums each file entry. Entries are sent in the archive's order, so
+/// there's nothing to sort.
+fn collect_archive(
+    args: &Args,
+    path: &Path,
+    human: bool,
+    multibar: &MultiProgress,
+) -> Result<Collected, Box<dyn std::error::Error>> {
+    let fd = File::open(path)
+        .map_err(|e| MainError(format!("couldn't open '{}': {}", path.display(), e)))?;
+    let len = fd.metadata()?.len();
+
+    say!(human, "[+] calculating checksums...");
+    let bar = multibar.add(ProgressBar::new(len));
+    bar.tick();
+    let mut archive = archive::open(bar.wrap_read(fd))
+        .map_err(|e| MainError(format!("error reading '{}': {}", path.display(), e)))?;
+
+    let mut hashed_files: HashMap<String, Vec<HashedFile>> = HashMap::new();
+    let mut sha256_to_filenames: HashMap<String, Vec<String>> = HashMap::new();
+    let mut sorted_sha256es: Vec<String> = Vec::new();
+    let mut all_files: Vec<(String, String)> = Vec::new();
+    let mut attributes: HashMap<String, FileAttributes> = HashMap::new();
+    let mut dirs: Vec<String> = Vec::new();
+    let mut symlinks: Vec<(String, String)> = Vec::new();
+    let mut sha256_by_path: HashMap<String, String> = HashMap::new();
+    let mut buffer = vec![0u8; args.chunk_size];
+
+    let read_error = |e: io::Error| MainError(format!("error reading '{}': {}", path.display(), e));
+    for data in archive.entries().map_err(read_error)? {
+        let mut data = data.map_err(read_error)?;
+        let entry = archive::Entry::new(&data).map_err(read_error)?;
+        let sha256sum = match entry.kind {
+            EntryKind::File => {
+                let mut hasher = args.hash.hasher();
+                loop {
+                    match data.read(&mut buffer).map_err(read_error)? {
+                        0 => break,
+                        n => hasher.update(&buffer[..n]),
+                    }
+                }
+                let sha256sum = hasher.finish();
+                hashed_files
+                    .entry(sha256sum.clone())
+                    .or_default()
+                    .push(HashedFile {
+                        filename: entry.path.clone(),
+                        size: entry.size,
+                        modified: Some(entry.modified()),
+                    });
+                sorted_sha256es.push(sha256sum.clone());
+                sha256sum
+            }
+            EntryKind::HardLink => {
+                let target = entry.link.as_deref().unwrap_or_default();
+                match sha256_by_path.get(target) {
+                    Some(sha256sum) => sha256sum.clone(),
+                    None => {
+                        warn!(
+                            "skipping {}: links to {}, which isn't a file earlier in the archive",
+                            entry.path, target
+                        );
+                        continue;
+                    }
+                }
+            }
+            EntryKind::Dir => {
+                dirs.push(entry.path.trim_end_matches('/').to_string());
+                continue;
+            }
+            EntryKind::Symlink if args.preserve_symlinks => {
+                let target = entry.link.unwrap_or_default();
+                symlinks.push((entry.path, target));
+                continue;
+            }
+            EntryKind::Symlink => {
+                warn!("skipping symlink {}", entry.path);
+                continue;
+            }
+            EntryKind::Other(_) => {
+                warn!("skipping {}: not a regular file", entry.path);
+                continue;
+            }
+        };
+
+        attributes.insert(
+            entry.path.clone(),
+            FileAttributes {
+                mode: Some(entry.mode),
+                mtime: Some(entry.mtime),
+                mtime_nsec: Some(0),
+            },
+        );
+        sha256_by_path.insert(entry.path.clone(), sha256sum.clone());
+        all_files.push((entry.path.clone(), sha256sum.clone()));
+        sha256_to_filenames
+            .entry(sha256sum)
+            .or_default()
+            .push(entry.path);
+    }
+    drop(bar);
+
+    // directories with something in them are created along with it
+    let mut parents: HashSet<&str> = HashSet::new();
+    let names = all_files
+        .iter()
+        .map(|(f, _)| f)
+        .chain(symlinks.iter().map(|(link, _)| link))
+        .chain(&dirs);
+    for name in names {
+        let mut name = name.as_str();
+        while let Some((parent, _)) = name.rsplit_once('/') {
+            parents.insert(parent);
+            name = parent;
+        }
+    }
+    let empty_dirs: Vec<String> = dirs
+        .iter()
+        .filter(|d| !d.is_empty() && *d != "." && !parents.contains(d.as_str()))
+        .cloned()
+        .collect();
+
+    if all_files.is_empty() && empty_dirs.is_empty() && symlinks.is_empty() {
+        return Err(MainError("no files found".to_string()).into());
+    }
+
+    Ok(Collected {
+        hashed_files,
+        sha256_to_filenames,
+        sorted_sha256es,
+        all_files,
+        empty_dirs,
+        symlinks,
+        attributes: Some(attributes),
+        unreadable: vec![],
+        roots: vec![],
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = Args::parse();
+    logging::init(args.log_level.as_deref(), "warn");
+
+    // there's no host to name when dialing a unix socket or an endpoint, so
+    // the first positional argument is already a file
+    if (args.unix_socket.is_some() || args.endpoint.is_some())
+        && let Some(host) = args.host.take()
+    {
+        args.files.insert(0, host);
+    }
+
+    // checked here rather than by clap so the endpoint can say which it needs
+    if let Some(endpoint) = &args.endpoint {
+        match (endpoint.scheme_str(), args.tls) {
+            (Some("https"), false) => {
+                return Err(MainError(format!(
+                    "--endpoint {} is https, add --tls to connect to it",
+                    endpoint
+                ))
+                .into());
+            }
+            (Some("http"), true) => {
+                return Err(MainError(format!(
+                    "--endpoint {} is plain http but --tls is set, use https:// instead",
+                    endpoint
+                ))
+                .into());
+            }
+            _ => {}
+        }
+    }
+
+    args.token = read_token(args.token.take(), args.token_file.as_deref())
+        .map_err(|e| MainError(format!("couldn't read token: {}", e)))?;
+
+    if args.list {
+        let mut client = connect(&args, &Server::primary(&args)).await?;
+        let files = client
+            .list_files()
+            .await
+            .map_err(|e| MainError(format!("remote error listing files: {}", e.message())))?;
+        print_file_list(&files, args.json);
+        return Ok(());
+    }
+
+    if let Some(sha256sum) = args.history.take() {
+        let mut client = connect(&args, &Server::primary(&args)).await?;
+        let entries = client
+            .get_history(sha256sum)
+            .await
+            .map_err(|e| MainError(format!("remote error getting history: {}", e.message())))?;
+        print_history(&entries, args.json);
+        return Ok(());
+    }
+
+    if args.stats {
+        let mut client = connect(&args, &Server::primary(&args)).await?;
+        let stats = client
+            .get_stats()
+            .await
+            .map_err(|e| MainError(format!("remote error getting stats: {}", e.message())))?;
+        print_stats(&stats, args.json);
+        return Ok(());
+    }
+
+    let servers = std::iter::once(Ok(Server::primary(&args)))
+        .chain(args.to.iter().map(|addr| Server::parse(addr, args.port)))
+        .collect::<Result<Vec<Server>, MainError>>()?;
+    if servers.len() > 1 && args.delete && !args.force {
+        return Err(MainError(
+            "--delete with more than one server needs --force, they can't all prompt at once"
+                .to_string(),
+        )
+        .into());
+    }
+
+    let started = Instant::now();
+    let human = !args.json;
+    if args.json && args.delete && !args.force {
+        return Err(
+            MainError("--delete with --json needs --force, there's no prompt".to_string()).into(),
+        );
+    }
+
+    if args.chunk_size == 0 {
+        return Err(MainError("chunk size must be non-zero".to_string()).into());
+    }
+
+    if args.chunk_size >= GRPC_MAX_MESSAGE_SIZE {
+        warn!(
+            "chunk size {} is not below the grpc max message size ({}); the server will likely reject it",
+            args.chunk_size, GRPC_MAX_MESSAGE_SIZE
+        );
+    }
+
+    // connected before anything is walked, so a server on this host can say
+    // where its store is and it's left out
+    let args = Arc::new(args);
+    let mut clients = connect_all(&args, &servers).await;
+    // with nowhere else to send, there's no point checksumming anything
+    if let [Err(_)] = clients.as_slice()
+        && let Some(Err(e)) = clients.pop()
+    {
+        return Err(e.into());
+    }
+    let mut store_dirs: Vec<PathBuf> = Vec::new();
+    for client in clients.iter_mut().flatten() {
+        match client.store_dirs().await {
+            Ok(dirs) => store_dirs.extend(dirs),
+            Err(e) => debug!("couldn't get the server's store directories: {}", e),
+        }
+    }
+
+    let multibar = progress::multi_progress(
+        match args.json {
+            true => ProgressMode::Never,
+            false => args.progress,
+        },
+        args.progress_interval,
+    );
+
+    let mut plan_path: Option<PathBuf> = None;
+    let Collected {
+        hashed_files,
+        sha256_to_filenames,
+        sorted_sha256es,
+        all_files,
+        empty_dirs,
+        symlinks,
+        attributes,
+        unreadable,
+        roots,
+    } = match &args.from_tar {
+        Some(archive) => collect_archive(&args, archive, human, &multibar)?,
+        None => {
+            let files = input_files(&args)?;
+            plan_path = args
+                .state_dir
+                .as_deref()
+                .map(|dir| plan::plan_path(dir, &plan_key(&args, &files)));
+            let plan = match plan_path.as_deref().and_then(Plan::load) {
+                Some(plan) => {
+                    say!(
+                        human,
+                        "[+] resuming with the file list saved by an earlier run..."
+                    );
+                    plan
+                }
+                None => {
+                    let plan = collect_files(&args, &files, &store_dirs, human, &multibar)?;
+                    if let Some(path) = &plan_path
+                        && let Err(e) = plan.save(path)
+                    {
+                        warn!("couldn't save plan {}: {}", path.display(), e);
+                    }
+                    plan
+                }
+            };
+            Collected::from(plan)
+        }
+    };
+
+    // the name each path is given on the server, worked out now so a file
+    // outside --base-dir is caught before anything is sent
+    let base_dir =
+        match &args.base_dir {
+            Some(base) => Some(absolute_path(base).map_err(|e| {
+                MainError(format!("bad base directory '{}': {}", base.display(), e))
+            })?),
+            None => None,
+        };
+    let name_of = |path: &str| match &base_dir {
+        None => Ok(name_in_roots(&roots, path)),
+        Some(base) => match name_relative_to(base, path) {
+            Some(name) if !name.is_empty() => Ok(name),
+            _ => Err(MainError(format!(
+                "'{}' isn't inside the base directory {}",
+                path,
+                base.display()
+            ))),
+        },
+    };
+    let names: HashMap<String, String> = all_files
+        .iter()
+        .map(|(f, _)| Ok((f.clone(), name_of(f)?)))
+        .collect::<Result<_, MainError>>()?;
+    let symlinks: Vec<Symlink> = symlinks
+        .into_iter()
+        .map(|(link, target)| {
+            Ok(Symlink {
+                name: name_of(&link)?,
+                target,
+            })
+        })
+        .collect::<Result<_, MainError>>()?;
+    let empty_dirs: Vec<String> = match &base_dir {
+        None => empty_dirs
+            .iter()
+            .map(|d| name_in_roots(&roots, d))
+            .filter(|name| !name.is_empty())
+            .collect(),
+        Some(base) => {
+            empty_dirs
+                .iter()
+                .filter_map(|d| match name_relative_to(base, d) {
+                    // the base itself exists as the transfer directory
+                    Some(name) if name.is_empty() => None,
+                    Some(name) => Some(Ok(name)),
+                    None => Some(Err(MainError(format!(
+                        "'{}' isn't inside the base directory {}",
+                        d,
+                        base.display()
+                    )))),
+                })
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let prepared = Arc::new(Prepared {
+        hashed_files,
+        sha256_to_filenames,
+        sorted_sha256es,
+        all_files,
+        empty_dirs,
+        symlinks,
+        attributes,
+        names,
+    });
+    let fan_out = servers.len() > 1;
+    let totals = Arc::new(Totals {
+        byte_cap: args.max_bytes.map(|max| Arc::new(ByteCap::new(max as u64))),
+        ..Default::default()
+    });
+
+    // 4-6: the rest is done against each server at once. They share only
+    // the checksums, so one failing doesn't hold up the others
+    let mut pushes = tokio::task::JoinSet::new();
+    for (i, (server, client)) in servers.iter().zip(clients).enumerate() {
+        let args = args.clone();
+        let server = server.clone();
+        let prepared = prepared.clone();
+        let totals = totals.clone();
+        let multibar = multibar.clone();
+        pushes.spawn(async move {
+            let pushed = push(
+                &args, &server, client, fan_out, &prepared, &totals, &multibar,
+            )
+            .await;
+            (i, pushed)
+        });
+    }
+    let joined = async {
+        let mut results: Vec<Option<Result<Option<Pushed>, MainError>>> =
+            servers.iter().map(|_| None).collect();
+        while let Some(joined) = pushes.join_next().await {
+            let (i, pushed) = joined.map_err(|e| MainError(format!("send task failed: {}", e)))?;
+            results[i] = Some(pushed);
+        }
+        Ok::<_, MainError>(results.into_iter().flatten().collect::<Vec<_>>())
+    };
+
+    // stop on ctrl-c rather than dying mid-redraw; whatever reached a
+    // server stays there as a partial for the next run
+    let results = tokio::select! {
+        results = joined => results?,
+        _ = tokio::signal::ctrl_c() => {
+            multibar.clear()?;
+            let (bytes, files, to_send) = (
+                totals.bytes_sent.load(Ordering::Relaxed),
+                totals.files_sent.load(Ordering::Relaxed),
+                totals.files_to_send.load(Ordering::Relaxed),
+            );
+            if args.json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "event": "interrupted",
+                        "files_sent": files,
+                        "bytes_sent": bytes,
+                        "elapsed_secs": started.elapsed().as_secs_f64(),
+                    })
+                );
+            } else {
+                eprintln!(
+                    "interrupted: {} sent ({} of {} files finished); run again to resume",
+                    HumanBytes(bytes),
+                    files,
+                    to_send
+                );
+            }
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    };
+
+    let mut pushed: Vec<(&Server, Pushed)> = Vec::new();
+    let mut servers_failed: Vec<(&Server, MainError)> = Vec::new();
+    for (server, result) in servers.iter().zip(results) {
+        match result {
+            Ok(Some(p)) => pushed.push((server, p)),
+            // a dry run has already printed its plan
+            Ok(None) => {}
+            Err(e) if !fan_out => return Err(e.into()),
+            Err(e) => servers_failed.push((server, e)),
+        }
+    }
+    if pushed.is_empty() && servers_failed.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(path) = &args.manifest_out {
+        // only what every server that was reached has
+        let assigned: Vec<(String, String)> = prepared
+            .all_files
+            .iter()
+            .map(|(filename, sha256sum)| (prepared.names[filename].clone(), sha256sum.clone()))
+            .filter(|(name, _)| {
+                pushed
+                    .iter()
+                    .all(|(_, p)| p.name_results.get(name) == Some(&"assigned"))
+            })
+            .collect();
+        File::create(path)
+            .and_then(|f| {
+                manifest::write_manifest(
+                    io::BufWriter::new(f),
+                    args.manifest_format,
+                    args.hash,
+                    &assigned,
+                )
+            })
+            .map_err(|e| {
+                MainError(format!(
+                    "couldn't write manifest '{}': {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+    }
+
+    // a rerun has nothing left to resume
+    if let Some(path) = &plan_path
+        && servers_failed.is_empty()
+        && pushed
+            .iter()
+            .all(|(_, p)| p.failures.is_empty() && p.skipped.is_empty() && p.left.is_empty())
+        && let Err(e) = std::fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        warn!("couldn't remove plan {}: {}", path.display(), e);
+    }
+
+    for (server, p) in &pushed {
+        report(
+            &args,
+            fan_out.then_some(*server),
+            &prepared,
+            &unreadable,
+            p,
+            started,
+        );
+    }
+    for (server, e) in &servers_failed {
+        match args.json {
+            true => println!(
+                "{}",
+                serde_json::json!({
+                    "event": "summary",
+                    "server": server.to_string(),
+                    "error": e.to_string(),
+                })
+            ),
+            false => println!("\n{}: {}", server, e),
+        }
+    }
+    if !unreadable.is_empty() {
+        say!(human, "{} unreadable paths skipped:", unreadable.len());
+        for (filename, e) in &unreadable {
+            say!(human, "  {}: {}", filename, e);
+        }
+    }
+
+    run_result(
+        servers_failed.len(),
+        pushed.iter().map(|(_, p)| p.failures.len()).sum(),
+        pushed.iter().map(|(_, p)| p.left.len()).sum(),
+        pushed.iter().map(|(_, p)| p.num_names_failed).sum(),
+        unreadable.len(),
+    )
+}
+
+/// What a run works out before talking to any server, shared by every
+/// server it sends to.
+struct Prepared {
+    hashed_files: HashMap<String, Vec<HashedFile>>,
+    sha256_to_filenames: HashMap<String, Vec<String>>,
+    sorted_sha256es: Vec<String>,
+    all_files: Vec<(String, String)>,
+    /// Named as they are on the server.
+    empty_dirs: Vec<String>,
+    /// Symlinks to recreate, also named as they are on the server.
+    symlinks: Vec<Symlink>,
+    attributes: Option<HashMap<String, FileAttributes>>,
+    /// The name each path is given on the server.
+    names: HashMap<String, String>,
+}
+
+/// Progress across every server, for reporting an interrupted run.
+#[derive(Default)]
+struct Totals {
+    bytes_sent: AtomicU64,
+    files_sent: AtomicU64,
+    files_to_send: AtomicU64,
+    /// --max-bytes, as one budget shared by every server.
+    byte_cap: Option<Arc<ByteCap>>,
+}
+
+/// How sending to one server went.
+struct Pushed {
+    num_files_transferred: usize,
+    num_files_up_to_date: u64,
+    num_names_assigned: usize,
+    num_names_failed: usize,
+    num_files_deleted: usize,
+    failures: Vec<(String, SendFileError)>,
+    /// Files that changed after they were checksummed.
+    skipped: Vec<(String, SendFileError)>,
+    /// Files not started because --max-bytes was reached.
+    left: Vec<String>,
+    sent_sha256es: HashSet<String>,
+    name_results: HashMap<String, &'static str>,
+    bytes_sent: u64,
+}
+
+/// Steps 4-6 against one server, over `client` as first connected to it:
+/// check what it needs, stream those files, name them and prune what's no
+/// longer sent. A dry run prints its plan and
+/// returns None. With `fan_out` everything printed is tagged with the
+/// server, as it runs alongside the others.
+async fn push(
+    args: &Args,
+    server: &Server,
+    client: Result<Client, MainError>,
+    fan_out: bool,
+    prepared: &Prepared,
+    totals: &Arc<Totals>,
+    multibar: &MultiProgress,
+) -> Result<Option<Pushed>, MainError> {
+    let human = !args.json;
+    let tag = match fan_out {
+        true => format!("{}: ", server),
+        false => String::new(),
+    };
+
+    // 4: check what the server needs, then stream those files.
+    let mut client = client?;
+
+    if args.dry_run {
+        say!(human, "{}[+] checking remote state...", tag);
+        let (to_send, total_to_send, num_files_up_to_date) = client
+            .upload_files(&prepared.sorted_sha256es, &prepared.hashed_files, args.hash)
+            .await
+            .map_err(|e| MainError(format!("check stream error: {}", e)))?;
+        let to_delete = match args.delete {
+            true => Some(
+                client
+                    .delete_files(prepared.sorted_sha256es.clone(), true, true)
+                    .await
+                    .map_err(|e| {
+                        MainError(format!("remote error deleting files: {}", e.message()))
+                    })?
+                    .sha256sums,
+            ),
+            false => None,
+        };
+        // held so the plans of several servers don't interleave
+        let _stdout = io::stdout().lock();
+        if fan_out {
+            println!("\n{}:", server);
+        }
+        print_plan(&to_send, total_to_send, num_files_up_to_date);
+        if let Some(to_delete) = to_delete {
+            say!(
+                human,
+                "{} files would be deleted from the server",
+                to_delete.len()
+            );
+            for sha256sum in &to_delete {
+                say!(human, "  {}", sha256sum);
+            }
+        }
+        return Ok(None);
+    }
+
+    say!(
+        human,
+        "{}[+] checking remote state and streaming files...",
+        tag
+    );
+    let bytes_sent = Arc::new(AtomicU64::new(0));
+    let opts = SendOptions {
+        force_unlock: args.force_unlock,
+        chunk_size: args.chunk_size,
+        fail_fast: args.fail_fast,
+        hash_algo: args.hash,
+        rate_limiter: args
+            .bwlimit
+            .map(|rate| Arc::new(RateLimiter::new(rate as u64))),
+        byte_cap: totals.byte_cap.clone(),
+        on_event: Some(Arc::new({
+            let bytes_sent = bytes_sent.clone();
+            let json = args.json;
+            let server = fan_out.then(|| server.to_string());
+            let totals = totals.clone();
+            move |event| {
+                match event {
+                    SendEvent::BytesSent { bytes, .. } => {
+                        bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+                        totals.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+                    }
+                    SendEvent::FileSent { .. } => {
+                        totals.files_sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+                if json {
+                    let mut event = send_event_json(&event);
+                    if let Some(server) = &server {
+                        event["server"] = server.as_str().into();
+                    }
+                    println!("{}", event);
+                }
+            }
+        })),
+        archive: args.from_tar.clone(),
+    };
+
+    // files with the same content share a hash, and it only needs checking
+    // once
+    let mut seen: HashSet<&String> = HashSet::new();
+    let mut to_check: Vec<String> = prepared
+        .sorted_sha256es
+        .iter()
+        .filter(|s| seen.insert(*s))
+        .cloned()
+        .collect();
+    // what the server needed when first asked, which is what a retry asks
+    // about again; None until the first check has finished
+    let mut pending_sha256es: Option<Vec<String>> = None;
+    let mut num_files_up_to_date: u64 = 0;
+    let mut attempt = 0;
+    let mut failures = loop {
+        // each file the server needs starts sending as soon as it says so,
+        // while it's still checking the rest
+        let (files_tx, files_rx) = mpsc::channel::<FilenameWithState>(CHECKED_AHEAD);
+        let first_attempt = pending_sha256es.is_none();
+        let mut sender = client.clone();
+        let check = async {
+            let files_tx = files_tx;
+            let mut checked = client
+                .check_files(
+                    std::mem::take(&mut to_check),
+                    &prepared.hashed_files,
+                    args.hash,
+                )
+                .await?;
+            let mut needed: Vec<String> = Vec::new();
+            let mut up_to_date: u64 = 0;
+            while let Some(file) = checked.next().await? {
+                match file {
+                    CheckedFile::Needed(file) => {
+                        if first_attempt {
+                            totals.files_to_send.fetch_add(1, Ordering::Relaxed);
+                        }
+                        needed.push(file.sha256sum.clone());
+                        // once the upload has ended, the rest of the
+                        // answers are still wanted for the retry
+                        let _ = files_tx.send(file).await;
+                    }
+                    CheckedFile::Complete => up_to_date += 1,
+                }
+            }
+            Ok::<_, tonic::Status>((needed, up_to_date))
+        };
+        let send = sender.send_file_data(files_rx, opts.clone(), multibar.clone());
+        let (checked, sent) = tokio::join!(check, send);
+
+        let err = match checked {
+            Ok((needed, up_to_date)) => {
+                if first_attempt {
+                    num_files_up_to_date = up_to_date;
+                    pending_sha256es = Some(needed);
+                }
+                match sent {
+                    Ok(failures) => break failures,
+                    Err(e) if attempt < args.retries && e.is_transient() => e.to_string(),
+                    Err(e) => return Err(MainError(e.to_string())),
+                }
+            }
+            // without the first check's answers there's nothing to resume
+            Err(e) if !first_attempt && attempt < args.retries => {
+                format!("check stream error: {}", e)
+            }
+            Err(e) => return Err(MainError(format!("check stream error: {}", e))),
+        };
+
+        // reconnect and ask the server where each file got to, so the
+        // retry resumes from its offset instead of starting over
+        loop {
+            attempt += 1;
+            let delay = args.retry_delay.saturating_mul(1 << (attempt - 1).min(16));
+            warn!(
+                "{}transfer interrupted ({}), retrying in {:?} ({}/{})",
+                tag, err, delay, attempt, args.retries
+            );
+            tokio::time::sleep(delay).await;
+
+            match connect(args, server).await {
+                Ok(c) => {
+                    client = c;
+                    break;
+                }
+                Err(e) if attempt < args.retries => warn!("{}{}", tag, e),
+                Err(e) => return Err(e),
+            }
+        }
+        to_check = pending_sha256es.clone().unwrap_or_default();
+    };
+    let pending_sha256es = pending_sha256es.unwrap_or_default();
+
+    // the server's word for it, not just the upload's
+    if args.verify_remote && !pending_sha256es.is_empty() {
+        say!(human, "{}[+] verifying remote state...", tag);
+        let (incomplete, _, _) = client
+            .upload_files(&pending_sha256es, &prepared.hashed_files, args.hash)
+            .await
+            .map_err(|e| MainError(format!("verify stream error: {}", e)))?;
+        let failed: HashSet<String> = failures.iter().map(|(f, _)| f.clone()).collect();
+        for file in incomplete {
+            if !failed.contains(&file.filename) {
+                warn!("{}{} isn't complete on the server", tag, file.filename);
+                failures.push((file.filename, SendFileError::NotComplete));
+            }
+        }
+    }
+    let num_files_transferred = pending_sha256es.len().saturating_sub(failures.len());
+    let sent_sha256es: HashSet<String> = pending_sha256es.into_iter().collect();
+
+    // don't name content that never made it to the server
+    let failed_filenames: HashSet<&String> = failures.iter().map(|(f, _)| f).collect();
+    let sha256_to_names: HashMap<String, Vec<String>> = prepared
+        .sha256_to_filenames
+        .iter()
+        .filter(|(_, filenames)| !filenames.iter().any(|f| failed_filenames.contains(f)))
+        .map(|(sha256sum, filenames)| {
+            let named = filenames
+                .iter()
+                .map(|f| prepared.names[f].clone())
+                .collect();
+            (sha256sum.clone(), named)
+        })
+        .collect();
+    let filenames_by_name: HashMap<&str, &str> = prepared
+        .names
+        .iter()
+        .map(|(filename, name)| (name.as_str(), filename.as_str()))
+        .collect();
+
+    // 5: send names
+    say!(human, "{}[+] updating filenames...", tag);
+    let archived_attributes = |name: &str| {
+        prepared
+            .attributes
+            .as_ref()
+            .and_then(|a| a.get(name).cloned())
+            .unwrap_or_default()
+    };
+    let local_attributes =
+        |name: &str| file_attributes(filenames_by_name.get(name).copied().unwrap_or(name));
+    let attributes: Option<&(dyn Fn(&str) -> FileAttributes + Sync)> = match &prepared.attributes {
+        _ if args.no_perms => None,
+        Some(_) => Some(&archived_attributes),
+        None => Some(&local_attributes),
+    };
+    let num_names = sha256_to_names.values().map(Vec::len).sum::<usize>()
+        + prepared.empty_dirs.len()
+        + prepared.symlinks.len();
+    let mut num_names_failed = 0;
+    let (name_statuses, server_manifest) = match client
+        .assign_names(
+            args.name.clone(),
+            args.force_name,
+            args.manifest_on_server,
+            attributes,
+            sha256_to_names,
+            prepared.empty_dirs.clone(),
+            prepared.symlinks.clone(),
+        )
+        .await
+    {
+        Ok(resp) => (resp.statuses, resp.manifest),
+        Err(e) => {
+            say!(
+                human,
+                "{}remote error assigning names: {}",
+                tag,
+                e.message()
+            );
+            num_names_failed = num_names;
+            (vec![], None)
+        }
+    };
+
+    let mut num_names_assigned = 0;
+    let mut name_results: HashMap<String, &'static str> = HashMap::new();
+    for ns in &name_statuses {
+        name_results.insert(
+            ns.name.clone(),
+            match ns.status() {
+                AssignNameStatus::AssignnamestatusSuccess => "assigned",
+                AssignNameStatus::AssignnamestatusAlreadyExists => "exists",
+                _ => "error",
+            },
+        );
+        match ns.status() {
+            AssignNameStatus::AssignnamestatusSuccess => {
+                if let Some(warning) = &ns.message {
+                    warn!("{}'{}': {}", tag, ns.name, warning);
+                }
+                num_names_assigned += 1
+            }
+            AssignNameStatus::AssignnamestatusAlreadyExists => {
+                say!(human, "{}skipped '{}': name already exists", tag, ns.name)
+            }
+            AssignNameStatus::AssignnamestatusError => {
+                say!(
+                    human,
+                    "{}couldn't assign '{}': {}",
+                    tag,
+                    ns.name,
+                    ns.message.as_deref().unwrap_or("unknown error")
+                );
+                num_names_failed += 1
+            }
+            AssignNameStatus::AssignnamestatusUnspecified => {
+                say!(
+                    human,
+                    "{}couldn't assign '{}': unspecified error",
+                    tag,
+                    ns.name
+                );
+                num_names_failed += 1
+            }
+        }
+    }
+    if let Some(manifest) = &server_manifest {
+        say!(
+            human,
+            "{}manifest written on the server as {}",
+            tag,
+            manifest
+        );
+    }
+
+    // 6: prune remote files that no longer exist locally
+    let mut num_files_deleted = 0;
+    if args.delete {
+        say!(human, "{}[+] checking for remote files to delete...", tag);
+        let plan = client
+            .delete_files(prepared.sorted_sha256es.clone(), true, true)
+            .await
+            .map_err(|e| MainError(format!("remote error deleting files: {}", e.message())))?;
+
+        if plan.sha256sums.is_empty() {
+            say!(human, "{}no remote files to delete", tag);
+        } else {
+            for sha256sum in &plan.sha256sums {
+                say!(human, "{}  {}", tag, sha256sum);
+            }
+            let prompt = format!("delete {} files from the server?", plan.sha256sums.len());
+            if args.force
+                || confirm(&prompt).map_err(|e| MainError(format!("couldn't prompt: {}", e)))?
+            {
+                let resp = client
+                    .delete_files(plan.sha256sums, false, false)
+                    .await
+                    .map_err(|e| {
+                        MainError(format!("remote error deleting files: {}", e.message()))
+                    })?;
+                num_files_deleted = resp.sha256sums.len();
+            }
+        }
+    }
+
+    // files that changed after they were checksummed are skipped, not
+    // failed, and those the byte cap held back are left for the next run
+    let (left, failures): (Vec<_>, Vec<_>) = failures
+        .into_iter()
+        .partition(|(_, e)| matches!(e, SendFileError::ByteCapReached));
+    let left: Vec<String> = left.into_iter().map(|(filename, _)| filename).collect();
+    let (skipped, failures): (Vec<_>, Vec<_>) =
+        failures.into_iter().partition(|(_, e)| e.is_skip());
+
+    Ok(Some(Pushed {
+        num_files_transferred,
+        num_files_up_to_date,
+        num_names_assigned,
+        num_names_failed,
+        num_files_deleted,
+        failures,
+        skipped,
+        left,
+        sent_sha256es,
+        name_results,
+        bytes_sent: bytes_sent.load(Ordering::Relaxed),
+    }))
+}
+
+/// Print how sending to a server went: a json summary event, or a few lines
+/// for people. `server` is only given when there are several.
+fn report(
+    args: &Args,
+    server: Option<&Server>,
+    prepared: &Prepared,
+    unreadable: &[(String, String)],
+    pushed: &Pushed,
+    started: Instant,
+) {
+    let Pushed {
+        num_files_transferred,
+        num_files_up_to_date,
+        num_names_assigned,
+        num_names_failed,
+        num_files_deleted,
+        failures,
+        skipped,
+        left,
+        sent_sha256es,
+        name_results,
+        bytes_sent,
+    } = pushed;
+
+    if args.json {
+        let errors: HashMap<&String, String> = failures
+            .iter()
+            .chain(skipped)
+            .map(|(f, e)| (f, e.to_string()))
+            .collect();
+        let skipped_filenames: HashSet<&String> = skipped.iter().map(|(f, _)| f).collect();
+        let left_filenames: HashSet<&String> = left.iter().collect();
+        let mut files: Vec<serde_json::Value> = prepared
+            .all_files
+            .iter()
+            .map(|(filename, sha256sum)| {
+                let mut file = serde_json::json!({
+                    "filename": filename,
+                    "sha256sum": sha256sum,
+                    "status": match errors.contains_key(filename) {
+                        _ if left_filenames.contains(filename) => "left",
+                        true if skipped_filenames.contains(filename) => "skipped",
+                        true => "failed",
+                        false if sent_sha256es.contains(sha256sum) => "sent",
+                        false => "up_to_date",
+                    },
+                    "name": name_results.get(&prepared.names[filename]),
+                });
+                if let Some(e) = errors.get(filename) {
+                    file["error"] = e.as_str().into();
+                }
+                file
+            })
+            .collect();
+        files.extend(unreadable.iter().map(|(filename, e)| {
+            serde_json::json!({
+                "filename": filename,
+                "status": "unreadable",
+                "error": e,
+            })
+        }));
+        let mut summary = serde_json::json!({
+            "event": "summary",
+            "files_sent": num_files_transferred,
+            "files_up_to_date": num_files_up_to_date,
+            "files_failed": failures.len(),
+            "files_skipped": skipped.len(),
+            "files_left": left.len(),
+            "files_unreadable": unreadable.len(),
+            "names_assigned": num_names_assigned,
+            "names_failed": num_names_failed,
+            "files_deleted": num_files_deleted,
+            "bytes_sent": bytes_sent,
+            "elapsed_secs": started.elapsed().as_secs_f64(),
+            "files": files,
+        });
+        if let Some(server) = server {
+            summary["server"] = server.to_string().into();
+        }
+        println!("{}", summary);
+        return;
+    }
+
+    println!();
+    if let Some(server) = server {
+        println!("{}:", server);
+    }
+
+    // everything that needed sending, whether it made it or not
+    let num_files_to_send = num_files_transferred + failures.len() + skipped.len() + left.len();
+    if num_files_to_send != 0 {
+        println!(
+            "{} of {} files transferred successfully",
+            num_files_transferred, num_files_to_send
+        );
+    }
+    if *num_files_up_to_date != 0 {
+        println!("{} files were already up to date", num_files_up_to_date);
+    }
+    if *num_names_assigned != 0 {
+        println!("{} names assigned", num_names_assigned);
+    }
+    if *num_files_deleted != 0 {
+        println!("{} files deleted from the server", num_files_deleted);
+    }
+    if !skipped.is_empty() {
+        println!("{} files skipped:", skipped.len());
+        for (filename, e) in skipped {
+            println!("  {}: {}", filename, e);
+        }
+    }
+    if !left.is_empty() {
+        println!(
+            "{} files left to send after reaching --max-bytes; run again to send them",
+            left.len()
+        );
+    }
+    if !failures.is_empty() {
+        println!("{} files failed:", failures.len());
+        for (filename, e) in failures {
+            println!("  {}: {}", filename, e);
+        }
+    }
+}
+
+/// Fail the run, once everything else has been sent and named, if any
+/// server couldn't be sent to, any file didn't make it to one, any was left
+/// for later by --max-bytes, any name couldn't be assigned or anything was
+/// skipped because it couldn't be read, so scripts can tell a partial
+/// transfer from a complete one.
+fn run_result(
+    servers_failed: usize,
+    files_failed: usize,
+    files_left: usize,
+    names_failed: usize,
+    unreadable: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = Vec::new();
+    if servers_failed != 0 {
+        problems.push(format!("{} servers couldn't be sent to", servers_failed));
+    }
+    if files_failed != 0 {
+        problems.push(format!("{} files failed to transfer", files_failed));
+    }
+    if files_left != 0 {
+        problems.push(format!("{} files left to send", files_left));
+    }
+    if names_failed != 0 {
+        problems.push(format!("{} names couldn't be assigned", names_failed));
+    }
+    if unreadable != 0 {
+        problems.push(format!("{} unreadable paths were skipped", unreadable));
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(MainError(problems.join(", ")).into()),
+    }
+}
@@ -0,0 +1,817 @@
+use std::io;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{ArgAction, Parser};
+use indicatif::{ProgressBar, ProgressStyle};
+use local_ip_address::list_afinet_netifas;
+use raptorboost::accesslog::AccessLog;
+use raptorboost::auth::{TokenAuth, read_token};
+use raptorboost::client::parse_byte_size;
+use raptorboost::health::HealthService;
+use raptorboost::health::proto::health_server::HealthServer;
+use raptorboost::hooks::Hooks;
+use raptorboost::progress::{self, ProgressMode};
+use raptorboost::proto::raptor_boost_server::RaptorBoostServer;
+use raptorboost::{controller, hash, logging, service, units};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tokio::sync::{Semaphore, watch};
+use tokio_stream::{StreamExt, StreamMap};
+use tonic::codec::CompressionEncoding;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tracing::{error, info, warn};
+
+// how often the --verify progress bar is redrawn
+const VERIFY_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Parser)]
+#[command(version, about, disable_help_flag = true)]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(
+        short,
+        long,
+        help = "listen on an interface, an address prefix like 10.0., or any; can be repeated"
+    )]
+    interface: Vec<String>,
+    #[arg(
+        long,
+        action,
+        requires = "interface",
+        help = "prefer the interfaces' ipv6 addresses over their ipv4 ones"
+    )]
+    ipv6: bool,
+    #[arg(
+        long,
+        action,
+        help = "when listening on ::, don't also accept ipv4 connections"
+    )]
+    ipv6_only: bool,
+    #[arg(short, long, default_value = "7272")]
+    port: u16,
+    #[arg(
+        long,
+        conflicts_with_all = ["interface", "ipv6_only"],
+        help = "listen on this unix socket instead of over tcp"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[arg(
+        long,
+        action,
+        requires = "unix_socket",
+        help = "replace an existing file at the unix socket path"
+    )]
+    force: bool,
+    #[arg(short, long, default_value = ".")]
+    out_dir: PathBuf,
+    #[arg(long, action, help = "create --out-dir if it doesn't exist")]
+    mkdir: bool,
+    #[arg(
+        long,
+        help = "where in-progress uploads are written [default: <out-dir>/partial]"
+    )]
+    partial_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "where finished files are stored [default: <out-dir>/complete]"
+    )]
+    complete_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "where named transfers are created [default: <out-dir>/transfers]"
+    )]
+    transfers_dir: Option<PathBuf>,
+    #[arg(long, help = "where upload locks are kept [default: <out-dir>/lock]")]
+    lock_dir: Option<PathBuf>,
+    #[arg(long, action, help = "serve over tls")]
+    tls: bool,
+    #[arg(
+        long,
+        requires = "tls",
+        help = "ca certificate used to verify client certificates (enables mutual tls)"
+    )]
+    ca_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        required_if_eq("tls", "true"),
+        requires = "tls",
+        help = "server certificate"
+    )]
+    cert: Option<PathBuf>,
+    #[arg(
+        long,
+        required_if_eq("tls", "true"),
+        requires = "tls",
+        help = "server private key"
+    )]
+    key: Option<PathBuf>,
+    #[arg(
+        long,
+        action,
+        help = "verify partial files against their checkpoints before resuming, discarding anything past a checkpoint that no longer matches"
+    )]
+    verify_partials: bool,
+    #[arg(
+        long,
+        action,
+        conflicts_with = "verify_partials",
+        help = "resume partials without re-reading them to check their hash, trusting the client's checksum for them"
+    )]
+    trust_client_checksum: bool,
+    #[arg(
+        long,
+        action,
+        help = "keep each client's partial files and locks apart from other clients', by address; complete files are still shared"
+    )]
+    isolate_clients: bool,
+    #[arg(
+        long,
+        default_value_t = true,
+        action = ArgAction::Set,
+        help = "fsync each completed file and the complete dir before acknowledging it; \
+                turning this off is faster for many small files but a crash can lose or \
+                truncate recently completed files"
+    )]
+    durable: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = controller::LinkMode::Symlink,
+        help = "how transfer names refer to stored files; only symlinked names show up in \
+                rbc --list and are cleaned up by rbc --delete; file modes sent by clients are \
+                only applied to copies"
+    )]
+    link_mode: controller::LinkMode,
+    #[arg(long, value_enum, default_value_t = hash::HashAlgo::Sha256, help = "hash algorithm clients must use")]
+    hash: hash::HashAlgo,
+    #[arg(long, default_value = "30s", value_parser = units::parse_duration, help = "how long to wait for in-flight transfers on shutdown")]
+    shutdown_timeout: Duration,
+    #[arg(long, default_value = "0s", value_parser = units::parse_duration, help = "on shutdown, fail health checks for this long before refusing new connections, so load balancers can move traffic away")]
+    drain_delay: Duration,
+    #[arg(long, value_parser = units::parse_duration, help = "remove partial uploads untouched for this long (e.g. 7d)")]
+    gc_partial_age: Option<Duration>,
+    #[arg(
+        long,
+        action,
+        help = "re-hash every stored file, print the ones that don't match their checksum, and exit"
+    )]
+    verify: bool,
+    #[arg(long, default_value = "30s", value_parser = units::parse_duration, help = "ping clients this often so idle connections aren't dropped by nat or firewalls (0 disables)")]
+    keepalive_interval: Duration,
+    #[arg(long, default_value = "20s", value_parser = units::parse_duration, help = "drop a client whose ping goes unanswered this long, releasing its locks")]
+    keepalive_timeout: Duration,
+    #[arg(long, default_value = "30s", value_parser = units::parse_duration, help = "how long an upload waits for another upload of the same file to finish (0 fails straight away)")]
+    lock_timeout: Duration,
+    #[arg(
+        long,
+        action,
+        help = "read files with plain reads instead of memory-mapping large ones: partials when \
+                resuming, and complete files when verifying the store with --link-mode copy"
+    )]
+    no_mmap: bool,
+    #[arg(
+        long,
+        help = "refuse uploads beyond this many at once [default: no limit]"
+    )]
+    max_concurrent_transfers: Option<NonZeroUsize>,
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        help = "run this program with the checksum and stored path of each file received, in the background"
+    )]
+    on_complete: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        help = "run this program with the transfer's name and directory each time names are assigned, in the background"
+    )]
+    on_assign: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "append a line per RPC to this file, in a format for log tools; reopened on SIGHUP"
+    )]
+    access_log: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        requires = "access_log",
+        help = "move the access log to <PATH>.1 and start a new one once it reaches this size (e.g. 100M)"
+    )]
+    access_log_max_size: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "refuse uploads that would grow partial files past this much in total (e.g. 50G) [default: no limit]"
+    )]
+    partial_quota: Option<usize>,
+    #[arg(
+        long,
+        action,
+        requires = "partial_quota",
+        help = "make room under --partial-quota by removing the oldest idle partials instead of refusing uploads"
+    )]
+    evict_partials: bool,
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=controller::MAX_SHARD_DEPTH as i64),
+        help = "keep stored files this many directories deep (complete/ab/cd/abcd... for 2) \
+                instead of all in one directory; changing it on an existing store needs --reshard"
+    )]
+    shard_depth: u8,
+    #[arg(
+        long,
+        action,
+        help = "move the store's files to the layout given by --shard-depth, repoint their names, and exit; \
+                don't run it while a server is using the store"
+    )]
+    reshard: bool,
+    #[arg(
+        long,
+        env = "RAPTORBOOST_TOKEN",
+        hide_env_values = true,
+        help = "require clients to present this authentication token"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "token",
+        help = "read the required authentication token from a file"
+    )]
+    token_file: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "log filter, e.g. debug or raptorboost=trace [default: $RUST_LOG or info]"
+    )]
+    log_level: Option<String>,
+    #[arg(long, action=ArgAction::Help)]
+    help: Option<bool>,
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("couldn't install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Expand a leading `~` and make `path` absolute, creating it first if
+/// `mkdir` is set.
+fn resolve_out_dir(path: &Path, mkdir: bool) -> Result<PathBuf, String> {
+    let path = match path.strip_prefix("~") {
+        Ok(rest) => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(rest),
+            None => return Err("can't expand ~ without $HOME".to_string()),
+        },
+        Err(_) => path.to_path_buf(),
+    };
+
+    if mkdir {
+        std::fs::create_dir_all(&path)
+            .map_err(|e| format!("couldn't create {}: {}", path.display(), e))?;
+    }
+
+    path.canonicalize().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => format!(
+            "{} doesn't exist (pass --mkdir to create it)",
+            path.display()
+        ),
+        _ => format!("{}: {}", path.display(), e),
+    })
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("couldn't read '{}': {}", path.display(), e))
+}
+
+fn load_tls_config(args: &Args) -> Result<ServerTlsConfig, String> {
+    let (Some(cert), Some(key)) = (&args.cert, &args.key) else {
+        return Err("tls requires both --cert and --key".to_string());
+    };
+
+    let mut tls_config =
+        ServerTlsConfig::new().identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+
+    if let Some(ca_cert) = &args.ca_cert {
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(read_pem(ca_cert)?));
+    }
+
+    Ok(tls_config)
+}
+
+/// Parse a bind address, also accepting a bracketed ipv6 address like `[::1]`.
+fn parse_host(host: &str) -> Result<IpAddr, AddrParseError> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    IpAddr::from_str(host)
+}
+
+/// Resolve an `--interface` value to the addresses to listen on: `any` is
+/// the unspecified address, an interface name gives that interface's best
+/// address, and anything else is taken as a prefix of local addresses.
+fn interface_ips(interface: &str, prefer_v6: bool) -> Result<Vec<IpAddr>, String> {
+    if interface == "any" {
+        return Ok(vec![match prefer_v6 {
+            true => Ipv6Addr::UNSPECIFIED.into(),
+            false => Ipv4Addr::UNSPECIFIED.into(),
+        }]);
+    }
+
+    let netifas = list_afinet_netifas()
+        .map_err(|e| format!("couldn't get list of local interfaces: {}", e))?;
+
+    // for ipv6, prefer addresses that aren't link-local (those need a scope
+    // id to bind)
+    let rank = |ip: &IpAddr| match ip {
+        IpAddr::V6(v6) if prefer_v6 && !v6.is_unicast_link_local() => 0,
+        IpAddr::V6(_) if prefer_v6 => 1,
+        IpAddr::V4(_) if !prefer_v6 => 0,
+        _ => 2,
+    };
+
+    if let Some(ip) = netifas
+        .iter()
+        .filter(|(name, _)| name == interface)
+        .map(|(_, ip)| *ip)
+        .min_by_key(rank)
+    {
+        return Ok(vec![ip]);
+    }
+
+    let mut ips: Vec<IpAddr> = netifas
+        .into_iter()
+        .map(|(_, ip)| ip)
+        .filter(|ip| ip.to_string().starts_with(interface))
+        .collect();
+    ips.sort();
+    ips.dedup();
+
+    match ips.is_empty() {
+        true => Err(format!(
+            "couldn't find an interface or address matching {}",
+            interface
+        )),
+        false => Ok(ips),
+    }
+}
+
+/// Bind a listening socket. Binding the ipv6 unspecified address also
+/// accepts ipv4 connections unless `ipv6_only` is set.
+fn bind(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Where the server accepts connections.
+enum Listener {
+    Tcp(StreamMap<SocketAddr, TcpIncoming>),
+    #[cfg(unix)]
+    Unix(tokio_stream::wrappers::UnixListenerStream, PathBuf),
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, force: bool) -> Result<Listener, String> {
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+
+    if path.symlink_metadata().is_ok() {
+        if !force {
+            return Err(format!(
+                "'{}' already exists, use --force to replace it",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| format!("couldn't remove '{}': {}", path.display(), e))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("couldn't bind to '{}': {}", path.display(), e))?;
+    info!("listening on {}", path.display());
+    Ok(Listener::Unix(
+        UnixListenerStream::new(listener),
+        path.to_path_buf(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path, _force: bool) -> Result<Listener, String> {
+    Err("unix sockets aren't supported on this platform".to_string())
+}
+
+fn bind_tcp(args: &Args) -> Result<Listener, String> {
+    let mut ips: Vec<(IpAddr, &str)> = Vec::new();
+    if args.interface.is_empty() {
+        let ip = parse_host(&args.host)
+            .map_err(|e| format!("couldn't parse host '{}': {}", args.host, e))?;
+        ips.push((ip, &args.host));
+    }
+    for interface in &args.interface {
+        for ip in interface_ips(interface, args.ipv6)? {
+            if let Some((_, other)) = ips.iter().find(|(i, _)| *i == ip) {
+                return Err(format!(
+                    "interfaces {} and {} both resolve to {}",
+                    other, interface, ip
+                ));
+            }
+            ips.push((ip, interface));
+        }
+    }
+
+    let mut incoming = StreamMap::new();
+    for (ip, _) in ips {
+        let addr = SocketAddr::new(ip, args.port);
+        let listener =
+            bind(addr, args.ipv6_only).map_err(|e| format!("couldn't bind to {}: {}", addr, e))?;
+
+        match listener.local_addr().unwrap_or(addr) {
+            a if a.ip().is_unspecified() && a.is_ipv6() && !args.ipv6_only => {
+                info!("listening on {} (ipv4 and ipv6)", a)
+            }
+            a => info!("listening on {}", a),
+        }
+
+        incoming.insert(addr, TcpIncoming::from(listener).with_nodelay(Some(true)));
+    }
+
+    Ok(Listener::Tcp(incoming))
+}
+
+// upper bound on how often stale partials are looked for
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Collect stale partials now and then periodically, for as long as the
+/// server runs.
+async fn gc_partials(controller: Arc<controller::RaptorBoostController>, max_age: Duration) {
+    let mut interval = tokio::time::interval(max_age.clamp(Duration::from_secs(60), GC_INTERVAL));
+    loop {
+        interval.tick().await;
+        let controller = controller.clone();
+        match tokio::task::spawn_blocking(move || controller.gc_partials(max_age)).await {
+            Ok(Ok(collected)) if !collected.is_empty() => {
+                info!("removed {} stale partial files", collected.len())
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("couldn't collect stale partials: {}", e),
+            Err(e) => warn!("partial collection panicked: {}", e),
+        }
+    }
+}
+
+/// Reopen the access log on each SIGHUP, so it can be rotated by moving it
+/// away, for as long as the server runs.
+#[cfg(unix)]
+async fn reopen_on_sighup(access_log: Arc<AccessLog>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            warn!("couldn't install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        match access_log.reopen() {
+            Ok(()) => info!("reopened access log"),
+            Err(e) => warn!("couldn't reopen access log: {}", e),
+        }
+    }
+}
+
+/// Re-hash the whole store, printing the checksum of each corrupt file on
+/// stdout. Fails if there are any.
+async fn verify_store(controller: controller::RaptorBoostController) -> ExitCode {
+    let multibar = progress::multi_progress(ProgressMode::Auto, VERIFY_PROGRESS_INTERVAL);
+    let bar = multibar.add(
+        ProgressBar::new(0).with_style(
+            ProgressStyle::with_template(
+                "verifying [{elapsed_precise}] [eta: {eta_precise}] {wide_bar} \
+                 [{decimal_bytes:>7}/{decimal_total_bytes:7}]",
+            )
+            .unwrap(),
+        ),
+    );
+
+    let verified = tokio::task::spawn_blocking({
+        let bar = bar.clone();
+        move || {
+            controller.verify_store(|done, total| {
+                bar.set_length(total);
+                bar.set_position(done);
+            })
+        }
+    })
+    .await;
+    bar.finish_and_clear();
+
+    let corrupt = match verified {
+        Ok(Ok(corrupt)) => corrupt,
+        Ok(Err(e)) => {
+            error!("couldn't verify store: {}", e);
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            error!("verify panicked: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for f in &corrupt {
+        println!("{}  {}", f.sha256sum, f.problem);
+    }
+    if corrupt.is_empty() {
+        info!("all stored files verified");
+        ExitCode::SUCCESS
+    } else {
+        error!("{} stored files are corrupt", corrupt.len());
+        ExitCode::FAILURE
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    logging::init(args.log_level.as_deref(), "info");
+
+    let token = match read_token(args.token.clone(), args.token_file.as_deref()) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("couldn't read token: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let tls_config = if args.tls {
+        match load_tls_config(&args) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                error!("couldn't load tls config: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let store_dirs = controller::StoreDirs {
+        partial: args.partial_dir.clone(),
+        complete: args.complete_dir.clone(),
+        transfers: args.transfers_dir.clone(),
+        lock: args.lock_dir.clone(),
+    };
+
+    let out_dir = match resolve_out_dir(&args.out_dir, args.mkdir) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("bad output directory: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    info!("storing files in {}", out_dir.display());
+
+    let controller = match controller::RaptorBoostController::new(&out_dir, &store_dirs) {
+        Ok(c) => c
+            .with_verify_partials(args.verify_partials)
+            .with_trust_client_checksum(args.trust_client_checksum)
+            .with_isolate_clients(args.isolate_clients)
+            .with_durable(args.durable)
+            .with_link_mode(args.link_mode)
+            .with_lock_timeout(args.lock_timeout)
+            .with_mmap(!args.no_mmap)
+            .with_partial_quota(args.partial_quota.map(|q| q as u64))
+            .with_evict_partials(args.evict_partials)
+            .with_shard_depth(args.shard_depth.into())
+            .with_hash_algo(args.hash),
+        Err(e) => {
+            error!("couldn't create controller: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.reshard {
+        return match controller.reshard() {
+            Ok((moved, relinked)) => {
+                info!(
+                    "moved {} files and repointed {} names to a shard depth of {}",
+                    moved,
+                    relinked,
+                    controller.shard_depth()
+                );
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("couldn't reshard store: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    // files laid out for another depth would look missing, and be received
+    // all over again
+    match controller.stored_shard_depth() {
+        Ok(Some(depth)) if depth != controller.shard_depth() => {
+            error!(
+                "the store is sharded {} deep, not {}; rerun with --reshard to move it",
+                depth,
+                controller.shard_depth()
+            );
+            return ExitCode::FAILURE;
+        }
+        Ok(_) => {}
+        Err(e) => warn!("couldn't check the store's layout: {}", e),
+    }
+
+    if args.verify {
+        return verify_store(controller).await;
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (serving_tx, serving_rx) = watch::channel(true);
+
+    let controller = Arc::new(controller);
+
+    if let Some(max_age) = args.gc_partial_age {
+        tokio::spawn(gc_partials(controller.clone(), max_age));
+    }
+
+    let access_log = match &args.access_log {
+        Some(path) => match AccessLog::open(path) {
+            Ok(log) => Some(Arc::new(
+                log.with_max_size(args.access_log_max_size.map(|s| s as u64)),
+            )),
+            Err(e) => {
+                error!("couldn't open access log {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    #[cfg(unix)]
+    if let Some(access_log) = &access_log {
+        tokio::spawn(reopen_on_sighup(access_log.clone()));
+    }
+
+    let rb_service = service::RaptorBoostService {
+        controller,
+        shutdown: shutdown_rx.clone(),
+        transfer_slots: args
+            .max_concurrent_transfers
+            .map(|n| Arc::new(Semaphore::new(n.get()))),
+        hooks: Hooks::default()
+            .with_on_complete(args.on_complete.clone())
+            .with_on_assign(args.on_assign.clone()),
+        access_log,
+    };
+
+    let listener = match &args.unix_socket {
+        Some(path) => bind_unix(path, args.force),
+        None => bind_tcp(&args),
+    };
+    let listener = match listener {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut builder = Server::builder().max_concurrent_streams(100);
+    if !args.keepalive_interval.is_zero() {
+        builder = builder
+            .http2_keepalive_interval(Some(args.keepalive_interval))
+            .http2_keepalive_timeout(Some(args.keepalive_timeout));
+    }
+
+    if let Some(tls_config) = tls_config {
+        builder = match builder.tls_config(tls_config) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("invalid tls config: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+    }
+
+    let drain_delay = args.drain_delay;
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        let _ = serving_tx.send(false);
+        if !drain_delay.is_zero() {
+            info!("draining for {:?} before shutting down...", drain_delay);
+            // a second signal skips the rest of the wait
+            tokio::select! {
+                _ = tokio::time::sleep(drain_delay) => {}
+                _ = wait_for_signal() => {}
+            }
+        }
+        info!("shutting down, waiting for in-flight transfers...");
+        let _ = shutdown_tx.send(true);
+    });
+
+    let shutdown_started = {
+        let mut shutdown_rx = shutdown_rx.clone();
+        async move {
+            let _ = shutdown_rx.wait_for(|s| *s).await;
+        }
+    };
+
+    let shutdown_deadline = {
+        let mut shutdown_rx = shutdown_rx.clone();
+        async move {
+            let _ = shutdown_rx.wait_for(|s| *s).await;
+            tokio::time::sleep(args.shutdown_timeout).await;
+        }
+    };
+
+    // health checks come from supervisors that don't have the token
+    let router = builder
+        .add_service(InterceptedService::new(
+            RaptorBoostServer::new(rb_service)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd),
+            TokenAuth::new(token),
+        ))
+        .add_service(HealthServer::new(HealthService {
+            serving: serving_rx,
+        }));
+
+    let (server, socket_path): (Pin<Box<dyn Future<Output = _>>>, _) = match listener {
+        Listener::Tcp(incoming) => (
+            Box::pin(
+                router
+                    .serve_with_incoming_shutdown(incoming.map(|(_, conn)| conn), shutdown_started),
+            ),
+            None,
+        ),
+        #[cfg(unix)]
+        Listener::Unix(incoming, path) => (
+            Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown_started)),
+            Some(path),
+        ),
+    };
+
+    // returning from main drops the runtime, and with it any straggling
+    // transfers, which releases their locks and leaves partials for resume
+    let code = tokio::select! {
+        r = server => match r {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("error from grpc server: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        _ = shutdown_deadline => {
+            error!("timed out waiting for in-flight transfers");
+            ExitCode::FAILURE
+        }
+    };
+
+    if let Some(path) = socket_path
+        && let Err(e) = std::fs::remove_file(&path)
+    {
+        warn!("couldn't remove '{}': {}", path.display(), e);
+    }
+
+    code
+}
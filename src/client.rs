@@ -1,41 +1,58 @@
-mod proto {
-    tonic::include_proto!("raptorboost");
-}
-use crate::proto::SendFileDataResponse;
-use proto::raptor_boost_client::RaptorBoostClient;
-use proto::{AssignNamesRequest, FileData, FileStateResult, Sha256Filenames};
-
-use crate::proto::UploadFilesRequest;
-
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{self, ErrorKind, Read};
-use std::io::{BufReader, Seek, SeekFrom};
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
+use bytes::{Bytes, BytesMut};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response};
-use walkdir::WalkDir;
+use tonic::metadata::errors::InvalidMetadataValue;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Streaming};
+use tracing::{debug, warn};
+
+use crate::archive::{self, EntryKind};
+use crate::hash::HashAlgo;
+use crate::proto::raptor_boost_client::RaptorBoostClient;
+use crate::proto::{
+    self, AssignNamesRequest, AssignNamesResponse, DeleteFilesRequest, DeleteFilesResponse,
+    FileAttributes, FileData, FileState, FileStateResult, GetHistoryRequest, GetStatsRequest,
+    GetStatsResponse, GetVersionRequest, HistoryEntry, ListFilesRequest, ListedFile,
+    Sha256Filenames, Symlink, UploadFilesRequest, UploadFilesResponse,
+};
+use crate::ratelimit::{ByteCap, RateLimiter};
 
+/// Reads straight into a shared buffer and hands out each chunk as a slice
+/// of it. Once the chunks sent earlier have been dropped, the buffer's
+/// memory is reused for the next one rather than allocated again. Empty
+/// input still yields a single empty chunk, so an empty file goes out as one
+/// last packet like the end of any other.
 pub struct ToChunks<R> {
     reader: R,
+    buffer: BytesMut,
     chunk_size: usize,
+    yielded: bool,
 }
 
 impl<R: Read> Iterator for ToChunks<R> {
-    type Item = io::Result<Vec<u8>>;
+    type Item = io::Result<Bytes>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = vec![0u8; self.chunk_size];
-        match self.reader.read(&mut buffer) {
-            Ok(0) => None,
-            Ok(n) => Some(Ok(buffer[..n].to_vec())),
+        self.buffer.resize(self.chunk_size, 0);
+        match self.reader.read(&mut self.buffer) {
+            Ok(0) if self.yielded => None,
+            Ok(n) => {
+                self.yielded = true;
+                let chunk = self.buffer.split_to(n).freeze();
+                self.buffer.clear();
+                Some(Ok(chunk))
+            }
             Err(e) => Some(Err(e)),
         }
     }
@@ -53,19 +70,107 @@ impl<R: Read> IterChunks for R {
     fn iter_chunks(self, len: usize) -> Self::Output {
         ToChunks {
             reader: self,
+            buffer: BytesMut::with_capacity(len),
             chunk_size: len,
+            yielded: false,
         }
     }
 }
 
-struct FilenameWithState {
-    filename: String,
-    sha256sum: String,
-    offset: u64,
+/// A file as it was when it was checksummed.
+#[derive(Clone, Debug)]
+pub struct HashedFile {
+    pub filename: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl HashedFile {
+    pub fn new(filename: String, metadata: &fs::Metadata) -> HashedFile {
+        HashedFile {
+            filename,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+pub struct FilenameWithState {
+    pub filename: String,
+    pub sha256sum: String,
+    pub offset: u64,
+    /// Whether the server has a partial of the file, even an empty one, as
+    /// opposed to never having seen it.
+    pub partial: bool,
+    /// The file's size when it was checksummed. Only this much is sent.
+    pub size: u64,
+    /// The file's mtime when it was checksummed, if the platform has one.
+    pub modified: Option<SystemTime>,
+    /// Other paths with the same content, read instead if this one has
+    /// changed since it was checksummed.
+    pub alternates: Vec<HashedFile>,
+}
+
+/// The server's answer for one of the hashes given to `check_files`.
+pub enum CheckedFile {
+    /// The server wants the file, from `offset` on.
+    Needed(FilenameWithState),
+    /// The server already has it.
+    Complete,
+}
+
+/// The answers to `check_files`, in the order the server gives them.
+pub struct CheckedFiles<'a> {
+    stream: Streaming<UploadFilesResponse>,
+    batch: VecDeque<FileState>,
+    hashed_files: &'a HashMap<String, Vec<HashedFile>>,
+}
+
+impl CheckedFiles<'_> {
+    /// The next answer, or None once the server has answered for every hash.
+    pub async fn next(&mut self) -> Result<Option<CheckedFile>, tonic::Status> {
+        loop {
+            let Some(fs) = self.batch.pop_front() else {
+                match self.stream.message().await? {
+                    Some(batch) => self.batch = batch.file_states.into(),
+                    None => return Ok(None),
+                }
+                continue;
+            };
+            match fs.state() {
+                FileStateResult::FilestateresultUnspecified => {
+                    debug!("unspecified state for {}", fs.sha256sum)
+                }
+                FileStateResult::FilestateresultNeedMoreData => {
+                    let offset = fs.offset();
+                    let Some((file, alternates)) = self
+                        .hashed_files
+                        .get(&fs.sha256sum)
+                        .and_then(|files| files.split_first())
+                    else {
+                        debug!("server asked for unknown hash {}", fs.sha256sum);
+                        continue;
+                    };
+                    return Ok(Some(CheckedFile::Needed(FilenameWithState {
+                        filename: file.filename.clone(),
+                        sha256sum: fs.sha256sum,
+                        offset,
+                        partial: fs.offset.is_some(),
+                        size: file.size,
+                        modified: file.modified,
+                        alternates: alternates.to_vec(),
+                    })));
+                }
+                FileStateResult::FilestateresultComplete => {
+                    return Ok(Some(CheckedFile::Complete));
+                }
+            }
+        }
+    }
 }
 
 #[derive(Error, Debug)]
-enum SendFileError {
+pub enum SendFileError {
     #[error(transparent)]
     ConnectError(#[from] tonic::transport::Error),
     #[error("open error")]
@@ -76,354 +181,1266 @@ enum SendFileError {
     ResponseError(#[from] tonic::Status),
     #[error("checksum mismatch")]
     ChecksumMismatch,
+    #[error("size mismatch")]
+    SizeMismatch,
+    #[error("timed out waiting for the server")]
+    Timeout,
+    #[error("chunk corrupted in transit at offset {offset}")]
+    ChunkCorrupted { offset: u64 },
+    #[error("server's partial is at offset {offset}, not where we resumed from")]
+    OffsetMismatch { offset: u64 },
+    #[error("upload ended partway through a file, at offset {offset}")]
+    Incomplete { offset: u64 },
+    #[error("disappeared since it was checksummed")]
+    Disappeared,
+    #[error("shrank from {expected} to {actual} bytes since it was checksummed")]
+    Shrank { expected: u64, actual: u64 },
+    #[error("changed since it was checksummed")]
+    Changed,
+    #[error("not started, the byte cap was reached")]
+    ByteCapReached,
+    #[error("sent, but the server doesn't have it complete")]
+    NotComplete,
     #[error(transparent)]
     OtherError(#[from] std::io::Error),
     #[error("unspecified error")]
     UnspecifiedError,
 }
 
-async fn send_files(
-    mut client: RaptorBoostClient<tonic::transport::Channel>,
-    files: Vec<FilenameWithState>,
-    total_bytes: u64,
-    force_unlock: bool,
-    multibar: MultiProgress,
-) -> Result<(), SendFileError> {
-    let filename_bar = multibar.add(
-        ProgressBar::new(0).with_style(ProgressStyle::with_template("sending {msg}...").unwrap()),
-    );
-
-    let total_file_size_bar = multibar.add(
-        ProgressBar::new(total_bytes).with_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] \
+impl SendFileError {
+    /// Whether the error came from the connection rather than the transfer
+    /// itself, so that reconnecting and resuming has a chance of working.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SendFileError::ConnectError(_)
+            | SendFileError::Timeout
+            | SendFileError::ChunkCorrupted { .. }
+            | SendFileError::OffsetMismatch { .. }
+            | SendFileError::Incomplete { .. } => true,
+            SendFileError::ResponseError(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the file changed on disk after it was checksummed, so it was
+    /// skipped rather than failed. These never stop the upload, even with
+    /// `fail_fast`.
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            SendFileError::Disappeared | SendFileError::Shrank { .. } | SendFileError::Changed
+        )
+    }
+}
+
+/// A connection to a raptorboost server.
+#[derive(Clone)]
+pub struct Client {
+    inner: RaptorBoostClient<Channel>,
+    authorization: Option<MetadataValue<Ascii>>,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    pub fn new(inner: RaptorBoostClient<Channel>) -> Client {
+        Client {
+            inner,
+            authorization: None,
+            timeout: None,
+        }
+    }
+
+    /// Give up on a call when the server hasn't answered within `timeout`.
+    /// Uploads can take arbitrarily long, so `send_file_data` only times out
+    /// once it has made no progress for that long.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Authenticate every request with `token`.
+    pub fn with_token(mut self, token: &str) -> Result<Self, InvalidMetadataValue> {
+        self.authorization = Some(format!("Bearer {}", token).parse()?);
+        Ok(self)
+    }
+
+    fn authorized<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        request
+    }
+
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = self.authorized(message);
+        if let Some(timeout) = self.timeout {
+            request.set_timeout(timeout);
+        }
+        request
+    }
+
+    pub async fn get_version(&mut self) -> Result<String, tonic::Status> {
+        let request = self.request(GetVersionRequest {});
+        Ok(self.inner.get_version(request).await?.into_inner().version)
+    }
+
+    /// Where the server keeps its store, if it's on this host. Sending
+    /// anything from in there would feed the store back into itself.
+    pub async fn store_dirs(&mut self) -> Result<Vec<PathBuf>, tonic::Status> {
+        let request = self.request(GetVersionRequest {});
+        let response = self.inner.get_version(request).await?.into_inner();
+        Ok(response.store_dirs.into_iter().map(PathBuf::from).collect())
+    }
+
+    pub async fn connect(endpoint: Endpoint) -> Result<Client, tonic::transport::Error> {
+        Ok(Client::new(RaptorBoostClient::new(
+            endpoint.connect().await?,
+        )))
+    }
+
+    /// The underlying generated grpc client, for calls not wrapped here.
+    pub fn inner(&mut self) -> &mut RaptorBoostClient<Channel> {
+        &mut self.inner
+    }
+
+    /// Ask the server which of `sha256es` it still needs, with `hashed_files`
+    /// giving the files behind each hash. The hashes are sent in batches as
+    /// the server takes them, and its answers can be read back while it's
+    /// still working through the rest. Each hash should be given once.
+    pub async fn check_files<'a, I>(
+        &mut self,
+        sha256es: I,
+        hashed_files: &'a HashMap<String, Vec<HashedFile>>,
+        hash_algo: HashAlgo,
+    ) -> Result<CheckedFiles<'a>, tonic::Status>
+    where
+        I: IntoIterator<Item = String>,
+        I::IntoIter: Send + 'static,
+    {
+        const BATCH: usize = 1000;
+        let mut sha256es = sha256es.into_iter();
+        let requests = std::iter::from_fn(move || {
+            let sha256sums: Vec<String> = sha256es.by_ref().take(BATCH).collect();
+            (!sha256sums.is_empty()).then(|| UploadFilesRequest {
+                sha256sums,
+                hash_algorithm: proto::HashAlgorithm::from(hash_algo).into(),
+            })
+        });
+
+        let response = self
+            .inner
+            .upload_files(self.request(tokio_stream::iter(requests)))
+            .await?;
+        Ok(CheckedFiles {
+            stream: response.into_inner(),
+            batch: VecDeque::new(),
+            hashed_files,
+        })
+    }
+
+    /// Like `check_files`, but waiting for every answer. Each hash is sent
+    /// once, from the first of its files. Returns the files to send with
+    /// their resume offsets, the number of bytes left to send, and how many
+    /// files are already up to date.
+    pub async fn upload_files(
+        &mut self,
+        sorted_sha256es: &[String],
+        hashed_files: &HashMap<String, Vec<HashedFile>>,
+        hash_algo: HashAlgo,
+    ) -> Result<(Vec<FilenameWithState>, u64, u64), tonic::Status> {
+        // files with the same content share a hash, and it only needs
+        // checking once
+        let mut seen: HashSet<&String> = HashSet::new();
+        let unique_sha256es: Vec<String> = sorted_sha256es
+            .iter()
+            .filter(|s| seen.insert(*s))
+            .cloned()
+            .collect();
+        let mut checked = self
+            .check_files(unique_sha256es, hashed_files, hash_algo)
+            .await?;
+
+        let mut to_send: Vec<FilenameWithState> = Vec::new();
+        let mut total_to_send: u64 = 0;
+        let mut num_files_up_to_date: u64 = 0;
+        while let Some(file) = checked.next().await? {
+            match file {
+                CheckedFile::Needed(file) => {
+                    total_to_send += file.size.saturating_sub(file.offset);
+                    to_send.push(file);
+                }
+                CheckedFile::Complete => num_files_up_to_date += 1,
+            }
+        }
+
+        Ok((to_send, total_to_send, num_files_up_to_date))
+    }
+
+    /// Stream `files` to the server in a single call, starting on each as
+    /// soon as it arrives and finishing once the sender is dropped. No call
+    /// is made if no files arrive. Per-file read errors and the files the
+    /// server rejected are collected and returned unless `fail_fast` is set.
+    pub async fn send_file_data(
+        &mut self,
+        mut files: mpsc::Receiver<FilenameWithState>,
+        opts: SendOptions,
+        multibar: MultiProgress,
+    ) -> Result<Vec<(String, SendFileError)>, SendFileError> {
+        let SendOptions {
+            force_unlock,
+            chunk_size,
+            fail_fast,
+            hash_algo,
+            rate_limiter,
+            byte_cap,
+            on_event,
+            archive,
+        } = opts;
+        let Some(first) = files.recv().await else {
+            return Ok(Vec::new());
+        };
+        let emit = move |event: SendEvent| {
+            if let Some(on_event) = &on_event {
+                on_event(event);
+            }
+        };
+
+        let filename_bar = multibar.add(
+            ProgressBar::new(0)
+                .with_style(ProgressStyle::with_template("sending {msg}...").unwrap()),
+        );
+
+        // grows as files are queued
+        let total_file_size_bar = multibar.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] \
                  [eta: {eta_precise}] \
                  {wide_bar} \
                  [{decimal_bytes:>7}/{decimal_total_bytes:7}] \
                  [{decimal_bytes_per_sec}]",
-            )
-            .unwrap(),
-        ),
-    );
-
-    let (tx, rx) = mpsc::channel::<FileData>(1);
-
-    let send_task: tokio::task::JoinHandle<Result<(), SendFileError>> = tokio::spawn({
-        let total_file_size_bar = total_file_size_bar.clone();
-        async move {
-            for file in files {
-                let file_size = std::fs::metadata(&file.filename)
-                    .map_err(|source| SendFileError::OpenError { source })?
-                    .len();
-
-                let remaining = file_size.saturating_sub(file.offset);
-
-                let mut f = File::open(&file.filename)
-                    .map_err(|source| SendFileError::OpenError { source })?;
-                f.seek(SeekFrom::Start(file.offset))
-                    .map_err(|source| SendFileError::SeekError { source })?;
-
-                let freader = BufReader::new(f);
-
-                let truncated_filename = spat::shorten(PathBuf::from_str(&file.filename).unwrap())
-                    .display()
-                    .to_string();
-                filename_bar.set_message(truncated_filename);
-
-                // empty file (or partial with 0 bytes left): send a single empty frame
-                if remaining == 0 {
-                    let fdata = FileData {
-                        first: true,
-                        last: true,
-                        sha256sum: Some(file.sha256sum),
-                        force: Some(force_unlock),
-                        data: vec![],
-                    };
-                    if tx.send(fdata).await.is_err() {
-                        return Ok(());
+                )
+                .unwrap(),
+            ),
+        );
+
+        // the server reports back by hash; filled in as each file starts,
+        // which is before the server can say anything about it
+        let filenames: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+
+        // enough messages queued for the stream that lots of small files
+        // don't each wait on a round trip through the transport
+        let (tx, rx) = mpsc::channel::<FileData>((SEND_BUFFER / chunk_size.max(1)).max(1));
+        let last_progress = Arc::new(Mutex::new(Instant::now()));
+
+        // files are opened and read on their own thread, up to READ_AHEAD
+        // bytes ahead of what's been sent, so the next file is already
+        // being read while the current one is still going out
+        let (prepared_tx, mut prepared_rx) =
+            mpsc::channel::<Prepared>((READ_AHEAD / chunk_size.max(1)).max(2));
+        let (queued_tx, queued_rx) = mpsc::unbounded_channel::<FilenameWithState>();
+        tokio::spawn({
+            let total_file_size_bar = total_file_size_bar.clone();
+            async move {
+                let mut file = first;
+                loop {
+                    total_file_size_bar.inc_length(file.size.saturating_sub(file.offset));
+                    if queued_tx.send(file).is_err() {
+                        break;
+                    }
+                    match files.recv().await {
+                        Some(next) => file = next,
+                        None => break,
                     }
-                    continue;
                 }
+            }
+        });
+        tokio::task::spawn_blocking({
+            let byte_cap = byte_cap.clone();
+            let last_progress = last_progress.clone();
+            move || match archive {
+                Some(archive) => read_ahead_archive(
+                    &archive,
+                    queued_rx,
+                    chunk_size,
+                    byte_cap,
+                    last_progress,
+                    prepared_tx,
+                ),
+                None => read_ahead(queued_rx, chunk_size, byte_cap, last_progress, prepared_tx),
+            }
+        });
 
-                let mut first = true;
-                let mut pos: u64 = file.offset;
-
-                for d in freader.iter_chunks(8192) {
-                    let data = d?;
-                    pos += data.len() as u64;
-                    total_file_size_bar.inc(data.len() as u64);
-                    let fdata = if first {
-                        first = false;
-                        FileData {
-                            first: true,
-                            last: file_size == pos,
-                            sha256sum: Some(file.sha256sum.clone()),
-                            force: Some(force_unlock),
-                            data,
+        let send_task: tokio::task::JoinHandle<
+            Result<Vec<(String, SendFileError)>, SendFileError>,
+        > = tokio::spawn({
+            let total_file_size_bar = total_file_size_bar.clone();
+            let last_progress = last_progress.clone();
+            let filenames = filenames.clone();
+            async move {
+                let mut failures: Vec<(String, SendFileError)> = Vec::new();
+                // the file being sent, with how far into it we are; None
+                // between files and after a file failed
+                let mut current: Option<(FilenameWithState, u64, u64)> = None;
+
+                while let Some(prepared) = prepared_rx.recv().await {
+                    let data = match prepared {
+                        Prepared::Failed { file, error } => {
+                            finished(&current)?;
+                            if matches!(error, SendFileError::ByteCapReached) {
+                                // not a failure, just not started
+                            } else if error.is_skip() {
+                                warn!("skipping {}: {}", file.filename, error);
+                            } else if fail_fast {
+                                return Err(error);
+                            }
+                            total_file_size_bar.dec_length(file.size.saturating_sub(file.offset));
+                            failures.push((file.filename, error));
+                            continue;
                         }
-                    } else {
-                        FileData {
-                            first: false,
-                            last: file_size == pos,
-                            sha256sum: None,
-                            force: None,
-                            data,
+                        Prepared::File(file) => {
+                            finished(&current)?;
+                            // the reader stops opening files once it sees the
+                            // cap reached; any it had read ahead are left too
+                            if byte_cap.as_ref().is_some_and(|cap| cap.reached()) {
+                                total_file_size_bar
+                                    .dec_length(file.size.saturating_sub(file.offset));
+                                failures.push((file.filename, SendFileError::ByteCapReached));
+                                continue;
+                            }
+                            filenames
+                                .lock()
+                                .unwrap()
+                                .insert(file.sha256sum.clone(), file.filename.clone());
+                            let size = file.size;
+                            let truncated_filename =
+                                spat::shorten(PathBuf::from_str(&file.filename).unwrap())
+                                    .display()
+                                    .to_string();
+                            filename_bar.set_message(truncated_filename);
+                            emit(SendEvent::FileStarted {
+                                filename: &file.filename,
+                                sha256sum: &file.sha256sum,
+                                offset: file.offset,
+                                size,
+                            });
+
+                            let pos = file.offset;
+                            current = Some((file, size, pos));
+                            continue;
                         }
+                        Prepared::Chunk(data) => data,
                     };
+
+                    // anything after a failure has nowhere to go
+                    let Some((file, file_size, pos)) = current.as_mut() else {
+                        continue;
+                    };
+                    let first = *pos == file.offset;
+
+                    let data = match data {
+                        Ok(data) => data,
+                        // once data is on the wire the server holds an open
+                        // transfer for this file, so the stream can't move on
+                        Err(e) if fail_fast || !first => return Err(e.into()),
+                        Err(e) => {
+                            total_file_size_bar.dec_length(*file_size - *pos);
+                            failures.push((file.filename.clone(), e.into()));
+                            current = None;
+                            continue;
+                        }
+                    };
+                    if let Some(limiter) = &rate_limiter {
+                        limiter.throttle(data.len()).await;
+                        // waiting on ourselves doesn't count as a stall
+                        *last_progress.lock().unwrap() = Instant::now();
+                    }
+                    let len = data.len() as u64;
+                    *pos += len;
+                    total_file_size_bar.inc(len);
+                    if let Some(cap) = &byte_cap {
+                        cap.add(len);
+                    }
+                    let fdata = file_data(file, *pos - len, data, force_unlock, hash_algo);
+                    let last = fdata.last;
                     if tx.send(fdata).await.is_err() {
-                        return Ok(());
+                        return Ok(failures);
+                    }
+                    *last_progress.lock().unwrap() = Instant::now();
+                    if len != 0 {
+                        emit(SendEvent::BytesSent {
+                            filename: &file.filename,
+                            bytes: len,
+                            position: *pos,
+                        });
+                    }
+                    if last {
+                        emit(SendEvent::FileSent {
+                            filename: &file.filename,
+                            sha256sum: &file.sha256sum,
+                        });
+                        current = None;
+                    }
+                }
+                finished(&current)?;
+                Ok(failures)
+            }
+        });
+
+        // the deadline is measured from the last chunk the server took, or
+        // from the end of the stream while it finishes up
+        let stalled = {
+            let timeout = self.timeout;
+            let last_progress = last_progress.clone();
+            async move {
+                let Some(timeout) = timeout else {
+                    return std::future::pending().await;
+                };
+                loop {
+                    let idle = last_progress.lock().unwrap().elapsed();
+                    if idle >= timeout {
+                        return;
+                    }
+                    tokio::time::sleep(timeout - idle).await;
+                }
+            }
+        };
+
+        let request = self.authorized(ReceiverStream::new(rx));
+        let results = async {
+            let mut results = self.inner.send_file_data(request).await?.into_inner();
+            let mut rejected: Vec<(String, SendFileError)> = Vec::new();
+            while let Some(resp) = results.message().await? {
+                *last_progress.lock().unwrap() = Instant::now();
+                let sha256sum = resp.sha256sum();
+                let filename = filenames
+                    .lock()
+                    .unwrap()
+                    .get(sha256sum)
+                    .cloned()
+                    .unwrap_or_else(|| sha256sum.to_string());
+                let e = match resp.status() {
+                    proto::SendFileDataStatus::SendfiledatastatusComplete => continue,
+                    proto::SendFileDataStatus::SendfiledatastatusHashing => {
+                        debug!(
+                            "server has re-hashed {} bytes of {} to resume it",
+                            resp.offset(),
+                            filename
+                        );
+                        continue;
+                    }
+                    proto::SendFileDataStatus::SendfiledatastatusUnspecified => {
+                        SendFileError::UnspecifiedError
                     }
+                    proto::SendFileDataStatus::SendfiledatastatusErrorChecksum => {
+                        SendFileError::ChecksumMismatch
+                    }
+                    proto::SendFileDataStatus::SendfiledatastatusErrorSize => {
+                        SendFileError::SizeMismatch
+                    }
+                    // these end the upload, and a retry picks up from the offset
+                    proto::SendFileDataStatus::SendfiledatastatusErrorChunk => {
+                        return Err(SendFileError::ChunkCorrupted {
+                            offset: resp.offset(),
+                        });
+                    }
+                    proto::SendFileDataStatus::SendfiledatastatusErrorOffset => {
+                        return Err(SendFileError::OffsetMismatch {
+                            offset: resp.offset(),
+                        });
+                    }
+                    proto::SendFileDataStatus::SendfiledatastatusErrorIncomplete => {
+                        return Err(SendFileError::Incomplete {
+                            offset: resp.offset(),
+                        });
+                    }
+                };
+                if fail_fast {
+                    return Err(e);
                 }
+                rejected.push((filename, e));
             }
-            Ok(())
+            Ok(rejected)
+        };
+        let rejected = tokio::select! {
+            rejected = results => rejected,
+            _ = stalled => return Err(SendFileError::Timeout),
+        };
+
+        // surface any producer-side error
+        let mut failures = match send_task.await {
+            Ok(Ok(failures)) => failures,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => vec![],
+        };
+
+        failures.extend(rejected?);
+        Ok(failures)
+    }
+
+    /// Name the uploaded content, one name per client-side path. With
+    /// `attributes` each path's mode and mtime are sent along, as it returns
+    /// them (see `file_attributes`). `directories` are created empty
+    /// alongside the names, and `symlinks` recreated as they are. With
+    /// `manifest` the server also writes a manifest of the names it
+    /// assigned, and says where.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn assign_names(
+        &mut self,
+        name: Option<String>,
+        force: bool,
+        manifest: bool,
+        attributes: Option<&(dyn Fn(&str) -> FileAttributes + Sync)>,
+        sha256_to_filenames: HashMap<String, Vec<String>>,
+        directories: Vec<String>,
+        symlinks: Vec<Symlink>,
+    ) -> Result<AssignNamesResponse, tonic::Status> {
+        const ASSIGN_BATCH: usize = 200;
+        // sorted, so names are assigned and reported in the same order on
+        // every run
+        let mut sha256_to_filenames: Vec<(String, Vec<String>)> =
+            sha256_to_filenames.into_iter().collect();
+        sha256_to_filenames.sort();
+        let owned: Vec<Sha256Filenames> = sha256_to_filenames
+            .into_iter()
+            .map(|(sha256sum, names)| {
+                let attributes = match attributes {
+                    Some(attributes) => names.iter().map(|n| attributes(n)).collect(),
+                    None => vec![],
+                };
+                Sha256Filenames {
+                    sha256sum,
+                    names,
+                    attributes,
+                }
+            })
+            .collect();
+
+        let mut messages: Vec<AssignNamesRequest> =
+            Vec::with_capacity(owned.len() / ASSIGN_BATCH + 1);
+        messages.push(AssignNamesRequest {
+            name,
+            force: force.then_some(true),
+            sha256_to_filenames: vec![],
+            directories: vec![],
+            manifest: manifest.then_some(true),
+            symlinks: vec![],
+        });
+        for chunk in owned.chunks(ASSIGN_BATCH) {
+            messages.push(AssignNamesRequest {
+                name: None,
+                force: None,
+                sha256_to_filenames: chunk.to_vec(),
+                directories: vec![],
+                manifest: None,
+                symlinks: vec![],
+            });
+        }
+        for chunk in directories.chunks(ASSIGN_BATCH) {
+            messages.push(AssignNamesRequest {
+                name: None,
+                force: None,
+                sha256_to_filenames: vec![],
+                directories: chunk.to_vec(),
+                manifest: None,
+                symlinks: vec![],
+            });
+        }
+        for chunk in symlinks.chunks(ASSIGN_BATCH) {
+            messages.push(AssignNamesRequest {
+                name: None,
+                force: None,
+                sha256_to_filenames: vec![],
+                directories: vec![],
+                manifest: None,
+                symlinks: chunk.to_vec(),
+            });
         }
-    });
 
-    let request = Request::new(ReceiverStream::new(rx));
-    let resp: Result<Response<SendFileDataResponse>, tonic::Status> =
-        client.send_file_data(request).await;
+        let resp = self
+            .inner
+            .assign_names(self.request(tokio_stream::iter(messages)))
+            .await?;
 
-    // surface any producer-side error
-    if let Ok(Err(e)) = send_task.await {
-        return Err(e);
+        Ok(resp.into_inner())
     }
 
-    let resp = match resp {
-        Err(e) => {
-            eprintln!("err: {}", e);
-            return Err(SendFileError::UnspecifiedError);
+    /// Delete `sha256sums` from the server, or with `except` everything but
+    /// them. A dry run only reports what would go.
+    pub async fn delete_files(
+        &mut self,
+        sha256sums: Vec<String>,
+        except: bool,
+        dry_run: bool,
+    ) -> Result<DeleteFilesResponse, tonic::Status> {
+        const DELETE_BATCH: usize = 1000;
+
+        let mut messages: Vec<DeleteFilesRequest> = vec![DeleteFilesRequest {
+            sha256sums: vec![],
+            dry_run: Some(dry_run),
+            except: Some(except),
+        }];
+        for chunk in sha256sums.chunks(DELETE_BATCH) {
+            messages.push(DeleteFilesRequest {
+                sha256sums: chunk.to_vec(),
+                dry_run: None,
+                except: None,
+            });
         }
-        Ok(r) => r,
-    };
 
-    match resp.into_inner().status() {
-        proto::SendFileDataStatus::SendfiledatastatusUnspecified => {
-            eprintln!("\runspecified error occurred");
-            Err(SendFileError::UnspecifiedError)
+        let resp = self
+            .inner
+            .delete_files(self.request(tokio_stream::iter(messages)))
+            .await?;
+
+        Ok(resp.into_inner())
+    }
+
+    pub async fn list_files(&mut self) -> Result<Vec<ListedFile>, tonic::Status> {
+        let mut stream = self
+            .inner
+            .list_files(self.request(ListFilesRequest {}))
+            .await?
+            .into_inner();
+
+        let mut files = Vec::new();
+        while let Some(batch) = stream.message().await? {
+            files.extend(batch.files);
         }
-        proto::SendFileDataStatus::SendfiledatastatusComplete => Ok(()),
-        proto::SendFileDataStatus::SendfiledatastatusErrorChecksum => {
-            eprintln!("\rchecksum error!");
-            Err(SendFileError::ChecksumMismatch)
+
+        Ok(files)
+    }
+
+    /// The server's history journal, oldest first, optionally only for one
+    /// file.
+    pub async fn get_history(
+        &mut self,
+        sha256sum: Option<String>,
+    ) -> Result<Vec<HistoryEntry>, tonic::Status> {
+        let mut stream = self
+            .inner
+            .get_history(self.request(GetHistoryRequest { sha256sum }))
+            .await?
+            .into_inner();
+
+        let mut entries = Vec::new();
+        while let Some(batch) = stream.message().await? {
+            entries.extend(batch.entries);
         }
+
+        Ok(entries)
+    }
+
+    /// How full the server's store is.
+    pub async fn get_stats(&mut self) -> Result<GetStatsResponse, tonic::Status> {
+        let resp = self
+            .inner
+            .get_stats(self.request(GetStatsRequest {}))
+            .await?;
+
+        Ok(resp.into_inner())
     }
 }
 
-#[derive(Error, Debug)]
-#[error("{0}")]
-pub struct MainError(String);
-
-#[derive(Parser)]
-#[command(version, about)]
-struct Args {
-    #[arg(long, short, default_value = "7272")]
-    port: u16,
-    #[arg(short, long)]
-    name: Option<String>,
-    #[arg(long, action, help = "don't sort files by size")]
-    no_sort: bool,
-    #[arg(long, action)]
+// how much the reader thread may read ahead of what's been sent
+const READ_AHEAD: usize = 16 * 1024 * 1024;
+// how much of that may be queued for the transport; counted in full chunks,
+// so a chunk size this big or more queues one at a time
+const SEND_BUFFER: usize = 1024 * 1024;
+
+/// What the reader thread has ready for `send_file_data`, in file order.
+enum Prepared {
+    /// The file is open and its chunks follow.
+    File(FilenameWithState),
+    /// The next chunk of the last `File`. An error ends that file.
+    Chunk(io::Result<Bytes>),
+    /// `file` couldn't be opened, so none of it follows.
+    Failed {
+        file: FilenameWithState,
+        error: SendFileError,
+    },
+}
+
+/// Fail if the file being sent was cut short. The server has its first
+/// chunks by now, and a stream can't abandon a file halfway through.
+fn finished(current: &Option<(FilenameWithState, u64, u64)>) -> Result<(), SendFileError> {
+    match current {
+        Some((file, size, pos)) => {
+            warn!("{} shrank while it was being sent", file.filename);
+            Err(SendFileError::Shrank {
+                expected: *size,
+                actual: *pos,
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// The packet carrying `data`, read from `file` at `pos`. The first packet
+/// of a file says which file it is and where it starts; the one that
+/// reaches its size is the last, even when it's the first and empty.
+fn file_data(
+    file: &FilenameWithState,
+    pos: u64,
+    data: Bytes,
     force_unlock: bool,
-    #[arg(long, action, default_value = "false")]
-    force_name: bool,
-    #[arg(index = 1)]
-    host: String,
-    #[arg(trailing_var_arg = true, index = 2)]
-    files: Vec<String>,
+    hash_algo: HashAlgo,
+) -> FileData {
+    let last = pos + data.len() as u64 == file.size;
+    if pos == file.offset {
+        FileData {
+            first: true,
+            last,
+            sha256sum: Some(file.sha256sum.clone()),
+            force: Some(force_unlock),
+            hash_algorithm: proto::HashAlgorithm::from(hash_algo).into(),
+            total_size: Some(file.size),
+            crc32: Some(crc32fast::hash(&data)),
+            offset: Some(file.offset),
+            data,
+        }
+    } else {
+        FileData {
+            first: false,
+            last,
+            sha256sum: None,
+            force: None,
+            hash_algorithm: 0,
+            total_size: None,
+            crc32: Some(crc32fast::hash(&data)),
+            offset: None,
+            data,
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+/// Open `filename` at `offset`, as long as it still looks the way it did when
+/// it was checksummed. Sending a file that's changed would only fail the
+/// checksum once it's all been sent.
+fn open_unchanged(
+    filename: &str,
+    size: u64,
+    modified: Option<SystemTime>,
+    offset: u64,
+) -> Result<File, SendFileError> {
+    let mut f = File::open(filename).map_err(|source| match source.kind() {
+        ErrorKind::NotFound => SendFileError::Disappeared,
+        _ => SendFileError::OpenError { source },
+    })?;
 
-    if args.files.is_empty() {
-        return Err(MainError("no file(s) specified".to_string()).into());
+    let metadata = f.metadata()?;
+    if metadata.len() < size {
+        return Err(SendFileError::Shrank {
+            expected: size,
+            actual: metadata.len(),
+        });
+    }
+    if metadata.len() != size || metadata.modified().ok() != modified {
+        return Err(SendFileError::Changed);
     }
 
-    let mut deduped_filenames: HashSet<String> = HashSet::new();
+    f.seek(SeekFrom::Start(offset))
+        .map_err(|source| SendFileError::SeekError { source })?;
+    Ok(f)
+}
 
-    // 1: dedup files
-    for f in &args.files {
-        let fd = match File::open(f) {
-            Ok(fd) => fd,
-            Err(e) => return Err(MainError(format!("couldn't open '{}': {}", f, e)).into()),
+/// Open and read `files` in order as they're queued, blocking whenever the
+/// sender falls behind, until they're all read or the sender goes away.
+/// Files that have changed since they were checksummed are read from an
+/// unchanged alternate if there is one, and skipped otherwise. Once
+/// `byte_cap` is reached, the rest aren't opened at all.
+fn read_ahead(
+    mut files: mpsc::UnboundedReceiver<FilenameWithState>,
+    chunk_size: usize,
+    byte_cap: Option<Arc<ByteCap>>,
+    last_progress: Arc<Mutex<Instant>>,
+    tx: mpsc::Sender<Prepared>,
+) {
+    while let Some(mut file) = files.blocking_recv() {
+        // waiting on the check for more files doesn't count as a stall
+        *last_progress.lock().unwrap() = Instant::now();
+        if byte_cap.as_ref().is_some_and(|cap| cap.reached()) {
+            let error = SendFileError::ByteCapReached;
+            if tx.blocking_send(Prepared::Failed { file, error }).is_err() {
+                return;
+            }
+            continue;
+        }
+        let mut f = open_unchanged(&file.filename, file.size, file.modified, file.offset);
+        if f.as_ref().is_err_and(|e| e.is_skip()) {
+            for alternate in std::mem::take(&mut file.alternates) {
+                let Ok(alternate_f) = open_unchanged(
+                    &alternate.filename,
+                    alternate.size,
+                    alternate.modified,
+                    file.offset,
+                ) else {
+                    continue;
+                };
+                debug!(
+                    "{} has changed, sending identical {} instead",
+                    file.filename, alternate.filename
+                );
+                file.filename = alternate.filename;
+                file.modified = alternate.modified;
+                f = Ok(alternate_f);
+                break;
+            }
+        }
+        let f = match f {
+            Ok(f) => f,
+            Err(error) => {
+                if tx.blocking_send(Prepared::Failed { file, error }).is_err() {
+                    return;
+                }
+                continue;
+            }
         };
-        if fd.metadata()?.is_dir() {
-            for entry in WalkDir::new(f)
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|e| !e.file_type().is_dir() && !e.file_type().is_symlink())
-            {
-                deduped_filenames.insert(entry.path().to_string_lossy().into_owned());
+
+        let remaining = file.size.saturating_sub(file.offset);
+        if tx.blocking_send(Prepared::File(file)).is_err() {
+            return;
+        }
+        for chunk in BufReader::new(f.take(remaining)).iter_chunks(chunk_size) {
+            let failed = chunk.is_err();
+            if tx.blocking_send(Prepared::Chunk(chunk)).is_err() {
+                return;
+            }
+            if failed {
+                break;
             }
-        } else {
-            deduped_filenames.insert(f.to_owned());
         }
     }
+}
 
-    if deduped_filenames.is_empty() {
-        return Err(MainError("no files found".to_string()).into());
+/// Like `read_ahead`, but reading the files from the entries of a tar
+/// archive in a single pass, so they're sent in the archive's order. An entry
+/// that doesn't look the way it did when it was checksummed is passed over
+/// for a later identical one; files with no such entry left are skipped.
+/// Every file has to be queued before the archive can be read.
+fn read_ahead_archive(
+    archive: &Path,
+    mut queued: mpsc::UnboundedReceiver<FilenameWithState>,
+    chunk_size: usize,
+    byte_cap: Option<Arc<ByteCap>>,
+    last_progress: Arc<Mutex<Instant>>,
+    tx: mpsc::Sender<Prepared>,
+) {
+    let mut files: Vec<FilenameWithState> = Vec::new();
+    while let Some(file) = queued.blocking_recv() {
+        *last_progress.lock().unwrap() = Instant::now();
+        files.push(file);
     }
 
-    // 2: sort files
-    let mut sorted_files: Vec<&String> = deduped_filenames.iter().collect();
-
-    if !args.no_sort {
-        println!("[+] sorting files...");
-        sorted_files.sort_by(|a, b| {
-            let size_a = File::open(a).unwrap().metadata().unwrap().size();
-            let size_b = File::open(b).unwrap().metadata().unwrap().size();
-            size_b.cmp(&size_a)
-        })
+    // each path a file can be read from, with the size and mtime to expect
+    let mut wanted: HashMap<String, (usize, u64, Option<SystemTime>)> = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        wanted.insert(file.filename.clone(), (i, file.size, file.modified));
+        for alternate in &file.alternates {
+            wanted.entry(alternate.filename.clone()).or_insert((
+                i,
+                alternate.size,
+                alternate.modified,
+            ));
+        }
     }
+    let mut files: Vec<Option<FilenameWithState>> = files.into_iter().map(Some).collect();
 
-    // 3: calculate checksums
-    let mut filename_to_sha256es: HashMap<String, String> = HashMap::new();
-    let mut sha256_to_filenames: HashMap<String, Vec<String>> = HashMap::new();
-    let mut sorted_sha256es: Vec<String> = Vec::new();
-    println!("[+] calculating checksums...");
-    let multibar = MultiProgress::new();
-    let bar = multibar.add(ProgressBar::new(sorted_files.len().try_into().unwrap()));
-    for filename in sorted_files {
-        bar.tick(); // show the bar even if the first file takes a while to checksum
-
-        let mut f = File::open(filename).unwrap();
-
-        let mut buffer = [0; 8192];
+    let mut failed: Option<io::Error> = None;
+    let mut archive = match File::open(archive).and_then(archive::open) {
+        Ok(archive) => Some(archive),
+        Err(e) => {
+            failed = Some(e);
+            None
+        }
+    };
+    let entries = archive.as_mut().map(|archive| archive.entries());
+    match entries {
+        None => {}
+        Some(Err(e)) => failed = Some(e),
+        Some(Ok(entries)) => {
+            for data in entries {
+                let entry = data.and_then(|data| Ok((archive::Entry::new(&data)?, data)));
+                let (entry, mut data) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                };
+                if entry.kind != EntryKind::File {
+                    continue;
+                }
+                let Some(&(i, size, modified)) = wanted.get(&entry.path) else {
+                    continue;
+                };
+                if entry.size != size || Some(entry.modified()) != modified {
+                    continue;
+                }
+                let Some(mut file) = files[i].take() else {
+                    continue;
+                };
+                if byte_cap.as_ref().is_some_and(|cap| cap.reached()) {
+                    let error = SendFileError::ByteCapReached;
+                    if tx.blocking_send(Prepared::Failed { file, error }).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                if file.filename != entry.path {
+                    debug!(
+                        "{} has changed, sending identical {} instead",
+                        file.filename, entry.path
+                    );
+                    file.filename = entry.path;
+                    file.modified = modified;
+                }
 
-        let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+                if let Err(e) = io::copy(&mut (&mut data).take(file.offset), &mut io::sink()) {
+                    let error = SendFileError::SeekError { source: e };
+                    if tx.blocking_send(Prepared::Failed { file, error }).is_err() {
+                        return;
+                    }
+                    continue;
+                }
 
-        loop {
-            match f.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => {
-                    hasher.update(&buffer[..n]);
+                let remaining = file.size.saturating_sub(file.offset);
+                if tx.blocking_send(Prepared::File(file)).is_err() {
+                    return;
                 }
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(e) => {
-                    return Err(MainError(format!("error reading `{}`: {}", filename, e)).into());
+                for chunk in BufReader::new(data.take(remaining)).iter_chunks(chunk_size) {
+                    let failed = chunk.is_err();
+                    if tx.blocking_send(Prepared::Chunk(chunk)).is_err() {
+                        return;
+                    }
+                    if failed {
+                        break;
+                    }
                 }
             }
         }
+    }
 
-        let sha256sum = hex::encode(hasher.finish());
-        filename_to_sha256es.insert(sha256sum.clone(), filename.clone());
-        sorted_sha256es.push(sha256sum.clone());
-        sha256_to_filenames
-            .entry(sha256sum)
-            .or_default()
-            .push(filename.clone());
-        bar.inc(1);
+    for file in files.into_iter().flatten() {
+        let error = match &failed {
+            Some(e) => SendFileError::OpenError {
+                source: io::Error::new(e.kind(), e.to_string()),
+            },
+            None => SendFileError::Changed,
+        };
+        if tx.blocking_send(Prepared::Failed { file, error }).is_err() {
+            return;
+        }
     }
+}
 
-    drop(bar);
+/// What `send_file_data` is up to, for callers that report progress
+/// themselves.
+#[derive(Debug)]
+pub enum SendEvent<'a> {
+    /// `filename` is about to be sent, starting from `offset`.
+    FileStarted {
+        filename: &'a str,
+        sha256sum: &'a str,
+        offset: u64,
+        size: u64,
+    },
+    /// A chunk of `filename` was queued, leaving `position` bytes sent.
+    BytesSent {
+        filename: &'a str,
+        bytes: u64,
+        position: u64,
+    },
+    /// The last of `filename` was queued. Whether the server accepted it
+    /// is reported once `send_file_data` returns.
+    FileSent {
+        filename: &'a str,
+        sha256sum: &'a str,
+    },
+}
 
-    // 4: check what the server needs, then stream those files.
-    let client = RaptorBoostClient::connect(format!("http://{}:{}", args.host, args.port))
-        .await
-        .map_err(|e| MainError(format!("error connecting: {}", e)))?;
+pub type SendEventCallback = Arc<dyn Fn(SendEvent) + Send + Sync>;
 
-    println!("[+] checking remote state...");
+#[derive(Clone)]
+pub struct SendOptions {
+    pub force_unlock: bool,
+    pub chunk_size: usize,
+    pub fail_fast: bool,
+    pub hash_algo: HashAlgo,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Don't start any more files once this is reached; they're returned
+    /// as `ByteCapReached` instead.
+    pub byte_cap: Option<Arc<ByteCap>>,
+    pub on_event: Option<SendEventCallback>,
+    /// Read the files from this tar archive, in which their filenames are
+    /// entry paths, rather than from the filesystem.
+    pub archive: Option<PathBuf>,
+}
 
-    const BATCH: usize = 1000;
-    let check_requests: Vec<UploadFilesRequest> = sorted_sha256es
-        .chunks(BATCH)
-        .map(|c| UploadFilesRequest {
-            sha256sums: c.to_vec(),
-        })
-        .collect();
-
-    let response = client
-        .clone()
-        .upload_files(Request::new(tokio_stream::iter(check_requests)))
-        .await
-        .map_err(|e| MainError(format!("check stream error: {}", e)))?;
-    let mut stream = response.into_inner();
-
-    let mut to_send: Vec<FilenameWithState> = Vec::new();
-    let mut total_to_send: u64 = 0;
-    let mut num_files_up_to_date: u64 = 0;
-
-    while let Some(batch) = stream
-        .message()
-        .await
-        .map_err(|e| MainError(format!("check stream error: {}", e)))?
-    {
-        for fs in batch.file_states {
-            match fs.state() {
-                FileStateResult::FilestateresultUnspecified => eprintln!("wut"),
-                FileStateResult::FilestateresultNeedMoreData => {
-                    let offset = fs.offset();
-                    let filename = filename_to_sha256es
-                        .get(&fs.sha256sum)
-                        .cloned()
-                        .unwrap_or_default();
-                    let file_size = std::fs::metadata(&filename).map(|m| m.len()).unwrap_or(0);
-                    total_to_send += file_size.saturating_sub(offset);
-                    to_send.push(FilenameWithState {
-                        filename,
-                        sha256sum: fs.sha256sum,
-                        offset,
-                    });
-                }
-                FileStateResult::FilestateresultComplete => num_files_up_to_date += 1,
-            }
-        }
-    }
+/// Whether a client and server of these versions speak the same protocol:
+/// the major version has to match, or the minor version too while the major
+/// version is still 0.
+pub fn is_compatible_version(ours: &str, theirs: &str) -> Result<bool, semver::Error> {
+    let ours = semver::Version::parse(ours)?;
+    let theirs = semver::Version::parse(theirs)?;
 
-    let num_files_transferred = to_send.len();
-    if !to_send.is_empty() {
-        println!("[+] streaming files...");
-        send_files(
-            client.clone(),
-            to_send,
-            total_to_send,
-            args.force_unlock,
-            multibar.clone(),
-        )
-        .await?;
-    }
-
-    // 5: send names
-    println!("[+] updating filenames...");
-    let mut client = client;
-
-    const ASSIGN_BATCH: usize = 200;
-    let owned: Vec<Sha256Filenames> = sha256_to_filenames
-        .into_iter()
-        .map(|(sha256sum, names)| Sha256Filenames { sha256sum, names })
-        .collect();
-
-    let mut messages: Vec<AssignNamesRequest> = Vec::with_capacity(owned.len() / ASSIGN_BATCH + 1);
-    messages.push(AssignNamesRequest {
-        name: args.name,
-        force: args.force_name.then_some(true),
-        sha256_to_filenames: vec![],
-    });
-    for chunk in owned.chunks(ASSIGN_BATCH) {
-        messages.push(AssignNamesRequest {
-            name: None,
-            force: None,
-            sha256_to_filenames: chunk.to_vec(),
-        });
+    Ok(ours.major == theirs.major && (ours.major != 0 || ours.minor == theirs.minor))
+}
+
+pub fn file_attributes(filename: &str) -> FileAttributes {
+    let Ok(metadata) = fs::metadata(filename) else {
+        return FileAttributes::default();
+    };
+
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode())
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+
+    FileAttributes {
+        mode,
+        mtime: mtime.and_then(|d| i64::try_from(d.as_secs()).ok()),
+        mtime_nsec: mtime.map(|d| d.subsec_nanos()),
     }
+}
+
+/// Hash a whole file, memory-mapping it if it's large and `mmap` is set,
+/// which is only safe if nothing truncates the file meanwhile.
+pub fn hash_file(
+    filename: &str,
+    chunk_size: usize,
+    hash_algo: HashAlgo,
+    mmap: bool,
+) -> io::Result<String> {
+    let mut f = File::open(filename)?;
+    let mut hasher = hash_algo.hasher();
+    hasher.update_from_file(&mut f, chunk_size, mmap)?;
+    Ok(hasher.finish())
+}
+
+// tonic's default max decoding message size on the server
+pub const GRPC_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn parse_byte_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (num, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    num.parse::<usize>()
+        .map_err(|e| format!("invalid size '{}': {}", s, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let assign_names_resp = client
-        .assign_names(Request::new(tokio_stream::iter(messages)))
-        .await;
+    /// The packets `send_file_data` makes of a file holding `contents`,
+    /// resumed at `offset`.
+    fn packets(contents: &[u8], offset: u64, chunk_size: usize) -> Vec<FileData> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        fs::write(&path, contents).unwrap();
+        let filename = path.to_str().unwrap().to_string();
+        let file = HashedFile::new(filename.clone(), &fs::metadata(&path).unwrap());
 
-    if let Err(e) = assign_names_resp {
-        println!("remote error assigning names: {}", e.message());
+        let (queued_tx, queued_rx) = mpsc::unbounded_channel();
+        queued_tx
+            .send(FilenameWithState {
+                filename,
+                sha256sum: "sha".to_string(),
+                offset,
+                partial: offset > 0,
+                size: file.size,
+                modified: file.modified,
+                alternates: Vec::new(),
+            })
+            .unwrap();
+        drop(queued_tx);
+        let (tx, mut rx) = mpsc::channel(64);
+        let last_progress = Arc::new(Mutex::new(Instant::now()));
+        read_ahead(queued_rx, chunk_size, None, last_progress, tx);
+
+        let Some(Prepared::File(file)) = rx.blocking_recv() else {
+            panic!("expected the file to be opened");
+        };
+        let mut pos = file.offset;
+        let mut packets = Vec::new();
+        while let Some(prepared) = rx.blocking_recv() {
+            let Prepared::Chunk(Ok(data)) = prepared else {
+                panic!("expected only chunks");
+            };
+            let len = data.len() as u64;
+            packets.push(file_data(&file, pos, data, false, HashAlgo::Sha256));
+            pos += len;
+        }
+        packets
     }
 
-    println!();
+    #[test]
+    fn empty_input_is_one_empty_chunk() {
+        let chunks: Vec<Bytes> = io::empty().iter_chunks(4).map(Result::unwrap).collect();
+        assert_eq!(chunks, vec![Bytes::new()]);
+
+        let packets = packets(b"", 0, 4);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].first && packets[0].last);
+        assert!(packets[0].data.is_empty());
+        assert_eq!(packets[0].total_size, Some(0));
+    }
 
-    if num_files_transferred != 0 {
-        println!("{} files transferred", num_files_transferred);
+    #[test]
+    fn file_resumed_at_its_end_is_one_empty_last_packet() {
+        let packets = packets(b"hello", 5, 4);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].first && packets[0].last);
+        assert!(packets[0].data.is_empty());
+        assert_eq!(packets[0].offset, Some(5));
     }
-    if num_files_up_to_date != 0 {
-        println!("{} files were already up to date", num_files_up_to_date);
+
+    #[test]
+    fn file_is_chunked_in_order() {
+        let packets = packets(b"0123456789", 0, 4);
+        let data: Vec<&[u8]> = packets.iter().map(|p| &p.data[..]).collect();
+        assert_eq!(data, vec![&b"0123"[..], b"4567", b"89"]);
+        let ends: Vec<(bool, bool)> = packets.iter().map(|p| (p.first, p.last)).collect();
+        assert_eq!(ends, vec![(true, false), (false, false), (false, true)]);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn identical_files_are_sent_once_and_all_named() {
+        use crate::controller::{RaptorBoostController, StoreDirs};
+        use crate::hooks::Hooks;
+        use crate::proto::raptor_boost_server::RaptorBoostServer;
+        use crate::service::RaptorBoostService;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::sync::watch;
+
+        let store = tempfile::tempdir().unwrap();
+        let src = tempfile::tempdir().unwrap();
+        let controller =
+            Arc::new(RaptorBoostController::new(store.path(), &StoreDirs::default()).unwrap());
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let service = RaptorBoostService {
+            controller: controller.clone(),
+            shutdown,
+            transfer_slots: None,
+            hooks: Hooks::default(),
+            access_log: None,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(RaptorBoostServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        let mut client =
+            Client::connect(Endpoint::from_shared(format!("http://{}", addr)).unwrap())
+                .await
+                .unwrap();
+
+        let mut hasher = HashAlgo::Sha256.hasher();
+        hasher.update(b"same");
+        let sha256sum = hasher.finish();
+        let names = ["a", "b", "c"];
+        let files: Vec<HashedFile> = names
+            .iter()
+            .map(|name| {
+                let path = src.path().join(name);
+                fs::write(&path, b"same").unwrap();
+                HashedFile::new(
+                    path.to_str().unwrap().to_string(),
+                    &fs::metadata(&path).unwrap(),
+                )
+            })
+            .collect();
+        let hashed_files = HashMap::from([(sha256sum.clone(), files)]);
+        let sorted_sha256es = vec![sha256sum.clone(); names.len()];
+
+        let (to_send, _, _) = client
+            .upload_files(&sorted_sha256es, &hashed_files, HashAlgo::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(to_send.len(), 1);
+        assert_eq!(to_send[0].alternates.len(), 2);
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let opts = SendOptions {
+            force_unlock: false,
+            chunk_size: 1024,
+            fail_fast: true,
+            hash_algo: HashAlgo::Sha256,
+            rate_limiter: None,
+            byte_cap: None,
+            on_event: Some(Arc::new({
+                let started = started.clone();
+                move |event| {
+                    if let SendEvent::FileStarted { .. } = event {
+                        started.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })),
+            archive: None,
+        };
+        let (tx, rx) = mpsc::channel(1);
+        for file in to_send {
+            tx.send(file).await.unwrap();
+        }
+        drop(tx);
+        let failures = client
+            .send_file_data(
+                rx,
+                opts,
+                MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden()),
+            )
+            .await
+            .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(started.load(Ordering::Relaxed), 1);
+
+        let sha256_to_names =
+            HashMap::from([(sha256sum, names.iter().map(|n| n.to_string()).collect())]);
+        let resp = client
+            .assign_names(
+                Some("t".to_string()),
+                false,
+                false,
+                None,
+                sha256_to_names,
+                vec![],
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.statuses.len(), names.len());
+        assert!(
+            resp.statuses
+                .iter()
+                .all(|s| s.status() == proto::AssignNameStatus::AssignnamestatusSuccess)
+        );
+        for name in names {
+            let named = controller.get_transfers_dir().join("t").join(name);
+            assert_eq!(fs::read(named).unwrap(), b"same");
+        }
+    }
 }
@@ -1,14 +1,29 @@
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
+    fmt,
     fs::{self, File, OpenOptions, remove_file},
-    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use safe_path::scoped_join;
+use clap::ValueEnum;
+use filetime::FileTime;
+use serde_json::{Value, json};
 use thiserror::Error;
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
 
-use crate::lock::LockFile;
+use crate::hash::{HashAlgo, Hasher};
+use crate::lock::{self, LockFile};
+use crate::platform::{available_space, link_file, move_file, scoped_join, sync_dir, write_all_at};
+use crate::proto;
 
 #[derive(Error, Debug)]
 pub enum RaptorBoostError {
@@ -20,6 +35,22 @@ pub enum RaptorBoostError {
     TransferAlreadyComplete,
     #[error("checksum mismatch")]
     ChecksumMismatch,
+    #[error("size mismatch: expected {expected} bytes, got {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("hash algorithm mismatch: server uses {server}, client sent {client}")]
+    HashAlgoMismatch { server: HashAlgo, client: HashAlgo },
+    #[error("not enough space: need {needed} bytes, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error(
+        "partial store is full: need {needed} bytes, {available} of the {quota} byte quota left"
+    )]
+    PartialQuotaExceeded {
+        needed: u64,
+        available: u64,
+        quota: u64,
+    },
+    #[error("partial is {actual} bytes, expected to resume from {expected}")]
+    OffsetMismatch { expected: u64, actual: u64 },
     #[error("error renaming file: `{0}`")]
     RenameError(String),
     #[error("other error: `{0}`")]
@@ -30,140 +61,1061 @@ pub enum RaptorBoostError {
 #[error("{0}")]
 pub struct RaptorBoostControllerError(String);
 
+/// Overrides for where each part of the store lives. Anything left unset
+/// goes in its usual subdirectory of the output directory.
+#[derive(Debug, Default)]
+pub struct StoreDirs {
+    pub partial: Option<PathBuf>,
+    pub complete: Option<PathBuf>,
+    pub transfers: Option<PathBuf>,
+    pub lock: Option<PathBuf>,
+}
+
+/// How the names of a transfer refer to the complete files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LinkMode {
+    Symlink,
+    Hardlink,
+    Copy,
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkMode::Symlink => write!(f, "symlink"),
+            LinkMode::Hardlink => write!(f, "hardlink"),
+            LinkMode::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+impl From<LinkMode> for proto::LinkMode {
+    fn from(mode: LinkMode) -> Self {
+        match mode {
+            LinkMode::Symlink => proto::LinkMode::LinkmodeSymlink,
+            LinkMode::Hardlink => proto::LinkMode::LinkmodeHardlink,
+            LinkMode::Copy => proto::LinkMode::LinkmodeCopy,
+        }
+    }
+}
+
+// how often (in bytes) a transfer records the hash of everything written so far
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+const HASH_BUFFER_SIZE: usize = 8192;
+
+/// The most directory levels complete files and partials can be sharded
+/// into, at two hex digits each.
+pub const MAX_SHARD_DEPTH: usize = 4;
+
+/// Hash states of partials whose transfer stopped early, with the offset
+/// they've hashed up to, so resuming doesn't have to re-read the partial.
+/// Neither ring nor blake3 can export a hasher's state, so these only last
+/// as long as the server does.
+type SuspendedHashers = Arc<Mutex<HashMap<String, (u64, Hasher)>>>;
+
+/// Bytes in the partial dir, kept up to date as partials are written and
+/// removed rather than by statting the dir.
+type PartialBytes = Arc<AtomicU64>;
+
 pub struct RaptorBoostController {
     partial_dir: PathBuf,
     complete_dir: PathBuf,
+    shard_depth: usize,
     transfers_dir: PathBuf,
     lock_dir: PathBuf,
+    checkpoints_dir: PathBuf,
+    verify_partials: bool,
+    trust_client_checksum: bool,
+    isolate_clients: bool,
+    durable: bool,
+    link_mode: LinkMode,
+    hash_algo: HashAlgo,
+    lock_timeout: Duration,
+    mmap: bool,
+    suspended: SuspendedHashers,
+    partial_bytes: PartialBytes,
+    partial_quota: Option<u64>,
+    evict_partials: bool,
+    history_path: PathBuf,
+    // keeps concurrent appends to the journal from interleaving
+    history_lock: Mutex<()>,
+}
+
+pub struct StoredFile {
+    pub sha256sum: String,
+    pub size: u64,
+    pub names: Vec<String>,
+}
+
+/// How full the store is.
+pub struct StoreStats {
+    /// Bytes available on the complete dir's filesystem, if it can be told.
+    pub complete_free: Option<u64>,
+    /// Bytes available on the partial dir's filesystem, if it can be told.
+    pub partial_free: Option<u64>,
+    pub complete_files: u64,
+    pub complete_bytes: u64,
+    pub active_locks: u64,
+}
+
+/// A complete file whose contents no longer match its name.
+pub struct CorruptFile {
+    pub sha256sum: String,
+    pub problem: String,
+}
+
+/// One line of the history journal: a file that finished uploading, or the
+/// names a transfer gave it.
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch.
+    pub time: i64,
+    pub sha256sum: String,
+    pub size: u64,
+    /// The transfer the names were assigned in; `None` for a received file.
+    pub transfer: Option<String>,
+    pub names: Vec<String>,
+}
+
+impl HistoryEntry {
+    fn to_json(&self) -> Value {
+        let mut entry = json!({
+            "time": self.time,
+            "sha256sum": self.sha256sum,
+            "size": self.size,
+        });
+        if let Some(transfer) = &self.transfer {
+            entry["transfer"] = json!(transfer);
+            entry["names"] = json!(self.names);
+        }
+        entry
+    }
+
+    fn from_json(line: &str) -> Option<HistoryEntry> {
+        let entry: Value = serde_json::from_str(line).ok()?;
+        Some(HistoryEntry {
+            time: entry["time"].as_i64()?,
+            sha256sum: entry["sha256sum"].as_str()?.to_string(),
+            size: entry["size"].as_u64()?,
+            transfer: entry["transfer"].as_str().map(str::to_string),
+            names: entry["names"]
+                .as_array()
+                .map(|names| {
+                    names
+                        .iter()
+                        .filter_map(|n| n.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
 }
 
 pub enum CheckFileResult {
     FileComplete,
+    /// A partial is there to resume from this offset, which is 0 for an
+    /// empty one, e.g. left by a zero-byte file that never completed.
     FilePartialOffset(u64),
+    /// Nothing of the file has been received.
+    FileNew,
 }
 
 pub struct RaptorBoostTransfer {
     sha256sum: String,
+    // what the partial, its lock and its checkpoints are named
+    partial_name: String,
     complete_path: PathBuf,
     partial_path: PathBuf,
+    checkpoint_path: PathBuf,
     f: File,
-    _l: LockFile,
-    hasher: ring::digest::Context,
+    lock: LockFile,
+    // only taken by `complete`; whatever's left on drop is suspended
+    hasher: Option<Hasher>,
+    // how much of the partial was there before this transfer and taken on
+    // the client's word, which `hasher` doesn't cover
+    trusted: u64,
+    suspended: SuspendedHashers,
+    partial_bytes: PartialBytes,
+    offset: u64,
+    total_size: Option<u64>,
+    durable: bool,
 }
 
 impl RaptorBoostTransfer {
-    pub fn write_all(&mut self, d: &[u8]) -> io::Result<()> {
-        self.f.write_all(d)?;
-        self.hasher.update(d);
+    pub fn sha256sum(&self) -> &str {
+        &self.sha256sum
+    }
+
+    /// Number of bytes in the partial so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn write_all(&mut self, mut d: &[u8]) -> io::Result<()> {
+        while !d.is_empty() {
+            let until_checkpoint = CHECKPOINT_INTERVAL - self.offset % CHECKPOINT_INTERVAL;
+            let n = d
+                .len()
+                .min(until_checkpoint.try_into().unwrap_or(usize::MAX));
+            let (head, tail) = d.split_at(n);
+
+            write_all_at(&self.f, head, self.offset)?;
+            self.partial_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(head);
+            }
+            self.offset += n as u64;
+
+            if self.offset.is_multiple_of(CHECKPOINT_INTERVAL) {
+                self.write_checkpoint()?;
+            }
+
+            d = tail;
+        }
         Ok(())
     }
 
-    pub fn complete(self) -> Result<(), RaptorBoostError> {
-        let calc_sha256sum = hex::encode(self.hasher.finish());
+    fn write_checkpoint(&self) -> io::Result<()> {
+        // a checkpoint is the hash of everything before it
+        let Some(hasher) = self.hasher.as_ref().filter(|_| self.trusted == 0) else {
+            return Ok(());
+        };
+        let digest = hasher.clone().finish();
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.checkpoint_path)?;
+        writeln!(f, "{} {}", self.offset, digest)
+    }
 
-        if self.sha256sum != calc_sha256sum {
-            let _ = remove_file(&self.partial_path);
-            return Err(RaptorBoostError::ChecksumMismatch);
+    pub fn complete(mut self) -> Result<(), RaptorBoostError> {
+        if let Some(expected) = self.total_size
+            && expected != self.offset
+        {
+            // a short partial can still be resumed; one that's too long
+            // can never hash correctly
+            if self.offset > expected {
+                self.hasher = None;
+                let _ = remove_file(&self.checkpoint_path);
+                self.remove_partial();
+            }
+            return Err(RaptorBoostError::SizeMismatch {
+                expected,
+                actual: self.offset,
+            });
         }
 
-        fs::rename(&self.partial_path, &self.complete_path).map_err(|e| {
-            let _ = remove_file(&self.partial_path);
-            RaptorBoostError::RenameError(e.to_string())
-        })
+        let _ = remove_file(&self.checkpoint_path);
+
+        // writes land at our own offset, so nothing else writing to the
+        // partial can interleave with them, but it would still change its
+        // length
+        let len = self
+            .f
+            .metadata()
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+            .len();
+        if len != self.offset {
+            self.hasher = None;
+            self.remove_partial();
+            return Err(RaptorBoostError::OtherError(format!(
+                "partial changed while it was being written: {} bytes, expected {}",
+                len, self.offset
+            )));
+        }
+
+        if let Some(hasher) = self.hasher.take() {
+            let digest = hasher.finish();
+            if self.trusted == 0 {
+                if self.sha256sum != digest {
+                    self.remove_partial();
+                    return Err(RaptorBoostError::ChecksumMismatch);
+                }
+            } else {
+                // without the start there's nothing to compare this with
+                debug!(
+                    "completing {} unverified: the first {} bytes were trusted, the {} streamed after them hash to {}",
+                    self.sha256sum,
+                    self.trusted,
+                    self.offset - self.trusted,
+                    digest
+                );
+            }
+        }
+
+        // without this a crash shortly after the rename can leave a complete
+        // file that exists but is missing data
+        if self.durable {
+            self.f
+                .sync_all()
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+        }
+
+        // the partial has been verified at this point, so keep it around if
+        // the move fails: the next upload of this file finds it fully written
+        // and only has to retry the move
+        if let Some(dir) = self.complete_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| RaptorBoostError::RenameError(e.to_string()))?;
+        }
+        move_file(&self.partial_path, &self.complete_path, self.durable)
+            .map_err(|e| RaptorBoostError::RenameError(e.to_string()))?;
+        release_partial_bytes(&self.partial_bytes, self.offset);
+
+        if self.durable
+            && let Some(complete_dir) = self.complete_path.parent()
+            && let Err(e) = sync_dir(complete_dir)
+        {
+            warn!("couldn't sync {}: {}", complete_dir.display(), e);
+        }
+
+        // the file is complete either way, and a leftover lock file is
+        // harmless once it's unlocked
+        if let Err(e) = self.lock.release() {
+            warn!("failed to release lock for {}: {}", self.sha256sum, e);
+        }
+
+        Ok(())
+    }
+}
+
+impl RaptorBoostTransfer {
+    fn remove_partial(&self) {
+        if remove_file(&self.partial_path).is_ok() {
+            release_partial_bytes(&self.partial_bytes, self.offset);
+        }
+    }
+}
+
+impl Drop for RaptorBoostTransfer {
+    fn drop(&mut self) {
+        // a hash of only the end of the partial can't be resumed from
+        if self.trusted > 0 {
+            return;
+        }
+        if let Some(hasher) = self.hasher.take() {
+            self.suspended
+                .lock()
+                .unwrap()
+                .insert(self.partial_name.clone(), (self.offset, hasher));
+        }
     }
 }
 
 impl RaptorBoostController {
-    pub fn new(output_dir: &Path) -> Result<RaptorBoostController, Box<dyn Error>> {
+    pub fn new(
+        output_dir: &Path,
+        dirs: &StoreDirs,
+    ) -> Result<RaptorBoostController, Box<dyn Error>> {
         if !output_dir.try_exists()? {
             return Err(Box::new(RaptorBoostControllerError(
                 "output directory doesn't exist".to_string(),
             )));
         }
 
-        let partial_dir = output_dir.join("partial");
-        if !partial_dir.exists() {
-            fs::create_dir(&partial_dir)?;
-        }
+        let store_dir = |dir: &Option<PathBuf>, default: &str| -> io::Result<PathBuf> {
+            let dir = dir.clone().unwrap_or_else(|| output_dir.join(default));
+            if !dir.exists() {
+                fs::create_dir_all(&dir)?;
+            }
+            Ok(dir)
+        };
 
-        let complete_dir = output_dir.join("complete");
-        if !complete_dir.exists() {
-            fs::create_dir(&complete_dir)?;
-        }
+        let partial_dir = store_dir(&dirs.partial, "partial")?;
+        let complete_dir = store_dir(&dirs.complete, "complete")?;
+        let transfers_dir = store_dir(&dirs.transfers, "transfers")?;
 
-        let transfers_dir = output_dir.join("transfers");
-        if !transfers_dir.exists() {
-            fs::create_dir(&transfers_dir)?;
+        // locks are advisory and die with their holder, but the files of
+        // those held by a server that died are left behind; another server
+        // sharing this dir keeps its own
+        let lock_dir = store_dir(&dirs.lock, "lock")?;
+        match lock::remove_stale(&lock_dir) {
+            Ok(0) => {}
+            Ok(n) => debug!("removed {} stale lock files", n),
+            Err(e) => warn!("couldn't clear stale lock files: {}", e),
         }
 
-        let lock_dir = output_dir.join("lock");
-        if lock_dir.exists() {
-            fs::remove_dir_all(&lock_dir)?;
+        let partial_bytes = stored_files(&partial_dir)?
+            .iter()
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+
+        let checkpoints_dir = output_dir.join("checkpoints");
+        if !checkpoints_dir.exists() {
+            fs::create_dir(&checkpoints_dir)?;
         }
-        fs::create_dir(&lock_dir)?;
 
         Ok(RaptorBoostController {
             partial_dir,
             complete_dir,
+            shard_depth: 0,
             transfers_dir,
             lock_dir,
+            checkpoints_dir,
+            verify_partials: false,
+            trust_client_checksum: false,
+            isolate_clients: false,
+            durable: true,
+            link_mode: LinkMode::Symlink,
+            hash_algo: HashAlgo::Sha256,
+            lock_timeout: Duration::ZERO,
+            mmap: true,
+            suspended: Arc::default(),
+            partial_bytes: Arc::new(AtomicU64::new(partial_bytes)),
+            partial_quota: None,
+            evict_partials: false,
+            history_path: output_dir.join("history.jsonl"),
+            history_lock: Mutex::new(()),
         })
     }
 
+    /// Flush completed files and their directory entry to disk before
+    /// reporting them complete. On by default.
+    pub fn with_durable(mut self, durable: bool) -> Self {
+        self.durable = durable;
+        self
+    }
+
+    /// Create names as symlinks (the default), hardlinks or copies. Only
+    /// symlinked names are seen by `list_files` and `delete_files`.
+    pub fn with_link_mode(mut self, link_mode: LinkMode) -> Self {
+        self.link_mode = link_mode;
+        self
+    }
+
+    /// How long `start_transfer` waits for another transfer of the same
+    /// file to finish before failing with `LockFailure`. Zero, the default,
+    /// fails straight away.
+    pub fn with_lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Memory-map large partials when re-hashing them for a resume. On by
+    /// default: partials are only written under their lock, so nothing
+    /// truncates them while they're mapped. Complete files are only mapped
+    /// to verify the store with `LinkMode::Copy`, since otherwise every
+    /// name under the transfers dir leads to them and truncating one would
+    /// raise SIGBUS.
+    pub fn with_mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Cap the total size of the partial dir. A transfer whose remaining
+    /// bytes don't fit is refused with `PartialQuotaExceeded`. Concurrent
+    /// transfers are each checked against what's on disk when they start,
+    /// so together they can overshoot it.
+    pub fn with_partial_quota(mut self, partial_quota: Option<u64>) -> Self {
+        self.partial_quota = partial_quota;
+        self
+    }
+
+    /// Make room under the partial quota by removing the least recently
+    /// written partials that aren't being uploaded, rather than refusing
+    /// the transfer.
+    pub fn with_evict_partials(mut self, evict_partials: bool) -> Self {
+        self.evict_partials = evict_partials;
+        self
+    }
+
+    pub fn link_mode(&self) -> LinkMode {
+        self.link_mode
+    }
+
+    /// Keep complete files and partials `depth` directories deep, each
+    /// named by the next two hex digits of the checksum (`ab/cd/abcd...` for
+    /// 2), rather than all in one directory. 0, the default, keeps them
+    /// flat. A store written at another depth has to be moved over with
+    /// `reshard` first. At most `MAX_SHARD_DEPTH`.
+    pub fn with_shard_depth(mut self, depth: usize) -> Self {
+        self.shard_depth = depth.min(MAX_SHARD_DEPTH);
+        self
+    }
+
+    pub fn shard_depth(&self) -> usize {
+        self.shard_depth
+    }
+
+    pub fn with_hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.hash_algo = hash_algo;
+        self
+    }
+
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
+
+    fn check_hash_algo(&self, hash_algo: HashAlgo) -> Result<(), RaptorBoostError> {
+        if hash_algo != self.hash_algo {
+            return Err(RaptorBoostError::HashAlgoMismatch {
+                server: self.hash_algo,
+                client: hash_algo,
+            });
+        }
+        Ok(())
+    }
+
+    /// Re-verify partial files against their recorded checkpoints before
+    /// reporting a resume offset. This costs a full read of the partial.
+    pub fn with_verify_partials(mut self, verify_partials: bool) -> Self {
+        self.verify_partials = verify_partials;
+        self
+    }
+
+    /// Resume partials without re-reading them to rebuild their hash, and
+    /// complete them on the client's word that they match their checksum.
+    /// Only resumes that would have to re-read are affected: a fresh transfer,
+    /// or one whose hash is still in memory, is verified as usual. The bytes
+    /// streamed after such a resume are still hashed as they're written, but
+    /// with the start of the file unread that hash can't be checked against
+    /// the checksum, so the file as a whole is stored unverified.
+    pub fn with_trust_client_checksum(mut self, trust_client_checksum: bool) -> Self {
+        self.trust_client_checksum = trust_client_checksum;
+        self
+    }
+
+    /// Keep each client's partials, and their locks, apart from every other
+    /// client's, so one can't resume or write to a partial another started.
+    /// Complete files are still shared. Clients are told apart by address.
+    pub fn with_isolate_clients(mut self, isolate_clients: bool) -> Self {
+        self.isolate_clients = isolate_clients;
+        self
+    }
+
+    /// The name the partial of `sha256sum` sent by `peer` is kept under. The
+    /// checksum comes first, so it's sharded like any other partial. Only the
+    /// address's IP is used, as each connection gets a new port.
+    fn partial_name(&self, sha256sum: &str, peer: Option<SocketAddr>) -> String {
+        if !self.isolate_clients {
+            return sha256sum.to_string();
+        }
+        // a unix socket has no peer address
+        let client = match peer {
+            Some(peer) => peer.ip().to_string().replace(':', "_"),
+            None => "local".to_string(),
+        };
+        format!("{}.{}", sha256sum, client)
+    }
+
+    /// Open (or resume) the partial for `sha256sum` sent by `peer`. Resuming
+    /// a partial whose hash state wasn't kept means re-hashing it, which adds
+    /// to `hashed` as it goes.
+    #[allow(clippy::too_many_arguments)]
     pub fn start_transfer(
         &self,
         sha256sum: &str,
+        peer: Option<SocketAddr>,
         force: bool,
+        hash_algo: HashAlgo,
+        total_size: Option<u64>,
+        expected_offset: Option<u64>,
+        hashed: Option<&AtomicU64>,
     ) -> Result<RaptorBoostTransfer, RaptorBoostError> {
-        let partial_lock_path = scoped_join(self.get_lock_dir(), sha256sum)
+        self.check_hash_algo(hash_algo)?;
+
+        let partial_name = self.partial_name(sha256sum, peer);
+        let partial_lock_path = scoped_join(self.get_lock_dir(), &partial_name)
             .map_err(|_| RaptorBoostError::PathSanitization(sha256sum.to_string()))?;
 
         if force {
             let _ = remove_file(&partial_lock_path);
         }
 
-        let partial_lock =
-            LockFile::open(partial_lock_path).map_err(|_| RaptorBoostError::LockFailure)?;
+        // after waiting out a concurrent upload of the same file, the check
+        // below finds it complete
+        let partial_lock = LockFile::open(partial_lock_path, self.lock_timeout)
+            .map_err(|_| RaptorBoostError::LockFailure)?;
 
-        if let CheckFileResult::FileComplete = self.check_file(sha256sum)? {
+        let complete_path = self.complete_path(sha256sum)?;
+        if complete_path.exists() {
             return Err(RaptorBoostError::TransferAlreadyComplete);
         }
 
-        let partial_path = self.partial_dir.join(sha256sum);
+        let partial_path = self.partial_path(&partial_name)?;
+
+        // check before creating the partial so a rejected transfer doesn't
+        // leave an empty one behind
+        if let Some(total_size) = total_size {
+            let written = fs::metadata(&partial_path).map(|m| m.len()).unwrap_or(0);
+            let needed = total_size.saturating_sub(written);
+            self.check_space(needed)?;
+            self.check_partial_quota(&partial_name, needed)?;
+        }
+
+        if let Some(dir) = partial_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+        }
+
+        // written with positioned writes at our own offset rather than in
+        // append mode, so the offset checked below is the one written at
         let mut f = OpenOptions::new()
             .create(true)
             .read(true)
-            .append(true)
+            .write(true)
+            .truncate(false)
             .open(&partial_path)
             .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
 
-        f.seek(SeekFrom::Start(0))
-            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+        let len = f
+            .metadata()
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+            .len();
 
-        let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
-        let mut buffer = [0; 8192];
-        loop {
-            match f.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => hasher.update(&buffer[..n]),
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(e) => return Err(RaptorBoostError::OtherError(e.to_string())),
-            }
+        // the partial may have changed since the client asked for its offset,
+        // and writing at the wrong place would only be noticed at the end
+        if let Some(expected) = expected_offset
+            && len != expected
+        {
+            return Err(RaptorBoostError::OffsetMismatch {
+                expected,
+                actual: len,
+            });
         }
 
-        f.seek(SeekFrom::End(0))
-            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+        let suspended = self.suspended.lock().unwrap().remove(&partial_name);
+        let mut trusted = 0;
+        let hasher = match suspended {
+            // only good if nothing's touched the partial since
+            Some((offset, hasher)) if offset == len => hasher,
+            _ if self.trust_client_checksum && len > 0 => {
+                debug!(
+                    "resuming {} at {} without verifying what's already there",
+                    sha256sum, len
+                );
+                // what's streamed from here is still hashed on its own
+                trusted = len;
+                self.hash_algo.hasher()
+            }
+            _ => {
+                if len > 0 {
+                    debug!(
+                        "re-reading {} bytes of {} to resume its hash",
+                        len, sha256sum
+                    );
+                }
+                f.seek(SeekFrom::Start(0))
+                    .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+                let mut hasher = self.hash_algo.hasher();
+                hasher
+                    .update_from_file_with_progress(&mut f, HASH_BUFFER_SIZE, self.mmap, |n| {
+                        if let Some(hashed) = hashed {
+                            hashed.fetch_add(n, Ordering::Relaxed);
+                        }
+                    })
+                    .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+                hasher
+            }
+        };
 
         Ok(RaptorBoostTransfer {
             f,
-            _l: partial_lock,
-            hasher,
+            lock: partial_lock,
+            hasher: Some(hasher),
+            trusted,
+            suspended: self.suspended.clone(),
+            partial_bytes: self.partial_bytes.clone(),
             sha256sum: sha256sum.to_owned(),
-            complete_path: self.complete_dir.join(sha256sum),
+            complete_path,
+            checkpoint_path: self.checkpoints_dir.join(&partial_name),
+            partial_name,
             partial_path,
+            offset: len,
+            total_size,
+            durable: self.durable,
         })
     }
 
+    /// Make sure the partial dir's filesystem can take another `needed`
+    /// bytes. Filesystems we can't query are assumed to have room.
+    fn check_space(&self, needed: u64) -> Result<(), RaptorBoostError> {
+        match available_space(self.get_partial_dir()) {
+            Ok(available) if available < needed => {
+                Err(RaptorBoostError::InsufficientSpace { needed, available })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure another `needed` bytes fit under the partial quota, evicting
+    /// other partials for them if that's enabled.
+    fn check_partial_quota(&self, name: &str, needed: u64) -> Result<(), RaptorBoostError> {
+        let Some(quota) = self.partial_quota else {
+            return Ok(());
+        };
+        let available = || quota.saturating_sub(self.partial_bytes.load(Ordering::Relaxed));
+        if available() >= needed {
+            return Ok(());
+        }
+
+        if self.evict_partials {
+            let mut partials: Vec<(SystemTime, String)> = stored_files(self.get_partial_dir())
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+                .into_iter()
+                .filter_map(|e| {
+                    let modified = e.metadata().ok()?.modified().ok()?;
+                    Some((modified, e.file_name().to_str()?.to_string()))
+                })
+                .filter(|(_, other)| other != name)
+                .collect();
+            partials.sort();
+
+            for (_, victim) in partials {
+                if available() >= needed {
+                    break;
+                }
+                if self.remove_idle_partial(&victim) {
+                    info!("evicted partial {} to make room for {}", victim, name);
+                }
+            }
+        }
+
+        let available = available();
+        if available < needed {
+            return Err(RaptorBoostError::PartialQuotaExceeded {
+                needed,
+                available,
+                quota,
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove the partial named `name` and everything kept alongside it,
+    /// unless a transfer has it locked. Returns whether it was removed.
+    fn remove_idle_partial(&self, name: &str) -> bool {
+        let (Ok(partial_path), Ok(lock_path)) = (
+            self.partial_path(name),
+            scoped_join(self.get_lock_dir(), name),
+        ) else {
+            return false;
+        };
+        // holding the lock keeps a new transfer from starting on this
+        // partial while it's being removed
+        let Ok(_l) = LockFile::open(lock_path, Duration::ZERO) else {
+            return false;
+        };
+
+        let size = fs::metadata(&partial_path).map(|m| m.len()).unwrap_or(0);
+        if let Err(e) = remove_file(&partial_path) {
+            warn!("couldn't remove partial {}: {}", name, e);
+            return false;
+        }
+        release_partial_bytes(&self.partial_bytes, size);
+        if let Ok(checkpoint_path) = scoped_join(&self.checkpoints_dir, name) {
+            let _ = remove_file(checkpoint_path);
+        }
+        self.suspended.lock().unwrap().remove(name);
+        true
+    }
+
+    /// Re-hash a partial file and compare it against the checkpoints recorded
+    /// while it was written. If one no longer matches, the partial is
+    /// truncated to the last one that does, so a resume re-sends the rest
+    /// instead of discovering corruption at the very end. A partial with
+    /// nothing to compare, e.g. one shorter than a checkpoint interval or
+    /// resumed on the client's word, is kept as it is.
+    ///
+    /// Returns the offset the transfer can safely resume from.
+    pub fn verify_partial(&self, name: &str) -> Result<u64, RaptorBoostError> {
+        let partial_path = self.partial_path(name)?;
+        let checkpoint_path = scoped_join(&self.checkpoints_dir, name)
+            .map_err(|_| RaptorBoostError::PathSanitization(name.to_string()))?;
+        let lock_path = scoped_join(self.get_lock_dir(), name)
+            .map_err(|_| RaptorBoostError::PathSanitization(name.to_string()))?;
+
+        let mut f = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&partial_path)
+        {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(RaptorBoostError::OtherError(e.to_string())),
+        };
+        let len = f
+            .metadata()
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+            .len();
+
+        // an in-flight transfer owns the partial; don't touch it
+        let Ok(_l) = LockFile::open(lock_path, Duration::ZERO) else {
+            return Ok(len);
+        };
+
+        let mut hasher = self.hash_algo.hasher();
+        let mut good: Vec<(u64, String)> = Vec::new();
+        let mut good_offset = 0;
+        let mut good_hasher = hasher.clone();
+        let mut diverged = false;
+
+        for (offset, digest) in read_checkpoints(&checkpoint_path) {
+            if offset > len {
+                break;
+            }
+            hash_reader(&mut (&mut f).take(offset - good_offset), &mut hasher)
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+            if hasher.clone().finish() != digest {
+                warn!("partial {} diverged after offset {}", name, good_offset);
+                diverged = true;
+                break;
+            }
+            good_offset = offset;
+            good_hasher = hasher.clone();
+            good.push((offset, digest));
+        }
+
+        // nothing past the last checkpoint can be checked, but nothing says
+        // it's wrong either, so it's kept and hashed along with the rest
+        if !diverged {
+            hash_reader(&mut f, &mut good_hasher)
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+            good_offset = len;
+        }
+
+        let mut contents = String::new();
+        for (offset, digest) in &good {
+            contents.push_str(&format!("{} {}\n", offset, digest));
+        }
+        fs::write(&checkpoint_path, contents)
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+
+        if good_offset != len {
+            f.set_len(good_offset)
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+            release_partial_bytes(&self.partial_bytes, len - good_offset);
+        }
+
+        // the transfer that resumes this can pick up from here
+        self.suspended
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), (good_offset, good_hasher));
+
+        Ok(good_offset)
+    }
+
+    /// Delete complete files from the store, then remove any links under
+    /// the transfers dir left dangling by it. With `except` set, everything
+    /// *not* in `sha256sums` is deleted. Returns the deleted sha256sums and
+    /// the number of links removed.
+    pub fn delete_files(
+        &self,
+        sha256sums: &HashSet<String>,
+        except: bool,
+        dry_run: bool,
+    ) -> Result<(Vec<String>, u64), RaptorBoostError> {
+        let mut targets: Vec<String> = if except {
+            stored_files(self.get_complete_dir())
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+                .into_iter()
+                .filter_map(|e| e.file_name().to_str().map(str::to_string))
+                .filter(|name| !sha256sums.contains(name))
+                .collect()
+        } else {
+            sha256sums.iter().cloned().collect()
+        };
+        targets.sort();
+
+        let mut deleted = Vec::with_capacity(targets.len());
+        for sha256sum in targets {
+            let complete_path = self.complete_path(&sha256sum)?;
+            if !complete_path.is_file() {
+                continue;
+            }
+            if !dry_run {
+                fs::remove_file(&complete_path)
+                    .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+            }
+            deleted.push(sha256sum);
+        }
+
+        if dry_run || deleted.is_empty() {
+            return Ok((deleted, 0));
+        }
+
+        let mut links_removed = 0;
+        for entry in WalkDir::new(self.get_transfers_dir())
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.path_is_symlink())
+        {
+            // a symlink whose target is gone
+            if fs::metadata(entry.path()).is_err() && remove_file(entry.path()).is_ok() {
+                links_removed += 1;
+            }
+        }
+
+        Ok((deleted, links_removed))
+    }
+
+    /// List every complete file along with the names under the transfers dir
+    /// that link to it.
+    pub fn list_files(&self) -> Result<Vec<StoredFile>, RaptorBoostError> {
+        let mut names: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in WalkDir::new(self.get_transfers_dir())
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.path_is_symlink())
+        {
+            let Ok(target) = fs::read_link(entry.path()) else {
+                continue;
+            };
+            let (Some(sha256sum), Ok(name)) = (
+                target.file_name().and_then(|n| n.to_str()),
+                entry.path().strip_prefix(self.get_transfers_dir()),
+            ) else {
+                continue;
+            };
+            names
+                .entry(sha256sum.to_string())
+                .or_default()
+                .push(name.to_string_lossy().into_owned());
+        }
+
+        let mut files = Vec::new();
+        for entry in stored_files(self.get_complete_dir())
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+        {
+            let Some(sha256sum) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let size = entry
+                .metadata()
+                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+                .len();
+            let mut file_names = names.remove(&sha256sum).unwrap_or_default();
+            file_names.sort();
+            files.push(StoredFile {
+                sha256sum,
+                size,
+                names: file_names,
+            });
+        }
+        files.sort_by(|a, b| a.sha256sum.cmp(&b.sha256sum));
+
+        Ok(files)
+    }
+
+    /// Count what's stored and how much room is left for more.
+    pub fn stats(&self) -> Result<StoreStats, RaptorBoostError> {
+        let mut complete_files = 0;
+        let mut complete_bytes = 0;
+        for entry in stored_files(self.get_complete_dir())
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+        {
+            complete_files += 1;
+            complete_bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+        }
+
+        // a server that died leaves its lock files behind, so only count
+        // the ones someone holds
+        let active_locks = fs::read_dir(self.get_lock_dir())
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+            .filter_map(Result::ok)
+            .filter(|entry| lock::is_held(&entry.path()))
+            .count() as u64;
+
+        Ok(StoreStats {
+            complete_free: available_space(self.get_complete_dir()).ok(),
+            partial_free: available_space(self.get_partial_dir()).ok(),
+            complete_files,
+            complete_bytes,
+            active_locks,
+        })
+    }
+
+    /// Re-hash every complete file and return the ones that don't hash to
+    /// their name, e.g. from bit rot or a bad restore. `progress` is called
+    /// with the bytes checked so far and the total as it goes. Nothing is
+    /// removed.
+    pub fn verify_store(
+        &self,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Vec<CorruptFile>, RaptorBoostError> {
+        let mut files = Vec::new();
+        for entry in stored_files(self.get_complete_dir())
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+        {
+            let Some(sha256sum) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            files.push((sha256sum, entry.into_path(), size));
+        }
+        files.sort();
+
+        let total = files.iter().map(|(_, _, size)| size).sum();
+        let mut done = 0;
+        progress(done, total);
+
+        let mmap = self.mmap && self.link_mode == LinkMode::Copy;
+        let mut corrupt = Vec::new();
+        for (sha256sum, path, size) in files {
+            let started = done;
+            let mut hasher = self.hash_algo.hasher();
+            let hashed = File::open(&path).and_then(|mut f| {
+                hasher.update_from_file_with_progress(&mut f, HASH_BUFFER_SIZE, mmap, |n| {
+                    done += n;
+                    progress(done, total);
+                })
+            });
+            let problem = match hashed {
+                Err(e) => Some(format!("couldn't read: {}", e)),
+                Ok(()) => {
+                    let actual = hasher.finish();
+                    (actual != sha256sum).then(|| format!("contents hash to {}", actual))
+                }
+            };
+            if let Some(problem) = problem {
+                corrupt.push(CorruptFile { sha256sum, problem });
+            }
+            // a file that couldn't be read in full still counts as checked
+            done = started + size;
+            progress(done, total);
+        }
+
+        Ok(corrupt)
+    }
+
+    /// Remove partial files that haven't been written to in `max_age`, along
+    /// with their checkpoints, and lock files nobody holds. Partials with an
+    /// active lock are never touched. Returns the sha256sums that were
+    /// collected.
+    pub fn gc_partials(&self, max_age: Duration) -> Result<Vec<String>, RaptorBoostError> {
+        let mut collected = Vec::new();
+
+        for entry in stored_files(self.get_partial_dir())
+            .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+        {
+            let Some(sha256sum) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let is_old = entry
+                .metadata()
+                .ok()
+                .and_then(|m| m.modified().ok())
+                .and_then(|mtime| mtime.elapsed().ok())
+                .is_some_and(|age| age > max_age);
+            if !is_old {
+                continue;
+            }
+
+            if !self.remove_idle_partial(&sha256sum) {
+                continue;
+            }
+            collected.push(sha256sum);
+        }
+
+        match lock::remove_stale(self.get_lock_dir()) {
+            Ok(0) => {}
+            Ok(n) => debug!("removed {} stale lock files", n),
+            Err(e) => warn!("couldn't clear stale lock files: {}", e),
+        }
+
+        Ok(collected)
+    }
+
     pub fn get_partial_dir(&self) -> &Path {
         &self.partial_dir
     }
@@ -180,28 +1132,554 @@ impl RaptorBoostController {
         &self.transfers_dir
     }
 
+    /// Every directory the store keeps files in: the output dir, and those
+    /// that may have been moved out of it.
+    pub fn store_dirs(&self) -> Vec<&Path> {
+        let output_dir = self.history_path.parent().unwrap_or(Path::new("."));
+        vec![
+            output_dir,
+            &self.partial_dir,
+            &self.complete_dir,
+            &self.transfers_dir,
+            &self.lock_dir,
+        ]
+    }
+
+    /// Where the complete file for `sha256sum` is kept.
+    pub fn complete_path(&self, sha256sum: &str) -> Result<PathBuf, RaptorBoostError> {
+        self.stored_path(self.get_complete_dir(), sha256sum)
+    }
+
+    /// Where the partial named `name` is written: its checksum, unless
+    /// clients are isolated.
+    pub fn partial_path(&self, name: &str) -> Result<PathBuf, RaptorBoostError> {
+        self.stored_path(self.get_partial_dir(), name)
+    }
+
+    fn stored_path(&self, dir: &Path, sha256sum: &str) -> Result<PathBuf, RaptorBoostError> {
+        let mut path = PathBuf::new();
+        // anything that doesn't look like a checksum stays at the top
+        if let Some(prefix) = sha256sum.get(..2 * self.shard_depth)
+            && prefix.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            for i in 0..self.shard_depth {
+                path.push(&prefix[2 * i..2 * i + 2]);
+            }
+        }
+        path.push(sha256sum);
+        scoped_join(dir, path)
+            .map_err(|_| RaptorBoostError::PathSanitization(sha256sum.to_string()))
+    }
+
+    /// How deep the files already in the store are sharded, going by the
+    /// first one found, or None if it's empty. Cheap enough to check on
+    /// every start.
+    pub fn stored_shard_depth(&self) -> io::Result<Option<usize>> {
+        match shard_depth_of(self.get_complete_dir())? {
+            Some(depth) => Ok(Some(depth)),
+            None => shard_depth_of(self.get_partial_dir()),
+        }
+    }
+
+    /// Move every complete file and partial to where the configured shard
+    /// depth puts it, and repoint the symlinked names of the complete files
+    /// that moved. Hardlinked and copied names don't need it. Only safe
+    /// while no server is using the store. Returns the number of files moved
+    /// and of links repointed.
+    pub fn reshard(&self) -> io::Result<(u64, u64)> {
+        let mut moved = 0;
+        for dir in [self.get_complete_dir(), self.get_partial_dir()] {
+            for entry in stored_files(dir)? {
+                let Some(sha256sum) = entry.file_name().to_str() else {
+                    continue;
+                };
+                let path = self
+                    .stored_path(dir, sha256sum)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+                if path == entry.path() {
+                    continue;
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(entry.path(), &path)?;
+                moved += 1;
+            }
+
+            // the shard dirs of the old layout, now that they're empty
+            for entry in WalkDir::new(dir)
+                .min_depth(1)
+                .contents_first(true)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().is_dir())
+            {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+
+        let mut relinked = 0;
+        for entry in WalkDir::new(self.get_transfers_dir())
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.path_is_symlink())
+        {
+            let Ok(target) = fs::read_link(entry.path()) else {
+                continue;
+            };
+            let Some(sha256sum) = target.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !target.starts_with(self.get_complete_dir()) {
+                continue;
+            }
+            let Ok(new_target) = self.complete_path(sha256sum) else {
+                continue;
+            };
+            if new_target == target || !new_target.exists() {
+                continue;
+            }
+
+            // the link's mtime is the name's, so it survives the move
+            let mtime = fs::symlink_metadata(entry.path())
+                .map(|m| FileTime::from_last_modification_time(&m));
+            remove_file(entry.path())?;
+            link_file(&new_target, entry.path(), LinkMode::Symlink)?;
+            if let Ok(mtime) = mtime {
+                let _ = filetime::set_symlink_file_times(entry.path(), mtime, mtime);
+            }
+            relinked += 1;
+        }
+
+        Ok((moved, relinked))
+    }
+
+    /// Append a received file to the history journal.
+    pub fn record_received(&self, sha256sum: &str, size: u64) -> io::Result<()> {
+        self.record(HistoryEntry {
+            time: unix_now(),
+            sha256sum: sha256sum.to_string(),
+            size,
+            transfer: None,
+            names: Vec::new(),
+        })
+    }
+
+    /// Append the names `transfer` gave a complete file to the history
+    /// journal.
+    pub fn record_named(
+        &self,
+        transfer: &str,
+        sha256sum: &str,
+        names: Vec<String>,
+    ) -> io::Result<()> {
+        let complete_path = self
+            .complete_path(sha256sum)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        self.record(HistoryEntry {
+            time: unix_now(),
+            sha256sum: sha256sum.to_string(),
+            size: fs::metadata(complete_path)?.len(),
+            transfer: Some(transfer.to_string()),
+            names,
+        })
+    }
+
+    fn record(&self, entry: HistoryEntry) -> io::Result<()> {
+        let _guard = self.history_lock.lock().unwrap();
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.history_path)?;
+        writeln!(f, "{}", entry.to_json())
+    }
+
+    /// Everything in the history journal, oldest first, optionally only for
+    /// one file. Lines that don't parse, like one cut short by a crash, are
+    /// skipped.
+    pub fn history(&self, sha256sum: Option<&str>) -> Result<Vec<HistoryEntry>, RaptorBoostError> {
+        let f = match File::open(&self.history_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(RaptorBoostError::OtherError(e.to_string())),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(f).lines() {
+            let line = line.map_err(|e| RaptorBoostError::OtherError(e.to_string()))?;
+            let Some(entry) = HistoryEntry::from_json(&line) else {
+                continue;
+            };
+            if sha256sum.is_none_or(|s| s == entry.sha256sum) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
     pub fn get_version(&self) -> String {
         env!("CARGO_PKG_VERSION").to_string()
     }
 
-    pub fn check_file(&self, sha256sum: &str) -> Result<CheckFileResult, RaptorBoostError> {
-        let full_complete_file = scoped_join(self.get_complete_dir(), sha256sum)
-            .map_err(|_| RaptorBoostError::PathSanitization(sha256sum.to_string()))?;
+    pub fn check_file(
+        &self,
+        sha256sum: &str,
+        peer: Option<SocketAddr>,
+        hash_algo: HashAlgo,
+    ) -> Result<CheckFileResult, RaptorBoostError> {
+        self.check_hash_algo(hash_algo)?;
+
+        let full_complete_file = self.complete_path(sha256sum)?;
 
         if full_complete_file.exists() {
             return Ok(CheckFileResult::FileComplete);
         }
 
-        let full_partial_file = scoped_join(self.get_partial_dir(), sha256sum)
-            .map_err(|_| RaptorBoostError::PathSanitization(sha256sum.to_string()))?;
+        let partial_name = self.partial_name(sha256sum, peer);
+        let full_partial_file = self.partial_path(&partial_name)?;
 
         if full_partial_file.exists() {
-            let offset = fs::metadata(&full_partial_file)
-                .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
-                .len();
+            let offset = if self.verify_partials {
+                self.verify_partial(&partial_name)?
+            } else {
+                fs::metadata(&full_partial_file)
+                    .map_err(|e| RaptorBoostError::OtherError(e.to_string()))?
+                    .len()
+            };
             return Ok(CheckFileResult::FilePartialOffset(offset));
         }
 
-        Ok(CheckFileResult::FilePartialOffset(0))
+        Ok(CheckFileResult::FileNew)
+    }
+}
+
+fn hash_reader<R: Read>(r: &mut R, hasher: &mut Hasher) -> io::Result<()> {
+    let mut buffer = [0; HASH_BUFFER_SIZE];
+    loop {
+        match r.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => hasher.update(&buffer[..n]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_checkpoints(path: &Path) -> Vec<(u64, String)> {
+    let Ok(f) = File::open(path) else {
+        return vec![];
+    };
+
+    BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| {
+            let (offset, digest) = line.split_once(' ')?;
+            Some((offset.parse().ok()?, digest.to_string()))
+        })
+        .collect()
+}
+
+fn release_partial_bytes(partial_bytes: &AtomicU64, n: u64) {
+    // the count can drift from the dir if partials are removed behind our
+    // back, and wrapping around would refuse every transfer
+    let _ = partial_bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+        Some(b.saturating_sub(n))
+    });
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Every file in a complete or partial dir, however deep it's sharded.
+/// Fails only if `dir` itself can't be read.
+fn stored_files(dir: &Path) -> io::Result<Vec<walkdir::DirEntry>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(MAX_SHARD_DEPTH + 1)
+    {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => files.push(entry),
+            Ok(_) => {}
+            Err(e) if e.depth() == 0 => {
+                return Err(e.into_io_error().unwrap_or_else(|| ErrorKind::Other.into()));
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(files)
+}
+
+/// How many directories down the first file in `dir` is.
+fn shard_depth_of(dir: &Path) -> io::Result<Option<usize>> {
+    let mut dir = dir.to_path_buf();
+    for depth in 0..=MAX_SHARD_DEPTH {
+        let Some(entry) = fs::read_dir(&dir)?.next().transpose()? else {
+            return Ok(None);
+        };
+        if !entry.file_type()?.is_dir() {
+            return Ok(Some(depth));
+        }
+        dir = entry.path();
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    const SHA256SUM: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn controller() -> (TempDir, RaptorBoostController) {
+        let dir = tempfile::tempdir().unwrap();
+        let controller = RaptorBoostController::new(dir.path(), &StoreDirs::default()).unwrap();
+        (dir, controller)
+    }
+
+    // a partial last written an hour ago, with a checkpoint alongside it
+    fn stale_partial(controller: &RaptorBoostController) -> (PathBuf, PathBuf) {
+        let partial_path = controller.partial_path(SHA256SUM).unwrap();
+        fs::write(&partial_path, b"te").unwrap();
+        let an_hour_ago = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(3600));
+        filetime::set_file_mtime(&partial_path, an_hour_ago).unwrap();
+        let checkpoint_path = controller.checkpoints_dir.join(SHA256SUM);
+        fs::write(&checkpoint_path, "2 deadbeef\n").unwrap();
+        (partial_path, checkpoint_path)
+    }
+
+    #[test]
+    fn gc_partials_skips_locked_partials() {
+        let (_dir, controller) = controller();
+        let (partial_path, checkpoint_path) = stale_partial(&controller);
+        let _lock =
+            LockFile::open(controller.get_lock_dir().join(SHA256SUM), Duration::ZERO).unwrap();
+
+        let collected = controller.gc_partials(Duration::ZERO).unwrap();
+
+        assert!(collected.is_empty());
+        assert!(partial_path.exists());
+        assert!(checkpoint_path.exists());
+    }
+
+    #[test]
+    fn gc_partials_removes_stale_partials_and_checkpoints() {
+        let (_dir, controller) = controller();
+        let (partial_path, checkpoint_path) = stale_partial(&controller);
+
+        let collected = controller.gc_partials(Duration::ZERO).unwrap();
+
+        assert_eq!(collected, vec![SHA256SUM.to_string()]);
+        assert!(!partial_path.exists());
+        assert!(!checkpoint_path.exists());
+    }
+
+    #[test]
+    fn stats_only_counts_held_locks() {
+        let (_dir, controller) = controller();
+        let lock_dir = controller.get_lock_dir();
+        let _held = LockFile::open(lock_dir.join(SHA256SUM), Duration::ZERO).unwrap();
+        // as left by a server that died holding it
+        File::create(lock_dir.join("stale")).unwrap();
+
+        assert_eq!(controller.stats().unwrap().active_locks, 1);
+    }
+
+    fn digest(data: &[u8]) -> String {
+        let mut hasher = HashAlgo::Sha256.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn verify_partial_keeps_a_partial_without_checkpoints() {
+        let (_dir, controller) = controller();
+        let partial_path = controller.partial_path(SHA256SUM).unwrap();
+        fs::write(&partial_path, [7; 1000]).unwrap();
+
+        assert_eq!(controller.verify_partial(SHA256SUM).unwrap(), 1000);
+        assert_eq!(fs::metadata(&partial_path).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn verify_partial_truncates_to_the_last_matching_checkpoint() {
+        let (_dir, controller) = controller();
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let partial_path = controller.partial_path(SHA256SUM).unwrap();
+        fs::write(&partial_path, &data).unwrap();
+        let checkpoint_path = controller.checkpoints_dir.join(SHA256SUM);
+        fs::write(
+            &checkpoint_path,
+            format!(
+                "1000 {}\n2000 {}\n",
+                digest(&data[..1000]),
+                digest(&data[..2000])
+            ),
+        )
+        .unwrap();
+        let f = OpenOptions::new().write(true).open(&partial_path).unwrap();
+        write_all_at(&f, &[0xff], 1500).unwrap();
+
+        assert_eq!(controller.verify_partial(SHA256SUM).unwrap(), 1000);
+        assert_eq!(fs::metadata(&partial_path).unwrap().len(), 1000);
+        assert_eq!(
+            fs::read_to_string(&checkpoint_path).unwrap(),
+            format!("1000 {}\n", digest(&data[..1000]))
+        );
+    }
+
+    #[test]
+    fn transfer_resumes_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            RaptorBoostController::new(dir.path(), &StoreDirs::default())
+                .unwrap()
+                .with_verify_partials(true)
+        };
+        let data: Vec<u8> = (0..CHECKPOINT_INTERVAL + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let sha256sum = digest(&data);
+        let total_size = Some(data.len() as u64);
+
+        // get past the first checkpoint, then lose the controller and its
+        // hash state
+        let controller = open();
+        let mut transfer = controller
+            .start_transfer(
+                &sha256sum,
+                None,
+                false,
+                HashAlgo::Sha256,
+                total_size,
+                None,
+                None,
+            )
+            .unwrap();
+        transfer
+            .write_all(&data[..CHECKPOINT_INTERVAL as usize + 500])
+            .unwrap();
+        drop(transfer);
+        drop(controller);
+
+        let controller = open();
+        let Ok(CheckFileResult::FilePartialOffset(offset)) =
+            controller.check_file(&sha256sum, None, HashAlgo::Sha256)
+        else {
+            panic!("expected a partial to resume");
+        };
+        assert_eq!(offset, CHECKPOINT_INTERVAL + 500);
+
+        let mut transfer = controller
+            .start_transfer(
+                &sha256sum,
+                None,
+                false,
+                HashAlgo::Sha256,
+                total_size,
+                Some(offset),
+                None,
+            )
+            .unwrap();
+        transfer.write_all(&data[offset as usize..]).unwrap();
+        transfer.complete().unwrap();
+
+        let complete = fs::read(controller.complete_path(&sha256sum).unwrap()).unwrap();
+        assert_eq!(digest(&complete), sha256sum);
+        assert!(!controller.checkpoints_dir.join(&sha256sum).exists());
+    }
+
+    /// Write all of `data` to its partial, as a transfer that was cut off
+    /// while completing would have.
+    fn written_partial(controller: &RaptorBoostController, data: &[u8]) -> String {
+        let sha256sum = digest(data);
+        let mut transfer = controller
+            .start_transfer(
+                &sha256sum,
+                None,
+                false,
+                HashAlgo::Sha256,
+                Some(data.len() as u64),
+                None,
+                None,
+            )
+            .unwrap();
+        transfer.write_all(data).unwrap();
+        sha256sum
+    }
+
+    #[test]
+    fn completion_recovers_from_a_leftover_copy() {
+        let (dir, controller) = controller();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let sha256sum = written_partial(&controller, &data);
+        drop(controller);
+
+        // a copy across filesystems that was cut off halfway
+        let controller = RaptorBoostController::new(dir.path(), &StoreDirs::default()).unwrap();
+        let complete_path = controller.complete_path(&sha256sum).unwrap();
+        let tmp_path = complete_path.with_file_name(format!("{}.tmp", sha256sum));
+        fs::create_dir_all(complete_path.parent().unwrap()).unwrap();
+        fs::write(&tmp_path, &data[..50_000]).unwrap();
+
+        let Ok(CheckFileResult::FilePartialOffset(offset)) =
+            controller.check_file(&sha256sum, None, HashAlgo::Sha256)
+        else {
+            panic!("expected the partial to resume");
+        };
+        assert_eq!(offset, data.len() as u64);
+        controller
+            .start_transfer(
+                &sha256sum,
+                None,
+                false,
+                HashAlgo::Sha256,
+                Some(data.len() as u64),
+                Some(offset),
+                None,
+            )
+            .unwrap()
+            .complete()
+            .unwrap();
+
+        let complete = fs::read(&complete_path).unwrap();
+        assert_eq!(complete.len(), data.len());
+        assert_eq!(digest(&complete), sha256sum);
+        assert!(!tmp_path.exists());
+        assert!(!controller.partial_path(&sha256sum).unwrap().exists());
+    }
+
+    #[test]
+    fn completion_cut_off_after_the_rename_is_complete() {
+        let (dir, controller) = controller();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let sha256sum = written_partial(&controller, &data);
+        // renamed, but cut off before the lock was released
+        let complete_path = controller.complete_path(&sha256sum).unwrap();
+        fs::create_dir_all(complete_path.parent().unwrap()).unwrap();
+        move_file(
+            &controller.partial_path(&sha256sum).unwrap(),
+            &complete_path,
+            true,
+        )
+        .unwrap();
+        drop(controller);
+
+        let controller = RaptorBoostController::new(dir.path(), &StoreDirs::default()).unwrap();
+        assert!(matches!(
+            controller.check_file(&sha256sum, None, HashAlgo::Sha256),
+            Ok(CheckFileResult::FileComplete)
+        ));
+        let complete = fs::read(&complete_path).unwrap();
+        assert_eq!(complete.len(), data.len());
+        assert_eq!(digest(&complete), sha256sum);
+        assert!(controller.verify_store(|_, _| {}).unwrap().is_empty());
     }
 }
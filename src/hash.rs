@@ -0,0 +1,227 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use clap::ValueEnum;
+use memmap2::MmapOptions;
+
+use crate::proto::HashAlgorithm;
+
+// smaller files are read rather than mapped: setting up the mapping costs
+// more than the reads it saves
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+// how much of a file is mapped at once
+const MMAP_WINDOW: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgo {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgo::Sha256 => {
+                Hasher::Ring(Box::new(ring::digest::Context::new(&ring::digest::SHA256)))
+            }
+            HashAlgo::Sha512 => {
+                Hasher::Ring(Box::new(ring::digest::Context::new(&ring::digest::SHA512)))
+            }
+            HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgo::Sha256 => write!(f, "sha256"),
+            HashAlgo::Sha512 => write!(f, "sha512"),
+            HashAlgo::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl From<HashAlgo> for HashAlgorithm {
+    fn from(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha256 => HashAlgorithm::HashalgorithmSha256,
+            HashAlgo::Sha512 => HashAlgorithm::HashalgorithmSha512,
+            HashAlgo::Blake3 => HashAlgorithm::HashalgorithmBlake3,
+        }
+    }
+}
+
+impl From<HashAlgorithm> for HashAlgo {
+    fn from(algo: HashAlgorithm) -> Self {
+        match algo {
+            HashAlgorithm::HashalgorithmSha256 => HashAlgo::Sha256,
+            HashAlgorithm::HashalgorithmSha512 => HashAlgo::Sha512,
+            HashAlgorithm::HashalgorithmBlake3 => HashAlgo::Blake3,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Hasher {
+    Ring(Box<ring::digest::Context>),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Ring(ctx) => ctx.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Hash `f` from its current position to the end, leaving it positioned
+    /// there. Large files are memory-mapped if `mmap` is set, anything else
+    /// is read `buffer_size` bytes at a time.
+    ///
+    /// A file found to be shorter before a window is mapped is read from
+    /// there on instead. That only narrows the race, though: a file
+    /// truncated while a window is being hashed raises SIGBUS on the first
+    /// page past its new end, which kills the process. Only map files
+    /// nothing else truncates.
+    pub fn update_from_file(
+        &mut self,
+        f: &mut File,
+        buffer_size: usize,
+        mmap: bool,
+    ) -> io::Result<()> {
+        self.update_from_file_with_progress(f, buffer_size, mmap, |_| {})
+    }
+
+    /// Like `update_from_file`, calling `progress` with the number of bytes
+    /// hashed after each read or mapped window.
+    pub fn update_from_file_with_progress(
+        &mut self,
+        f: &mut File,
+        buffer_size: usize,
+        mmap: bool,
+        mut progress: impl FnMut(u64),
+    ) -> io::Result<()> {
+        if mmap {
+            let start = f.stream_position()?;
+            let end = f.metadata()?.len();
+            if end.saturating_sub(start) >= MMAP_THRESHOLD {
+                let pos = for_each_mapped(f, start, end, |data| {
+                    self.update(data);
+                    progress(data.len() as u64);
+                })?;
+                // whatever couldn't be mapped is read below
+                f.seek(SeekFrom::Start(pos))?;
+                if pos == end {
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut buffer = vec![0; buffer_size];
+        loop {
+            match f.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    self.update(&buffer[..n]);
+                    progress(n as u64);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the hex-encoded digest.
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Ring(ctx) => hex::encode(ctx.finish()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Call `f` with read-only mappings of `file` covering `start..end`,
+/// `MMAP_WINDOW` bytes at a time. Each window is unmapped when it's dropped,
+/// including when `f` panics. Stops early, returning how far it got, if the
+/// file turns out to be too short for the next window or can't be mapped.
+fn for_each_mapped(file: &File, start: u64, end: u64, mut f: impl FnMut(&[u8])) -> io::Result<u64> {
+    let mut pos = start;
+    while pos < end {
+        let len = MMAP_WINDOW.min(end - pos);
+        if file.metadata()?.len() < pos + len {
+            return Ok(pos);
+        }
+        let len = usize::try_from(len).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        // safety: the window was inside the file just now, and callers only
+        // map files nothing else truncates, so it stays valid while it's read
+        let map = match unsafe { MmapOptions::new().offset(pos).len(len).map(file) } {
+            Ok(map) => map,
+            Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(pos),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);
+        f(&map);
+        pos += len as u64;
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn mapped_and_read_hashes_agree() {
+        let mut f = tempfile::tempfile().unwrap();
+        let data: Vec<u8> = (0..MMAP_THRESHOLD + MMAP_WINDOW + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        f.write_all(&data).unwrap();
+
+        // from an offset that isn't page aligned, like a resumed partial
+        let mut hashes = Vec::new();
+        for mmap in [false, true] {
+            f.seek(SeekFrom::Start(1001)).unwrap();
+            let mut hasher = HashAlgo::Sha256.hasher();
+            hasher.update_from_file(&mut f, 8192, mmap).unwrap();
+            assert_eq!(f.stream_position().unwrap(), data.len() as u64);
+            hashes.push(hasher.finish());
+        }
+
+        let mut expected = HashAlgo::Sha256.hasher();
+        expected.update(&data[1001..]);
+        assert_eq!(hashes, vec![expected.finish(); 2]);
+    }
+
+    #[test]
+    fn file_truncated_between_windows_is_read_instead() {
+        let mut f = tempfile::tempfile().unwrap();
+        let data: Vec<u8> = (0..MMAP_WINDOW * 2).map(|i| (i % 251) as u8).collect();
+        f.write_all(&data).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+
+        // cut off partway into the second window once the first is hashed
+        let truncated = MMAP_WINDOW + 12345;
+        let other = f.try_clone().unwrap();
+        let mut hasher = HashAlgo::Sha256.hasher();
+        hasher
+            .update_from_file_with_progress(&mut f, 8192, true, |_| {
+                other.set_len(truncated).unwrap();
+            })
+            .unwrap();
+        assert_eq!(f.stream_position().unwrap(), truncated);
+
+        let mut expected = HashAlgo::Sha256.hasher();
+        expected.update(&data[..truncated as usize]);
+        assert_eq!(hasher.finish(), expected.finish());
+    }
+}
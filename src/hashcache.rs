@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::hash::HashAlgo;
+
+/// What a file looked like when it was hashed. Any difference means the
+/// cached hash can't be trusted.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: u32,
+    inode: u64,
+}
+
+impl Stamp {
+    pub(crate) fn new(metadata: &Metadata) -> Stamp {
+        let mtime = FileTime::from_last_modification_time(metadata);
+        Stamp {
+            size: metadata.len(),
+            mtime: mtime.unix_seconds(),
+            mtime_nsec: mtime.nanoseconds(),
+            inode: inode(metadata),
+        }
+    }
+
+    /// Add the stamp's fields to `entry`, a json object.
+    pub(crate) fn write_json(&self, entry: &mut Value) {
+        entry["size"] = json!(self.size);
+        entry["mtime"] = json!(self.mtime);
+        entry["mtime_nsec"] = json!(self.mtime_nsec);
+        entry["inode"] = json!(self.inode);
+    }
+
+    pub(crate) fn from_json(entry: &Value) -> Option<Stamp> {
+        Some(Stamp {
+            size: entry.get("size")?.as_u64()?,
+            mtime: entry.get("mtime")?.as_i64()?,
+            mtime_nsec: entry.get("mtime_nsec")?.as_u64()?.try_into().ok()?,
+            inode: entry.get("inode")?.as_u64()?,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Hashes of previously seen files, keyed by their canonical path, so files
+/// that haven't changed since the last run don't have to be read again.
+/// Each hash algorithm gets its own cache file.
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (Stamp, String)>,
+    dirty: bool,
+}
+
+impl HashCache {
+    /// Load the cache for `hash_algo` from `dir`. A missing cache starts out
+    /// empty, and so does an unreadable one, with a warning.
+    pub fn load(dir: &Path, hash_algo: HashAlgo) -> HashCache {
+        let path = dir.join(format!("hashes-{}.json", hash_algo));
+        let entries = match fs::read(&path) {
+            Ok(contents) => parse_entries(&contents).unwrap_or_else(|| {
+                warn!("ignoring corrupt hash cache {}", path.display());
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("couldn't read hash cache {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        HashCache {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// The cached hash of `filename`, if it hasn't changed since.
+    pub fn get(&self, filename: &Path, metadata: &Metadata) -> Option<&str> {
+        let key = fs::canonicalize(filename).ok()?;
+        match self.entries.get(&key) {
+            Some((stamp, hash)) if *stamp == Stamp::new(metadata) => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Remember `hash` for `filename` as it was described by `metadata`,
+    /// which should have been read before hashing started.
+    pub fn insert(&mut self, filename: &Path, metadata: &Metadata, hash: String) {
+        if let Ok(key) = fs::canonicalize(filename) {
+            self.entries.insert(key, (Stamp::new(metadata), hash));
+            self.dirty = true;
+        }
+    }
+
+    /// Write the cache back if anything was added.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let entries: serde_json::Map<String, Value> = self
+            .entries
+            .iter()
+            .map(|(path, (stamp, hash))| {
+                let mut entry = json!({ "hash": hash });
+                stamp.write_json(&mut entry);
+                (path.to_string_lossy().into_owned(), entry)
+            })
+            .collect();
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // written to the side first so an interrupted save can't leave a
+        // truncated cache behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, Value::Object(entries).to_string())?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn parse_entries(contents: &[u8]) -> Option<HashMap<PathBuf, (Stamp, String)>> {
+    let Value::Object(map) = serde_json::from_slice(contents).ok()? else {
+        return None;
+    };
+
+    map.into_iter()
+        .map(|(path, entry)| {
+            let stamp = Stamp::from_json(&entry)?;
+            let hash = entry.get("hash")?.as_str()?.to_string();
+            Some((PathBuf::from(path), (stamp, hash)))
+        })
+        .collect()
+}
+
+/// The per-user cache directory: `$XDG_CACHE_HOME/raptorboost`,
+/// `~/.cache/raptorboost`, or `%LOCALAPPDATA%\raptorboost` on windows.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(base.join("raptorboost"))
+}
@@ -0,0 +1,79 @@
+use std::pin::Pin;
+
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use self::proto::health_check_response::ServingStatus;
+use self::proto::health_server::Health;
+use self::proto::{HealthCheckRequest, HealthCheckResponse};
+
+pub mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+/// The services whose health is reported: the server as a whole, and the
+/// one service it runs.
+const SERVICES: [&str; 2] = ["", "raptorboost.RaptorBoost"];
+
+/// The standard grpc health service, for supervisors and load balancers.
+/// Everything is serving until `serving` turns false, which rbs does as
+/// soon as it's asked to shut down, so traffic moves elsewhere while the
+/// transfers in flight drain.
+pub struct HealthService {
+    pub serving: watch::Receiver<bool>,
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status.into(),
+    }
+}
+
+fn serving_status(serving: bool) -> ServingStatus {
+    match serving {
+        true => ServingStatus::Serving,
+        false => ServingStatus::NotServing,
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        if !SERVICES.contains(&service.as_str()) {
+            return Err(Status::not_found(format!("unknown service '{}'", service)));
+        }
+        Ok(Response::new(response(serving_status(
+            *self.serving.borrow(),
+        ))))
+    }
+
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + 'static>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        // an unknown service is reported as such rather than failing, in
+        // case it's registered later; none ever is
+        if !SERVICES.contains(&service.as_str()) {
+            let unknown = tokio_stream::once(Ok(response(ServingStatus::ServiceUnknown)));
+            return Ok(Response::new(Box::pin(
+                unknown.chain(tokio_stream::pending()),
+            )));
+        }
+
+        let mut last: Option<bool> = None;
+        let statuses = WatchStream::new(self.serving.clone())
+            .filter(move |&serving| last.replace(serving) != Some(serving))
+            .map(|serving| Ok(response(serving_status(serving))));
+        Ok(Response::new(Box::pin(statuses)))
+    }
+}
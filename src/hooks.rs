@@ -0,0 +1,89 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+// how many hooks can be running at once; the rest wait their turn
+const MAX_RUNNING: usize = 8;
+
+/// Programs the server runs after a file is received or names are assigned,
+/// for post-processing like reindexing or notifying someone. Each runs in
+/// the background, so a slow hook never holds up the RPC that started it,
+/// and its exit status is only logged.
+#[derive(Clone)]
+pub struct Hooks {
+    on_complete: Option<PathBuf>,
+    on_assign: Option<PathBuf>,
+    running: Arc<Semaphore>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            on_complete: None,
+            on_assign: None,
+            running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        }
+    }
+}
+
+impl Hooks {
+    /// Run `program` with a file's checksum and where it's stored each time
+    /// one is received.
+    pub fn with_on_complete(mut self, program: Option<PathBuf>) -> Self {
+        self.on_complete = program;
+        self
+    }
+
+    /// Run `program` with the transfer's name and directory each time names
+    /// are assigned.
+    pub fn with_on_assign(mut self, program: Option<PathBuf>) -> Self {
+        self.on_assign = program;
+        self
+    }
+
+    pub fn completed(&self, sha256sum: &str, path: &Path) {
+        if let Some(program) = &self.on_complete {
+            self.run("on-complete", program, [sha256sum.into(), path.into()]);
+        }
+    }
+
+    pub fn assigned(&self, transfer_name: &str, transfer_dir: &Path) {
+        if let Some(program) = &self.on_assign {
+            self.run(
+                "on-assign",
+                program,
+                [transfer_name.into(), transfer_dir.into()],
+            );
+        }
+    }
+
+    // the program is run directly, not through a shell, so nothing a client
+    // sends is ever interpreted as shell syntax
+    fn run(&self, hook: &'static str, program: &Path, args: [OsString; 2]) {
+        let program = program.to_path_buf();
+        let running = self.running.clone();
+        tokio::spawn(async move {
+            let Ok(_running) = running.acquire_owned().await else {
+                return;
+            };
+            let status = Command::new(&program)
+                .args(&args)
+                .stdin(Stdio::null())
+                .status()
+                .await;
+            let first_arg = args[0].to_string_lossy();
+            match status {
+                Ok(status) if status.success() => {
+                    debug!("{} hook for {} finished", hook, first_arg)
+                }
+                Ok(status) => warn!("{} hook for {} failed: {}", hook, first_arg, status),
+                Err(e) => warn!("couldn't run {} hook {}: {}", hook, program.display(), e),
+            }
+        });
+    }
+}
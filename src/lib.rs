@@ -0,0 +1,26 @@
+#![allow(clippy::result_large_err)]
+
+#[allow(clippy::enum_variant_names)]
+pub mod proto {
+    tonic::include_proto!("raptorboost");
+}
+
+pub mod accesslog;
+pub mod archive;
+pub mod auth;
+pub mod client;
+pub mod controller;
+pub mod hash;
+pub mod hashcache;
+pub mod health;
+pub mod hooks;
+mod lock;
+pub mod logging;
+pub mod manifest;
+pub mod plan;
+mod platform;
+pub mod progress;
+pub mod ratelimit;
+pub mod service;
+pub mod units;
+pub mod walk;
@@ -1,28 +1,182 @@
-use std::{fs::OpenOptions, path::PathBuf};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
 
+use tracing::warn;
+
+// how often a busy lock is retried while waiting for it
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock (`flock` on unix) held on a file in the lock dir. The OS
+/// drops it along with the file handle, so a server that dies can't leave a
+/// stale lock behind, and servers sharing the dir respect each other's locks.
+/// The file is removed on release, but one left by a server that died, or
+/// that couldn't be removed, stays behind unlocked until `remove_stale`
+/// clears it.
 #[derive(Debug)]
 pub struct LockFile {
     path: PathBuf,
+    file: File,
+    released: bool,
 }
 
 impl LockFile {
-    pub fn open(path: PathBuf) -> Result<LockFile, String> {
-        if let Err(e) = OpenOptions::new().create_new(true).write(true).open(&path) {
-            return Err(format!("couldn't create lock: {}", e));
-        };
+    /// Take the lock at `path`, waiting up to `timeout` for its holder to
+    /// let go. Blocks the calling thread while it waits.
+    pub fn open(path: PathBuf, timeout: Duration) -> Result<LockFile, String> {
+        let started = Instant::now();
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(|e| format!("couldn't create lock: {}", e))?;
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let waited = started.elapsed();
+                    if waited >= timeout {
+                        return Err("already locked".to_string());
+                    }
+                    thread::sleep(LOCK_POLL_INTERVAL.min(timeout - waited));
+                    continue;
+                }
+                Err(TryLockError::Error(e)) => return Err(format!("couldn't lock: {}", e)),
+            }
 
-        Ok(LockFile { path })
+            // the previous holder removes the file on release, possibly
+            // between our open and lock, and a lock on a file that's no longer
+            // at `path` keeps nobody out
+            if is_same_file(&file, &path) {
+                return Ok(LockFile {
+                    path,
+                    file,
+                    released: false,
+                });
+            }
+        }
+    }
+
+    /// Remove the lock file now, for callers that want to see the error
+    /// rather than have Drop log it. The lock itself is held until drop.
+    pub fn release(&mut self) -> io::Result<()> {
+        self.released = true;
+        self.remove()
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        // a forced unlock may have replaced the file with someone else's
+        if !is_same_file(&self.file, &self.path) {
+            return Ok(());
+        }
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
     }
 }
 
 impl Drop for LockFile {
     fn drop(&mut self) {
-        if let Err(e) = std::fs::remove_file(&self.path) {
-            eprintln!(
-                "warning: failed to remove lock file {}: {}",
-                self.path.display(),
-                e
-            );
+        if self.released {
+            return;
+        }
+        if let Err(e) = self.remove() {
+            warn!("failed to remove lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Remove the lock files in `dir` that nobody holds, returning how many were
+/// removed. Held ones, including those of other servers sharing the dir,
+/// are left alone.
+pub fn remove_stale(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
         }
+        // taking the lock and letting it go removes the file
+        if LockFile::open(path, Duration::ZERO).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether someone holds the lock at `path`.
+pub fn is_held(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    matches!(file.try_lock(), Err(TryLockError::WouldBlock))
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    // an open file can't be removed out from under us here
+    path.exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_waits_for_the_first_to_be_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock");
+
+        let first = LockFile::open(path.clone(), Duration::ZERO).unwrap();
+        assert_eq!(
+            LockFile::open(path.clone(), Duration::ZERO).unwrap_err(),
+            "already locked"
+        );
+
+        let waiter = thread::spawn({
+            let path = path.clone();
+            move || LockFile::open(path, Duration::from_secs(10))
+        });
+        thread::sleep(LOCK_POLL_INTERVAL * 2);
+        assert!(!waiter.is_finished());
+        drop(first);
+        let second = waiter.join().unwrap().unwrap();
+        assert!(path.exists());
+
+        drop(second);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_stale_keeps_held_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let held_path = dir.path().join("held");
+        let stale_path = dir.path().join("stale");
+        let _held = LockFile::open(held_path.clone(), Duration::ZERO).unwrap();
+        // as left by a server that died holding it
+        File::create(&stale_path).unwrap();
+
+        assert!(is_held(&held_path));
+        assert!(!is_held(&stale_path));
+        assert_eq!(remove_stale(dir.path()).unwrap(), 1);
+        assert!(held_path.exists());
+        assert!(!stale_path.exists());
     }
 }
@@ -0,0 +1,19 @@
+use std::io::{self, IsTerminal};
+
+use tracing_subscriber::EnvFilter;
+
+/// Send log output to stderr. An explicit `level` (anything `RUST_LOG`
+/// accepts, e.g. `debug` or `raptorboost=trace`) wins over `RUST_LOG`, which
+/// wins over `default`.
+pub fn init(level: Option<&str>, default: &str) {
+    let filter = match level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default)),
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
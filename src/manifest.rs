@@ -0,0 +1,54 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::hash::HashAlgo;
+
+/// How a manifest of a transfer's names and their checksums is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    /// `<checksum>  <name>` lines, as `sha256sum -c` (or `b3sum -c`) reads
+    Text,
+    /// One json object listing each name and its checksum
+    Json,
+}
+
+/// Write a manifest of `entries`, each a name and the checksum of its
+/// contents, sorted by name. Names are relative to the transfer dir.
+pub fn write_manifest(
+    mut w: impl Write,
+    format: ManifestFormat,
+    hash_algo: HashAlgo,
+    entries: &[(String, String)],
+) -> io::Result<()> {
+    let mut entries: Vec<&(String, String)> = entries.iter().collect();
+    entries.sort();
+
+    match format {
+        ManifestFormat::Text => {
+            for (name, checksum) in entries {
+                // coreutils escapes names it can't otherwise write on one
+                // line, and marks the line so they're unescaped when checking
+                if name.contains(['\\', '\n', '\r']) {
+                    let escaped = name
+                        .replace('\\', "\\\\")
+                        .replace('\n', "\\n")
+                        .replace('\r', "\\r");
+                    writeln!(w, "\\{}  {}", checksum, escaped)?;
+                } else {
+                    writeln!(w, "{}  {}", checksum, name)?;
+                }
+            }
+        }
+        ManifestFormat::Json => {
+            let files: Vec<_> = entries
+                .iter()
+                .map(|(name, checksum)| json!({ "name": name, "checksum": checksum }))
+                .collect();
+            let manifest = json!({ "hash": hash_algo.to_string(), "files": files });
+            writeln!(w, "{}", manifest)?;
+        }
+    }
+    w.flush()
+}
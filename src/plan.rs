@@ -0,0 +1,247 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::hashcache::Stamp;
+
+/// A file to send, as it was when it was checksummed.
+pub struct PlannedFile {
+    pub filename: String,
+    pub sha256sum: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    stamp: Stamp,
+}
+
+impl PlannedFile {
+    /// `metadata` should have been read before hashing started.
+    pub fn new(filename: String, sha256sum: String, metadata: &Metadata) -> PlannedFile {
+        PlannedFile {
+            filename,
+            sha256sum,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            stamp: Stamp::new(metadata),
+        }
+    }
+}
+
+/// The files a run found and checksummed, in the order they're sent. Saved
+/// to a state file, it lets a client that was killed skip straight to asking
+/// the server where each file got to. It's only reused while none of its
+/// files, and none of the directories they were found in, have changed.
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+    pub empty_dirs: Vec<String>,
+    /// Symlinks to recreate as they are, with their targets.
+    pub symlinks: Vec<(String, String)>,
+    /// The directories that were walked to find the files. A file added
+    /// anywhere under them makes the plan out of date.
+    pub roots: Vec<String>,
+    /// Paths that were skipped because they couldn't be read, and why. The
+    /// plan is out of date once any of them can be.
+    pub unreadable: Vec<(String, String)>,
+}
+
+/// Where the plan of a run identified by `key` is kept in `dir`.
+pub fn plan_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("plan-{}.json", key))
+}
+
+impl Plan {
+    /// Load the plan saved at `path`, unless there isn't one or it's out of
+    /// date. An unreadable plan is ignored with a warning.
+    pub fn load(path: &Path) -> Option<Plan> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("couldn't read plan {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let Some(saved) = parse_plan(&contents) else {
+            warn!("ignoring corrupt plan {}", path.display());
+            return None;
+        };
+
+        for (dir, stamp) in &saved.dirs {
+            if fs::metadata(dir).map(|m| Stamp::new(&m)).ok().as_ref() != Some(stamp) {
+                debug!("plan {} is out of date: {} changed", path.display(), dir);
+                return None;
+            }
+        }
+        for (unreadable, _) in &saved.unreadable {
+            if File::open(unreadable).is_ok() {
+                debug!(
+                    "plan {} is out of date: {} is readable",
+                    path.display(),
+                    unreadable
+                );
+                return None;
+            }
+        }
+        let mut files = Vec::with_capacity(saved.files.len());
+        for (filename, sha256sum, stamp) in saved.files {
+            match fs::metadata(&filename) {
+                Ok(metadata) if Stamp::new(&metadata) == stamp => {
+                    files.push(PlannedFile::new(filename, sha256sum, &metadata))
+                }
+                _ => {
+                    debug!(
+                        "plan {} is out of date: {} changed",
+                        path.display(),
+                        filename
+                    );
+                    return None;
+                }
+            }
+        }
+
+        Some(Plan {
+            files,
+            empty_dirs: saved.empty_dirs,
+            symlinks: saved.symlinks,
+            roots: saved.roots,
+            unreadable: saved.unreadable,
+        })
+    }
+
+    /// Save the plan to `path`, along with how the directories under its
+    /// roots look now.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .map(|f| {
+                let mut entry = json!({ "filename": f.filename, "sha256sum": f.sha256sum });
+                f.stamp.write_json(&mut entry);
+                entry
+            })
+            .collect();
+        let dirs: Vec<Value> = self
+            .dirs()
+            .into_iter()
+            .map(|dir| {
+                let mut entry = json!({ "path": dir });
+                Stamp::new(&fs::metadata(dir)?).write_json(&mut entry);
+                Ok(entry)
+            })
+            .collect::<io::Result<_>>()?;
+        let plan = json!({
+            "files": files,
+            "empty_dirs": self.empty_dirs,
+            "symlinks": self.symlinks,
+            "roots": self.roots,
+            "unreadable": self.unreadable,
+            "dirs": dirs,
+        });
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // written to the side first, like the hash cache
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, plan.to_string())?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Every directory from each root down to the files, empty directories
+    /// and symlinks found in it, which is everywhere a new file would change
+    /// a directory's mtime.
+    fn dirs(&self) -> BTreeSet<&str> {
+        let mut dirs: BTreeSet<&str> = self.empty_dirs.iter().map(String::as_str).collect();
+        let paths = self
+            .files
+            .iter()
+            .map(|f| &f.filename)
+            .chain(&self.empty_dirs)
+            .chain(self.symlinks.iter().map(|(link, _)| link));
+        for path in paths {
+            for root in &self.roots {
+                if !Path::new(path).starts_with(root) {
+                    continue;
+                }
+                let mut dir = Path::new(path);
+                while let Some(parent) = dir.parent() {
+                    dirs.insert(parent.to_str().unwrap_or_default());
+                    if parent == Path::new(root) {
+                        break;
+                    }
+                    dir = parent;
+                }
+            }
+        }
+        dirs.remove("");
+        dirs
+    }
+}
+
+/// A plan as it was saved, before checking it's still up to date.
+struct SavedPlan {
+    files: Vec<(String, String, Stamp)>,
+    empty_dirs: Vec<String>,
+    symlinks: Vec<(String, String)>,
+    roots: Vec<String>,
+    unreadable: Vec<(String, String)>,
+    dirs: Vec<(String, Stamp)>,
+}
+
+fn parse_plan(contents: &[u8]) -> Option<SavedPlan> {
+    let plan: Value = serde_json::from_slice(contents).ok()?;
+    let strings = |key: &str| -> Option<Vec<String>> {
+        plan.get(key)?
+            .as_array()?
+            .iter()
+            .map(|s| s.as_str().map(str::to_string))
+            .collect()
+    };
+
+    let files = plan
+        .get("files")?
+        .as_array()?
+        .iter()
+        .map(|f| {
+            Some((
+                f.get("filename")?.as_str()?.to_string(),
+                f.get("sha256sum")?.as_str()?.to_string(),
+                Stamp::from_json(f)?,
+            ))
+        })
+        .collect::<Option<_>>()?;
+    let pairs = |key: &str| -> Option<Vec<(String, String)>> {
+        plan.get(key)?
+            .as_array()?
+            .iter()
+            .map(|u| {
+                Some((
+                    u.get(0)?.as_str()?.to_string(),
+                    u.get(1)?.as_str()?.to_string(),
+                ))
+            })
+            .collect()
+    };
+
+    Some(SavedPlan {
+        files,
+        empty_dirs: strings("empty_dirs")?,
+        // plans saved before symlinks could be preserved have none
+        symlinks: match plan.get("symlinks") {
+            Some(_) => pairs("symlinks")?,
+            None => Vec::new(),
+        },
+        roots: strings("roots")?,
+        unreadable: pairs("unreadable")?,
+        dirs: plan
+            .get("dirs")?
+            .as_array()?
+            .iter()
+            .map(|d| Some((d.get("path")?.as_str()?.to_string(), Stamp::from_json(d)?)))
+            .collect::<Option<_>>()?,
+    })
+}
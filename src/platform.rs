@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+#[cfg(not(unix))]
+use std::path::Component;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+pub use safe_path::scoped_join;
+
+use crate::controller::LinkMode;
+
+/// Lexically resolve `unsafe_path` relative to `root`, clamping `..` at the
+/// root. Unlike safe_path on unix this doesn't follow symlinks.
+#[cfg(not(unix))]
+fn scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(_root: R, unsafe_path: U) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for c in unsafe_path.as_ref().components() {
+        match c {
+            Component::Normal(p) => resolved.push(p),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(not(unix))]
+pub fn scoped_join<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> io::Result<PathBuf> {
+    Ok(root.as_ref().join(scoped_resolve(&root, unsafe_path)?))
+}
+
+/// Create a symlink at `link` pointing at `target`, as it's given.
+#[cfg(unix)]
+pub fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(windows)]
+fn symlinks_unsupported(e: &io::Error) -> bool {
+    // ERROR_PRIVILEGE_NOT_HELD: creating symlinks needs admin or developer mode
+    e.raw_os_error() == Some(1314)
+        || matches!(
+            e.kind(),
+            ErrorKind::PermissionDenied | ErrorKind::Unsupported
+        )
+}
+
+#[cfg(not(windows))]
+fn symlinks_unsupported(e: &io::Error) -> bool {
+    // e.g. FAT/exFAT mounts refuse symlinks with EPERM
+    matches!(
+        e.kind(),
+        ErrorKind::PermissionDenied | ErrorKind::Unsupported
+    )
+}
+
+/// Make `link` refer to the file at `target` using `mode`. Where the
+/// platform or filesystem doesn't allow that, a symlink falls back to a
+/// hardlink, and a hardlink to a plain copy. Returns the mode actually used.
+pub fn link_file(target: &Path, link: &Path, mode: LinkMode) -> io::Result<LinkMode> {
+    if mode == LinkMode::Symlink {
+        match symlink_file(target, link) {
+            Err(e) if symlinks_unsupported(&e) => {}
+            r => return r.map(|_| LinkMode::Symlink),
+        }
+    }
+
+    if mode != LinkMode::Copy {
+        match fs::hard_link(target, link) {
+            // e.g. the transfers dir is on another filesystem than the store
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => {}
+            r => return r.map(|_| LinkMode::Hardlink),
+        }
+    }
+
+    // create_new so an existing name is reported rather than overwritten
+    let mut src = fs::File::open(target)?;
+    let mut dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(link)?;
+    if let Err(e) = io::copy(&mut src, &mut dst) {
+        let _ = fs::remove_file(link);
+        return Err(e);
+    }
+    Ok(LinkMode::Copy)
+}
+
+/// Rename `from` to `to`, falling back to copying when they're on different
+/// filesystems. The copy goes to a temporary name next to `to` first so that
+/// `to` never exists half-written; with `sync` the copy is flushed to disk
+/// before it takes that name. A temporary copy left by a crash is removed
+/// by the next move to `to`.
+pub fn move_file(from: &Path, to: &Path, sync: bool) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+        Ok(()) => {
+            let _ = fs::remove_file(tmp_path(to));
+            return Ok(());
+        }
+        r => return r,
+    }
+    copy_then_remove(from, to, sync)
+}
+
+/// Where `move_file` copies to before the copy takes the name `to`.
+fn tmp_path(to: &Path) -> PathBuf {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    to.with_file_name(tmp_name)
+}
+
+/// `move_file` across filesystems.
+fn copy_then_remove(from: &Path, to: &Path, sync: bool) -> io::Result<()> {
+    let tmp = tmp_path(to);
+
+    let copy = || -> io::Result<()> {
+        fs::copy(from, &tmp)?;
+        if sync {
+            fs::File::open(&tmp)?.sync_all()?;
+        }
+        fs::rename(&tmp, to)
+    };
+    if let Err(e) = copy() {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+/// Positioned writes, which leave a file's cursor where it is.
+pub trait WriteAt {
+    /// Write some of `buf` at `offset`, returning how much was written.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+}
+
+#[cfg(unix)]
+impl WriteAt for fs::File {
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl WriteAt for fs::File {
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        // moves the cursor, but nothing here relies on it
+        std::os::windows::fs::FileExt::seek_write(self, buf, offset)
+    }
+}
+
+/// Write all of `buf` to `f` at `offset`, regardless of the file's cursor.
+/// A write interrupted by a signal is retried, and one that writes nothing
+/// fails with `WriteZero` instead of spinning.
+pub fn write_all_at<W: WriteAt + ?Sized>(f: &W, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match f.write_at(buf, offset) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Flush a directory's entries to disk, so a file renamed into it is still
+/// there after a crash.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> io::Result<()> {
+    // directories can't be opened as files here; ntfs journals renames anyway
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::from(ErrorKind::Unsupported))
+}
+
+/// The id of the filesystem a file is on, where the platform has one.
+#[cfg(unix)]
+pub fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    // the only permission windows has is read-only
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn move_across_filesystems_copies_then_removes() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::write(&from, b"contents").unwrap();
+
+        copy_then_remove(&from, &to, true).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"contents");
+        assert!(!from.exists());
+        assert!(!dir.path().join("to.tmp").exists());
+    }
+
+    #[test]
+    fn failed_move_across_filesystems_leaves_both_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::write(&from, b"contents").unwrap();
+        // a directory with something in it can't be renamed over
+        let to = dir.path().join("to");
+        fs::create_dir(&to).unwrap();
+        fs::write(to.join("kept"), b"").unwrap();
+
+        assert!(copy_then_remove(&from, &to, false).is_err());
+        assert_eq!(fs::read(&from).unwrap(), b"contents");
+        assert!(to.join("kept").exists());
+        assert!(!dir.path().join("to.tmp").exists());
+    }
+
+    /// Answers each write with the next of `results`, recording where the
+    /// writes that succeeded went.
+    struct Scripted {
+        results: RefCell<VecDeque<io::Result<usize>>>,
+        written: RefCell<Vec<(u64, Vec<u8>)>>,
+    }
+
+    impl Scripted {
+        fn new(results: Vec<io::Result<usize>>) -> Scripted {
+            Scripted {
+                results: RefCell::new(results.into()),
+                written: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl WriteAt for Scripted {
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            let result = self
+                .results
+                .borrow_mut()
+                .pop_front()
+                .expect("too many writes");
+            if let Ok(n) = result {
+                self.written.borrow_mut().push((offset, buf[..n].to_vec()));
+            }
+            result
+        }
+    }
+
+    #[test]
+    fn write_all_at_retries_interrupted_and_short_writes() {
+        let f = Scripted::new(vec![
+            Err(ErrorKind::Interrupted.into()),
+            Ok(2),
+            Err(ErrorKind::Interrupted.into()),
+            Ok(3),
+        ]);
+        write_all_at(&f, b"hello", 10).unwrap();
+        assert_eq!(
+            f.written.into_inner(),
+            vec![(10, b"he".to_vec()), (12, b"llo".to_vec())]
+        );
+    }
+
+    #[test]
+    fn write_all_at_fails_on_a_zero_length_write() {
+        let f = Scripted::new(vec![Err(ErrorKind::Interrupted.into()), Ok(0)]);
+        let e = write_all_at(&f, b"hello", 0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WriteZero);
+        assert!(f.results.borrow().is_empty());
+    }
+
+    #[test]
+    fn write_all_at_writes_files_at_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        let f = fs::File::create(&path).unwrap();
+        write_all_at(&f, b"world", 6).unwrap();
+        write_all_at(&f, b"hello ", 0).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello world");
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressDrawTarget, TermLike};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// animated bars on a terminal, periodic progress lines otherwise
+    Auto,
+    /// animated bars even when stderr isn't a terminal
+    Always,
+    /// no progress output at all
+    Never,
+}
+
+impl fmt::Display for ProgressMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressMode::Auto => write!(f, "auto"),
+            ProgressMode::Always => write!(f, "always"),
+            ProgressMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+// how often progress is printed when it can't be animated
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Create the container for all of a run's progress bars, drawing them on
+/// stderr as `mode` asks. Animated bars are redrawn at most once per
+/// `interval`, however often they're updated; periodic lines keep their own
+/// pace.
+pub fn multi_progress(mode: ProgressMode, interval: Duration) -> MultiProgress {
+    let hz = redraw_rate(interval);
+    let target = match mode {
+        ProgressMode::Auto if !io::stderr().is_terminal() => {
+            ProgressDrawTarget::term_like_with_hz(Box::new(LineTerm::new(LINE_INTERVAL)), 1)
+        }
+        ProgressMode::Auto => ProgressDrawTarget::stderr_with_hz(hz),
+        ProgressMode::Always => {
+            ProgressDrawTarget::term_like_with_hz(Box::new(console::Term::stderr()), hz)
+        }
+        ProgressMode::Never => ProgressDrawTarget::hidden(),
+    };
+    MultiProgress::with_draw_target(target)
+}
+
+/// Redraws per second for `interval`, within what indicatif accepts.
+fn redraw_rate(interval: Duration) -> u8 {
+    if interval.is_zero() {
+        return u8::MAX;
+    }
+    (1.0 / interval.as_secs_f64()).clamp(1.0, u8::MAX as f64) as u8
+}
+
+/// A stand-in terminal for logs and pipes. Cursor movement is ignored and
+/// only every so many seconds is a whole frame of bars printed, one plain
+/// line per bar.
+#[derive(Debug)]
+struct LineTerm {
+    interval: Duration,
+    state: Mutex<LineTermState>,
+}
+
+#[derive(Debug)]
+struct LineTermState {
+    frame: Vec<String>,
+    last_printed: Option<Instant>,
+}
+
+impl LineTerm {
+    fn new(interval: Duration) -> LineTerm {
+        LineTerm {
+            interval,
+            state: Mutex::new(LineTermState {
+                frame: Vec::new(),
+                last_printed: None,
+            }),
+        }
+    }
+
+    fn push(&self, s: &str) {
+        let s = s.trim_end();
+        if !s.is_empty() {
+            self.state.lock().unwrap().frame.push(s.to_string());
+        }
+    }
+}
+
+impl TermLike for LineTerm {
+    fn width(&self) -> u16 {
+        80
+    }
+
+    fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.push(s);
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.push(s);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let frame = std::mem::take(&mut state.frame);
+        if frame.is_empty()
+            || state
+                .last_printed
+                .is_some_and(|t| t.elapsed() < self.interval)
+        {
+            return Ok(());
+        }
+        state.last_printed = Some(Instant::now());
+
+        let mut stderr = io::stderr().lock();
+        for line in frame {
+            writeln!(stderr, "{}", line)?;
+        }
+        stderr.flush()
+    }
+}
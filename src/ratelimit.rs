@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Spaces out sends so the average throughput stays at `rate` bytes per
+/// second. Shared by reference, so every transfer using the same limiter
+/// draws from one budget.
+pub struct RateLimiter {
+    rate: u64,
+    // the earliest time the next send may start
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `n` more bytes may be sent.
+    pub async fn throttle(&self, n: usize) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            // idle time isn't banked, so a pause doesn't turn into a burst
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(n as f64 / self.rate as f64);
+            start - now
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Stops new files being started once `max` bytes have been sent. The file
+/// already going out is finished, so a transfer can run past the cap by up
+/// to one file. Shared by reference like `RateLimiter`, so every transfer
+/// using the same cap counts against it.
+pub struct ByteCap {
+    max: u64,
+    sent: AtomicU64,
+}
+
+impl ByteCap {
+    pub fn new(max: u64) -> ByteCap {
+        ByteCap {
+            max,
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Whether no more files should be started.
+    pub fn reached(&self) -> bool {
+        self.sent.load(Ordering::Relaxed) >= self.max
+    }
+}
@@ -1,36 +1,81 @@
 use std::collections::HashSet;
-use std::fs::{create_dir, create_dir_all, remove_dir_all};
-use std::os::unix::fs::symlink;
-use std::path::Path;
+use std::fs::{File, create_dir, create_dir_all, remove_dir_all, remove_file};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::controller::{self, RaptorBoostError, RaptorBoostTransfer};
+use crate::accesslog::{Access, AccessLog};
+use crate::controller::{self, LinkMode, RaptorBoostError, RaptorBoostTransfer};
+use crate::hash::HashAlgo;
+use crate::hooks::Hooks;
+use crate::manifest::{ManifestFormat, write_manifest};
+use crate::platform::{link_file, scoped_join, set_mode, symlink_file};
+use crate::proto;
 use crate::proto::raptor_boost_server::RaptorBoost;
 use crate::proto::{
-    AssignNamesRequest, AssignNamesResponse, FileData, FileState, FileStateResult,
-    GetVersionRequest, GetVersionResponse, SendFileDataResponse, SendFileDataStatus,
-    Sha256Filenames, UploadFilesRequest, UploadFilesResponse,
+    AssignNameStatus, AssignNamesRequest, AssignNamesResponse, DeleteFilesRequest,
+    DeleteFilesResponse, FileAttributes, FileData, FileState, FileStateResult, GetHistoryRequest,
+    GetHistoryResponse, GetStatsRequest, GetStatsResponse, GetVersionRequest, GetVersionResponse,
+    HistoryEntry, ListFilesRequest, ListFilesResponse, ListedFile, NameStatus,
+    SendFileDataResponse, SendFileDataStatus, UploadFilesRequest, UploadFilesResponse,
 };
 
-use chrono::Local;
-use safe_path::{scoped_join, scoped_resolve};
+use bytes::Bytes;
+use chrono::{DateTime, Local};
+use filetime::FileTime;
+use tokio::sync::{Semaphore, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info, trace, warn};
 
 pub struct RaptorBoostService {
     pub controller: Arc<controller::RaptorBoostController>,
+    pub shutdown: watch::Receiver<bool>,
+    /// Caps how many uploads can be streaming at once; `None` for no cap.
+    pub transfer_slots: Option<Arc<Semaphore>>,
+    pub hooks: Hooks,
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl RaptorBoostService {
+    fn access<T>(&self, rpc: &'static str, request: &Request<T>) -> Access {
+        Access::new(self.access_log.clone(), rpc, request.remote_addr())
+    }
 }
 
 #[tonic::async_trait]
 impl RaptorBoost for RaptorBoostService {
     async fn get_version(
         &self,
-        _: Request<GetVersionRequest>,
+        request: Request<GetVersionRequest>,
     ) -> Result<Response<GetVersionResponse>, Status> {
-        Ok(Response::new(GetVersionResponse {
+        let access = self.access("GetVersion", &request);
+        // a unix socket has no peer address
+        let same_host = match (request.remote_addr(), request.local_addr()) {
+            (Some(remote), Some(local)) => remote.ip().is_loopback() || remote.ip() == local.ip(),
+            (remote, _) => remote.is_none(),
+        };
+        let store_dirs = match same_host {
+            true => self
+                .controller
+                .store_dirs()
+                .iter()
+                .filter_map(|dir| dir.canonicalize().ok())
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .collect(),
+            false => vec![],
+        };
+        access.finish(Ok(Response::new(GetVersionResponse {
             version: self.controller.get_version(),
-        }))
+            store_dirs,
+        })))
     }
 
     type UploadFilesStream =
@@ -40,179 +85,1158 @@ impl RaptorBoost for RaptorBoostService {
         &self,
         request: Request<Streaming<UploadFilesRequest>>,
     ) -> Result<Response<Self::UploadFilesStream>, Status> {
+        let access = self.access("UploadFiles", &request);
+        let peer = request.remote_addr();
         let stream = request.into_inner();
         let controller = self.controller.clone();
         let mut seen: HashSet<String> = HashSet::new();
 
-        let out = stream.map(move |req_result| -> Result<UploadFilesResponse, Status> {
-            let req = req_result?;
-            let mut states = Vec::with_capacity(req.sha256sums.len());
+        let out = stream.then(move |req_result| {
+            // checked in order here, so a repeat within the stream is dropped
+            // even if its first request is still being looked up
+            let req = req_result.map(|req| {
+                let hash_algo: HashAlgo = req.hash_algorithm().into();
+                let sha256sums: Vec<String> = req
+                    .sha256sums
+                    .into_iter()
+                    .filter(|s| seen.insert(s.clone()))
+                    .collect();
+                (hash_algo, sha256sums)
+            });
+            let controller = controller.clone();
+            async move {
+                let (hash_algo, sha256sums) = req?;
+                blocking(move || check_files(&controller, peer, hash_algo, sha256sums)).await?
+            }
+        });
+
+        Ok(Response::new(Box::pin(access.finish_stream(out))))
+    }
+
+    type SendFileDataStream =
+        Pin<Box<dyn Stream<Item = Result<SendFileDataResponse, Status>> + Send + 'static>>;
 
-            for sha256sum in req.sha256sums {
-                if !seen.insert(sha256sum.clone()) {
-                    continue;
+    async fn send_file_data(
+        &self,
+        request: Request<Streaming<FileData>>,
+    ) -> Result<Response<Self::SendFileDataStream>, Status> {
+        let mut access = self.access("SendFileData", &request);
+        let peer = request.remote_addr();
+        let slot = match &self.transfer_slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    return access.finish(Err(Status::resource_exhausted(
+                        "too many concurrent transfers, try again later",
+                    )));
                 }
-                match controller.check_file(&sha256sum) {
-                    Ok(controller::CheckFileResult::FileComplete) => states.push(FileState {
-                        sha256sum,
-                        state: FileStateResult::FilestateresultComplete.into(),
-                        offset: None,
-                    }),
-                    Ok(controller::CheckFileResult::FilePartialOffset(offset)) => {
-                        states.push(FileState {
-                            sha256sum,
-                            state: FileStateResult::FilestateresultNeedMoreData.into(),
-                            offset: Some(offset),
-                        })
-                    }
-                    Err(e) => {
-                        return Err(match e {
-                            RaptorBoostError::PathSanitization(msg) => {
-                                Status::invalid_argument(msg)
-                            }
-                            RaptorBoostError::OtherError(msg) => Status::internal(msg),
-                            RaptorBoostError::LockFailure => {
-                                Status::unavailable("couldn't lock!")
-                            }
-                            _ => Status::internal("unexpected error"),
-                        });
+            },
+            None => None,
+        };
+
+        let stream = request.into_inner();
+        let controller = self.controller.clone();
+        let shutdown = self.shutdown.clone();
+        let hooks = self.hooks.clone();
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            // held until this upload ends
+            let _slot = slot;
+            let received = receive_files(
+                &controller,
+                peer,
+                &shutdown,
+                &hooks,
+                &mut access,
+                stream,
+                &tx,
+            )
+            .await;
+            if let Err(status) = access.finish(received) {
+                let _ = tx.send(Err(status)).await;
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn assign_names(
+        &self,
+        request: Request<Streaming<AssignNamesRequest>>,
+    ) -> Result<Response<AssignNamesResponse>, Status> {
+        let access = self.access("AssignNames", &request);
+        let result = async {
+            let now = Local::now();
+            let mut stream = request.into_inner();
+
+            // the whole stream, merged into one request
+            let mut merged: Option<AssignNamesRequest> = None;
+            while let Some(msg) = stream.message().await? {
+                match &mut merged {
+                    None => merged = Some(msg),
+                    Some(merged) => {
+                        merged.sha256_to_filenames.extend(msg.sha256_to_filenames);
+                        merged.directories.extend(msg.directories);
+                        merged.symlinks.extend(msg.symlinks);
                     }
                 }
             }
 
-            Ok(UploadFilesResponse {
-                file_states: states,
+            let controller = self.controller.clone();
+            let (response, transfer_dir) =
+                blocking(move || assign_all(&controller, now, merged.unwrap_or_default()))
+                    .await??;
+
+            let transfer_name = transfer_dir
+                .strip_prefix(self.controller.get_transfers_dir())
+                .unwrap_or(&transfer_dir)
+                .to_string_lossy();
+            self.hooks.assigned(&transfer_name, &transfer_dir);
+
+            Ok::<_, Status>(Response::new(response))
+        }
+        .await;
+        access.finish(result)
+    }
+
+    type ListFilesStream =
+        Pin<Box<dyn Stream<Item = Result<ListFilesResponse, Status>> + Send + 'static>>;
+
+    async fn list_files(
+        &self,
+        request: Request<ListFilesRequest>,
+    ) -> Result<Response<Self::ListFilesStream>, Status> {
+        const BATCH: usize = 1000;
+
+        let access = self.access("ListFiles", &request);
+        let controller = self.controller.clone();
+        let files = blocking(move || controller.list_files())
+            .await
+            .and_then(|files| {
+                files.map_err(|e| match e {
+                    RaptorBoostError::OtherError(msg) => Status::internal(msg),
+                    _ => Status::internal("unexpected error"),
+                })
+            });
+        let files = match files {
+            Ok(files) => files,
+            Err(status) => return access.finish(Err(status)),
+        };
+
+        let batches: Vec<Result<ListFilesResponse, Status>> = files
+            .chunks(BATCH)
+            .map(|chunk| {
+                Ok(ListFilesResponse {
+                    files: chunk
+                        .iter()
+                        .map(|f| ListedFile {
+                            sha256sum: f.sha256sum.clone(),
+                            size: f.size,
+                            names: f.names.clone(),
+                        })
+                        .collect(),
+                })
             })
-        });
+            .collect();
 
-        Ok(Response::new(Box::pin(out)))
+        let batches = access.finish_stream(tokio_stream::iter(batches));
+        Ok(Response::new(Box::pin(batches)))
     }
 
-    async fn send_file_data(
+    type GetHistoryStream =
+        Pin<Box<dyn Stream<Item = Result<GetHistoryResponse, Status>> + Send + 'static>>;
+
+    async fn get_history(
         &self,
-        request: Request<Streaming<FileData>>,
-    ) -> Result<Response<SendFileDataResponse>, Status> {
-        let mut stream = request.into_inner();
-        let mut current: Option<RaptorBoostTransfer> = None;
-
-        while let Some(file_data) = stream.message().await? {
-            if file_data.first {
-                if current.is_some() {
-                    return Err(Status::invalid_argument(
-                        "unexpected 'first' packet before prior transfer completed",
-                    ));
-                }
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<Self::GetHistoryStream>, Status> {
+        const BATCH: usize = 1000;
+
+        let mut access = self.access("GetHistory", &request);
+        let request = request.into_inner();
+        if let Some(sha256sum) = &request.sha256sum {
+            access.set_sha256sum(sha256sum);
+        }
+        let controller = self.controller.clone();
+        let entries = blocking(move || controller.history(request.sha256sum.as_deref()))
+            .await
+            .and_then(|entries| {
+                entries.map_err(|e| match e {
+                    RaptorBoostError::OtherError(msg) => Status::internal(msg),
+                    _ => Status::internal("unexpected error"),
+                })
+            });
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(status) => return access.finish(Err(status)),
+        };
+
+        let batches: Vec<Result<GetHistoryResponse, Status>> = entries
+            .chunks(BATCH)
+            .map(|chunk| {
+                Ok(GetHistoryResponse {
+                    entries: chunk
+                        .iter()
+                        .map(|e| HistoryEntry {
+                            time: e.time,
+                            sha256sum: e.sha256sum.clone(),
+                            size: e.size,
+                            transfer: e.transfer.clone(),
+                            names: e.names.clone(),
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+
+        let batches = access.finish_stream(tokio_stream::iter(batches));
+        Ok(Response::new(Box::pin(batches)))
+    }
 
-                let sha256sum = file_data.sha256sum.as_deref().ok_or_else(|| {
-                    Status::invalid_argument("need sha256sum in first data packet")
+    async fn get_stats(
+        &self,
+        request: Request<GetStatsRequest>,
+    ) -> Result<Response<GetStatsResponse>, Status> {
+        let access = self.access("GetStats", &request);
+        let result = async {
+            let controller = self.controller.clone();
+            let stats = blocking(move || controller.stats())
+                .await?
+                .map_err(|e| match e {
+                    RaptorBoostError::OtherError(msg) => Status::internal(msg),
+                    _ => Status::internal("unexpected error"),
                 })?;
-                let force = file_data.force.unwrap_or(false);
 
-                current = Some(self.controller.start_transfer(sha256sum, force).map_err(
-                    |e| match e {
-                        RaptorBoostError::LockFailure => Status::unavailable("couldn't lock!"),
+            Ok::<_, Status>(Response::new(GetStatsResponse {
+                complete_free_bytes: stats.complete_free,
+                partial_free_bytes: stats.partial_free,
+                complete_files: stats.complete_files,
+                complete_bytes: stats.complete_bytes,
+                active_locks: stats.active_locks,
+            }))
+        }
+        .await;
+        access.finish(result)
+    }
+
+    async fn delete_files(
+        &self,
+        request: Request<Streaming<DeleteFilesRequest>>,
+    ) -> Result<Response<DeleteFilesResponse>, Status> {
+        let access = self.access("DeleteFiles", &request);
+        let result = async {
+            let mut stream = request.into_inner();
+
+            let mut dry_run = false;
+            let mut except = false;
+            let mut sha256sums: HashSet<String> = HashSet::new();
+            let mut first = true;
+
+            while let Some(msg) = stream.message().await? {
+                if first {
+                    dry_run = msg.dry_run.unwrap_or(false);
+                    except = msg.except.unwrap_or(false);
+                    first = false;
+                }
+                sha256sums.extend(msg.sha256sums);
+            }
+
+            let controller = self.controller.clone();
+            let (deleted, links_removed) =
+                blocking(move || controller.delete_files(&sha256sums, except, dry_run))
+                    .await?
+                    .map_err(|e| match e {
                         RaptorBoostError::PathSanitization(msg) => Status::invalid_argument(msg),
                         RaptorBoostError::OtherError(msg) => Status::internal(msg),
-                        RaptorBoostError::TransferAlreadyComplete => {
-                            Status::already_exists("already exists")
-                        }
-                        _ => Status::internal("unexpected error occurred"),
-                    },
-                )?);
+                        _ => Status::internal("unexpected error"),
+                    })?;
+
+            if !dry_run {
+                info!(
+                    "deleted {} files and {} links",
+                    deleted.len(),
+                    links_removed
+                );
             }
 
-            let transfer = current
-                .as_mut()
-                .ok_or_else(|| Status::invalid_argument("first packet not marked as first"))?;
+            Ok::<_, Status>(Response::new(DeleteFilesResponse {
+                sha256sums: deleted,
+                links_removed,
+            }))
+        }
+        .await;
+        access.finish(result)
+    }
+}
 
-            transfer.write_all(&file_data.data)?;
+/// Look up how much of each file the store already has.
+fn check_files(
+    controller: &controller::RaptorBoostController,
+    peer: Option<SocketAddr>,
+    hash_algo: HashAlgo,
+    sha256sums: Vec<String>,
+) -> Result<UploadFilesResponse, Status> {
+    let mut states = Vec::with_capacity(sha256sums.len());
 
-            if file_data.last {
-                current
-                    .take()
-                    .unwrap()
-                    .complete()
-                    .map_err(|e| Status::internal(format!("complete failed: {}", e)))?;
+    for sha256sum in sha256sums {
+        match controller.check_file(&sha256sum, peer, hash_algo) {
+            Ok(controller::CheckFileResult::FileComplete) => states.push(FileState {
+                sha256sum,
+                state: FileStateResult::FilestateresultComplete.into(),
+                offset: None,
+            }),
+            Ok(controller::CheckFileResult::FilePartialOffset(offset)) => states.push(FileState {
+                sha256sum,
+                state: FileStateResult::FilestateresultNeedMoreData.into(),
+                offset: Some(offset),
+            }),
+            Ok(controller::CheckFileResult::FileNew) => states.push(FileState {
+                sha256sum,
+                state: FileStateResult::FilestateresultNeedMoreData.into(),
+                offset: None,
+            }),
+            Err(e) => {
+                return Err(match e {
+                    RaptorBoostError::PathSanitization(msg) => Status::invalid_argument(msg),
+                    RaptorBoostError::OtherError(msg) => Status::internal(msg),
+                    RaptorBoostError::LockFailure => Status::unavailable("couldn't lock!"),
+                    e @ RaptorBoostError::HashAlgoMismatch { .. } => {
+                        Status::failed_precondition(e.to_string())
+                    }
+                    _ => Status::internal("unexpected error"),
+                });
             }
         }
+    }
 
-        Ok(Response::new(SendFileDataResponse {
-            status: SendFileDataStatus::SendfiledatastatusComplete.into(),
-        }))
+    Ok(UploadFilesResponse {
+        file_states: states,
+    })
+}
+
+fn file_result(
+    status: SendFileDataStatus,
+    sha256sum: &str,
+    offset: Option<u64>,
+    message: Option<String>,
+) -> SendFileDataResponse {
+    SendFileDataResponse {
+        status: status.into(),
+        offset,
+        sha256sum: Some(sha256sum.to_string()),
+        message,
     }
+}
 
-    async fn assign_names(
-        &self,
-        request: Request<Streaming<AssignNamesRequest>>,
-    ) -> Result<Response<AssignNamesResponse>, Status> {
-        let now = Local::now();
-        let mut stream = request.into_inner();
+/// Run filesystem work on tokio's blocking pool, so a slow disk doesn't hold
+/// up the other connections sharing the runtime's workers.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(format!("blocking task failed: {}", e)))
+}
 
-        let mut header_name: Option<String> = None;
-        let mut header_force: bool = false;
-        let mut all_sha256_to_filenames: Vec<Sha256Filenames> = Vec::new();
-        let mut first = true;
+// how often the client hears how far re-hashing a partial has got
+const HASH_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
-        while let Some(msg) = stream.message().await? {
-            if first {
-                header_name = msg.name;
-                header_force = msg.force.unwrap_or(false);
-                first = false;
-            }
-            all_sha256_to_filenames.extend(msg.sha256_to_filenames);
+// how many chunks can be waiting for a file's writer
+const WRITE_QUEUE: usize = 64;
+
+// hands the transfer back once the writer's queue is closed, or on the first
+// failed write
+type WriterHandle = JoinHandle<(RaptorBoostTransfer, io::Result<()>)>;
+
+/// Writes a file's chunks on the blocking pool as they're queued, so the
+/// upload can keep reading from the network while the disk catches up. The
+/// writer is only started by the first `write`, so a file that fits in one
+/// packet never needs one.
+struct FileWriter {
+    transfer: Option<RaptorBoostTransfer>,
+    writer: Option<(mpsc::Sender<Bytes>, WriterHandle)>,
+}
+
+impl FileWriter {
+    fn new(transfer: RaptorBoostTransfer) -> Self {
+        FileWriter {
+            transfer: Some(transfer),
+            writer: None,
         }
+    }
 
-        let transfer_dir = scoped_join(
-            self.controller.get_transfers_dir(),
-            match header_name {
-                None => format!("{}", now.format("%Y-%m-%d_%H:%M:%S")),
-                Some(ref name) => name.clone(),
-            },
-        )?;
+    /// Queue `data`. False if the writer has stopped on an error.
+    async fn write(&mut self, data: Bytes) -> bool {
+        if let Some(mut transfer) = self.transfer.take() {
+            let (tx, mut rx) = mpsc::channel::<Bytes>(WRITE_QUEUE);
+            let handle = tokio::task::spawn_blocking(move || {
+                while let Some(data) = rx.blocking_recv() {
+                    if let Err(e) = transfer.write_all(&data) {
+                        return (transfer, Err(e));
+                    }
+                }
+                (transfer, Ok(()))
+            });
+            self.writer = Some((tx, handle));
+        }
+        let (tx, _) = self.writer.as_ref().unwrap();
+        tx.send(data).await.is_ok()
+    }
+
+    /// Wait for everything queued to be written and get the transfer back.
+    async fn finish(self) -> Result<RaptorBoostTransfer, Status> {
+        let Some((tx, handle)) = self.writer else {
+            return Ok(self.transfer.unwrap());
+        };
+        drop(tx);
+        let (transfer, written) = handle
+            .await
+            .map_err(|e| Status::internal(format!("writer failed: {}", e)))?;
+        written?;
+        Ok(transfer)
+    }
+}
+
+/// Write each file on `stream` to the store, sending a result per file to
+/// `tx` as it completes. Results that end the upload are sent last.
+async fn receive_files(
+    controller: &Arc<controller::RaptorBoostController>,
+    peer: Option<SocketAddr>,
+    shutdown: &watch::Receiver<bool>,
+    hooks: &Hooks,
+    access: &mut Access,
+    mut stream: Streaming<FileData>,
+    tx: &mpsc::Sender<Result<SendFileDataResponse, Status>>,
+) -> Result<(), Status> {
+    let mut current: Option<FileWriter> = None;
+    // the access log line for the current file
+    let mut file_access: Option<Access> = None;
+    // the rest of a file that turned out to be complete already
+    let mut skipping = false;
 
-        if header_force {
-            let _ = remove_dir_all(&transfer_dir);
+    while let Some(file_data) = stream.message().await? {
+        access.add_bytes(file_data.data.len() as u64);
+        if skipping && !file_data.first {
+            skipping = !file_data.last;
+            continue;
         }
 
-        if let Err(e) = create_dir(&transfer_dir) {
-            return Err(Status::invalid_argument(format!(
-                "couldn't create transfer directory: {}",
-                e
-            )));
+        if file_data.first {
+            if *shutdown.borrow() {
+                return Err(Status::unavailable("server is shutting down"));
+            }
+
+            if current.is_some() || skipping {
+                return Err(Status::invalid_argument(
+                    "unexpected 'first' packet before prior transfer completed",
+                ));
+            }
+
+            let sha256sum = file_data
+                .sha256sum
+                .as_deref()
+                .ok_or_else(|| Status::invalid_argument("need sha256sum in first data packet"))?;
+            let force = file_data.force.unwrap_or(false);
+            let hash_algo = file_data.hash_algorithm().into();
+            debug!("starting transfer of {}", sha256sum);
+
+            // may wait on another upload of the same file for its lock, or
+            // re-hash a partial to resume it
+            let hashed = Arc::new(AtomicU64::new(0));
+            let mut started = tokio::task::spawn_blocking({
+                let controller = controller.clone();
+                let sha256sum = sha256sum.to_string();
+                let total_size = file_data.total_size;
+                let offset = file_data.offset;
+                let hashed = hashed.clone();
+                move || {
+                    controller.start_transfer(
+                        &sha256sum,
+                        peer,
+                        force,
+                        hash_algo,
+                        total_size,
+                        offset,
+                        Some(&hashed),
+                    )
+                }
+            });
+            let mut ticks = tokio::time::interval_at(
+                Instant::now() + HASH_PROGRESS_INTERVAL,
+                HASH_PROGRESS_INTERVAL,
+            );
+            let transfer = loop {
+                tokio::select! {
+                    transfer = &mut started => break transfer,
+                    _ = ticks.tick() => {
+                        let hashed = hashed.load(Ordering::Relaxed);
+                        if hashed == 0 {
+                            continue;
+                        }
+                        // tells the client the upload isn't stalled while
+                        // nothing is being read from it
+                        let _ = tx
+                            .send(Ok(file_result(
+                                SendFileDataStatus::SendfiledatastatusHashing,
+                                sha256sum,
+                                Some(hashed),
+                                None,
+                            )))
+                            .await;
+                    }
+                }
+            }
+            .map_err(|e| Status::internal(format!("start_transfer panicked: {}", e)))?;
+            if let Err(RaptorBoostError::OffsetMismatch { expected, actual }) = transfer {
+                debug!(
+                    "{}: client resumed from {}, partial is at {}",
+                    sha256sum, expected, actual
+                );
+                access.file(sha256sum).set_status("OffsetMismatch");
+                let _ = tx
+                    .send(Ok(file_result(
+                        SendFileDataStatus::SendfiledatastatusErrorOffset,
+                        sha256sum,
+                        Some(actual),
+                        None,
+                    )))
+                    .await;
+                return Ok(());
+            }
+            // most likely finished by a concurrent upload of the same file
+            // that we waited on
+            if let Err(RaptorBoostError::TransferAlreadyComplete) = transfer {
+                debug!("{} was completed by another upload", sha256sum);
+                access.file(sha256sum).set_status("AlreadyComplete");
+                if tx
+                    .send(Ok(file_result(
+                        SendFileDataStatus::SendfiledatastatusComplete,
+                        sha256sum,
+                        None,
+                        None,
+                    )))
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                skipping = !file_data.last;
+                continue;
+            }
+
+            let transfer = transfer.map_err(|e| match e {
+                RaptorBoostError::LockFailure => Status::unavailable("couldn't lock!"),
+                RaptorBoostError::PathSanitization(msg) => Status::invalid_argument(msg),
+                RaptorBoostError::OtherError(msg) => Status::internal(msg),
+                RaptorBoostError::TransferAlreadyComplete => {
+                    Status::already_exists("already exists")
+                }
+                e @ RaptorBoostError::HashAlgoMismatch { .. } => {
+                    Status::failed_precondition(e.to_string())
+                }
+                e @ (RaptorBoostError::InsufficientSpace { .. }
+                | RaptorBoostError::PartialQuotaExceeded { .. }) => {
+                    Status::resource_exhausted(e.to_string())
+                }
+                _ => Status::internal("unexpected error occurred"),
+            })?;
+            current = Some(FileWriter::new(transfer));
+            file_access = Some(access.file(sha256sum));
         }
 
-        let complete_dir = self.controller.get_complete_dir();
+        let writer = current
+            .as_mut()
+            .ok_or_else(|| Status::invalid_argument("first packet not marked as first"))?;
 
-        for sha256tonames in all_sha256_to_filenames {
-            for name in sha256tonames.names {
-                let mut path = Path::new(&name);
+        // drop a corrupted chunk before it reaches the partial, so the
+        // client can resume from right here instead of starting over
+        if file_data
+            .crc32
+            .is_some_and(|crc| crc != crc32fast::hash(&file_data.data))
+        {
+            let transfer = current.take().unwrap().finish().await?;
+            if let Some(mut file_access) = file_access.take() {
+                file_access.set_status("ChunkCorrupted");
+            }
+            let _ = tx
+                .send(Ok(file_result(
+                    SendFileDataStatus::SendfiledatastatusErrorChunk,
+                    transfer.sha256sum(),
+                    Some(transfer.offset()),
+                    Some("chunk failed its crc32 check".to_string()),
+                )))
+                .await;
+            return Ok(());
+        }
 
-                if path.has_root() {
-                    path = path.strip_prefix("/").unwrap();
+        if let Some(file_access) = &mut file_access {
+            file_access.add_bytes(file_data.data.len() as u64);
+        }
+        if !file_data.last {
+            if !writer.write(file_data.data).await {
+                // the writer only stops early on a failed write, which
+                // finish() returns
+                let stopped = current.take().unwrap().finish().await;
+                return Err(stopped
+                    .err()
+                    .unwrap_or_else(|| Status::internal("writer stopped")));
+            }
+        } else {
+            // the last chunk is written along with completing the file, which
+            // saves a trip to the blocking pool for files sent in one packet
+            let mut transfer = current.take().unwrap().finish().await?;
+            let sha256sum = transfer.sha256sum().to_string();
+            let data = file_data.data;
+            let completed = blocking({
+                let controller = controller.clone();
+                let sha256sum = sha256sum.clone();
+                move || -> io::Result<_> {
+                    transfer.write_all(&data)?;
+                    let size = transfer.offset();
+                    let completed = transfer.complete();
+                    if completed.is_ok()
+                        && let Err(e) = controller.record_received(&sha256sum, size)
+                    {
+                        warn!("couldn't record {} in history: {}", sha256sum, e);
+                    }
+                    Ok(completed)
+                }
+            })
+            .await??;
+            let (status, message) = match completed {
+                Ok(()) => {
+                    info!("received {}", sha256sum);
+                    match controller.complete_path(&sha256sum) {
+                        Ok(path) => hooks.completed(&sha256sum, &path),
+                        Err(e) => warn!("couldn't run on-complete hook for {}: {}", sha256sum, e),
+                    }
+                    (SendFileDataStatus::SendfiledatastatusComplete, None)
                 }
+                Err(e) => {
+                    warn!("couldn't complete {}: {}", sha256sum, e);
+                    let status = match e {
+                        RaptorBoostError::ChecksumMismatch => {
+                            SendFileDataStatus::SendfiledatastatusErrorChecksum
+                        }
+                        RaptorBoostError::SizeMismatch { .. } => {
+                            SendFileDataStatus::SendfiledatastatusErrorSize
+                        }
+                        e => return Err(Status::internal(format!("complete failed: {}", e))),
+                    };
+                    (status, Some(e.to_string()))
+                }
+            };
+            if let Some(mut file_access) = file_access.take() {
+                file_access.set_status(match status {
+                    SendFileDataStatus::SendfiledatastatusComplete => "Ok",
+                    SendFileDataStatus::SendfiledatastatusErrorChecksum => "ChecksumMismatch",
+                    _ => "SizeMismatch",
+                });
+            }
+            // the client's gone, so there's nobody left to send to
+            if tx
+                .send(Ok(file_result(status, &sha256sum, None, message)))
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    // the partial stays on disk for a resume; the lock goes with the
+    // transfer
+    if let Some(writer) = current {
+        let transfer = writer.finish().await?;
+        debug!(
+            "stream ended partway through {} at {}",
+            transfer.sha256sum(),
+            transfer.offset()
+        );
+        let _ = tx
+            .send(Ok(file_result(
+                SendFileDataStatus::SendfiledatastatusErrorIncomplete,
+                transfer.sha256sum(),
+                Some(transfer.offset()),
+                None,
+            )))
+            .await;
+    }
+
+    Ok(())
+}
+
+/// Turn a client-supplied name into a relative path that stays inside the
+/// transfer dir. A root/drive prefix and leading `..` are dropped, since
+/// clients send names relative to wherever they were run from; any other
+/// `..` that would climb out of the name is rejected. The result may be empty.
+fn sanitize_name(name: &str) -> Result<PathBuf, String> {
+    if name.contains('\0') {
+        return Err("invalid name: contains a NUL byte".to_string());
+    }
 
-                while path.starts_with("..") {
-                    path = path.strip_prefix("..").unwrap();
+    let mut components = Path::new(name).components().peekable();
+    while let Some(
+        Component::Prefix(_) | Component::RootDir | Component::CurDir | Component::ParentDir,
+    ) = components.peek()
+    {
+        components.next();
+    }
+
+    let mut path = PathBuf::new();
+    for c in components {
+        match c {
+            Component::Normal(p) => path.push(p),
+            Component::CurDir => {}
+            Component::ParentDir if path.pop() => {}
+            _ => return Err("invalid name: escapes the transfer directory".to_string()),
+        }
+    }
+
+    Ok(path)
+}
+
+/// Create the transfer directory and everything the merged `request` names
+/// in it, and with `manifest` a manifest of it next to it, returning the
+/// directory. Only fails if the
+/// directory itself can't be created; each name gets its own status.
+fn assign_all(
+    controller: &controller::RaptorBoostController,
+    now: DateTime<Local>,
+    request: AssignNamesRequest,
+) -> Result<(AssignNamesResponse, PathBuf), Status> {
+    let AssignNamesRequest {
+        name: header_name,
+        force,
+        sha256_to_filenames: all_sha256_to_filenames,
+        directories,
+        manifest,
+        symlinks,
+    } = request;
+    let header_force = force.unwrap_or(false);
+    let manifest = manifest.unwrap_or(false);
+    let transfer_dir = match header_name {
+        Some(ref name) => {
+            let transfer_dir = scoped_join(controller.get_transfers_dir(), name)?;
+            if header_force {
+                let _ = remove_dir_all(&transfer_dir);
+            }
+            create_dir(&transfer_dir).map(|()| transfer_dir)
+        }
+        // another unnamed transfer may have finished in the same second,
+        // and forcing would throw its names away
+        None => create_unique_dir(
+            controller.get_transfers_dir(),
+            &now.format("%Y-%m-%d_%H:%M:%S").to_string(),
+        ),
+    }
+    .map_err(|e| Status::invalid_argument(format!("couldn't create transfer directory: {}", e)))?;
+
+    let link_mode = controller.link_mode();
+    let mut statuses = Vec::new();
+    let mut manifest_entries = Vec::new();
+    let mut warned_fallback = false;
+
+    for name in directories {
+        if let Err(e) = create_name_dir(&transfer_dir, &name) {
+            statuses.push(NameStatus {
+                name,
+                status: AssignNameStatus::AssignnamestatusError.into(),
+                message: Some(e),
+                link_mode: 0,
+            });
+        }
+    }
+
+    let transfer_name = transfer_dir
+        .strip_prefix(controller.get_transfers_dir())
+        .unwrap_or(&transfer_dir)
+        .to_string_lossy()
+        .into_owned();
+
+    for sha256tonames in all_sha256_to_filenames {
+        let mut assigned = Vec::new();
+        for (i, name) in sha256tonames.names.into_iter().enumerate() {
+            let assigned_name = controller
+                .complete_path(&sha256tonames.sha256sum)
+                .map_err(|e| e.to_string())
+                .and_then(|complete_path| {
+                    assign_name(
+                        &transfer_dir,
+                        &complete_path,
+                        &name,
+                        sha256tonames.attributes.get(i),
+                        header_force,
+                        link_mode,
+                    )
+                });
+            let status = match assigned_name {
+                Ok((status, used_mode, warning)) => {
+                    if let Some(used_mode) = used_mode
+                        && used_mode != link_mode
+                        && !warned_fallback
+                    {
+                        warn!(
+                            "couldn't {} into {}, falling back to {}",
+                            link_mode,
+                            transfer_dir.display(),
+                            used_mode
+                        );
+                        warned_fallback = true;
+                    }
+                    NameStatus {
+                        name,
+                        status: status.into(),
+                        message: warning,
+                        link_mode: used_mode
+                            .map(|m| proto::LinkMode::from(m).into())
+                            .unwrap_or(0),
+                    }
                 }
+                Err(e) => NameStatus {
+                    name,
+                    status: AssignNameStatus::AssignnamestatusError.into(),
+                    message: Some(e),
+                    link_mode: 0,
+                },
+            };
+            trace!("{}: {:?}", status.name, status.status());
+            if status.status() == AssignNameStatus::AssignnamestatusSuccess {
+                assigned.push(status.name.clone());
+            }
+            statuses.push(status);
+        }
+        if manifest {
+            manifest_entries.extend(assigned.iter().filter_map(|name| {
+                let path = sanitize_name(name).ok()?;
+                Some((
+                    path.to_string_lossy().into_owned(),
+                    sha256tonames.sha256sum.clone(),
+                ))
+            }));
+        }
+        if !assigned.is_empty()
+            && let Err(e) =
+                controller.record_named(&transfer_name, &sha256tonames.sha256sum, assigned)
+        {
+            warn!(
+                "couldn't record names of {} in history: {}",
+                sha256tonames.sha256sum, e
+            );
+        }
+    }
 
-                let dir = path.parent().unwrap();
-                let file = path.file_name().unwrap();
+    // last, so nothing else is created through them
+    for symlink in symlinks {
+        let status = match create_symlink(&transfer_dir, &symlink, header_force) {
+            Ok(status) => status,
+            Err(e) => {
+                statuses.push(NameStatus {
+                    name: symlink.name,
+                    status: AssignNameStatus::AssignnamestatusError.into(),
+                    message: Some(e),
+                    link_mode: 0,
+                });
+                continue;
+            }
+        };
+        trace!("{} -> {}: {:?}", symlink.name, symlink.target, status);
+        statuses.push(NameStatus {
+            name: symlink.name,
+            status: status.into(),
+            message: None,
+            link_mode: 0,
+        });
+    }
 
-                let _ =
-                    create_dir_all(transfer_dir.join(scoped_resolve(&transfer_dir, dir).unwrap()));
+    info!(
+        "assigned {} names in {}",
+        statuses.len(),
+        transfer_dir.display()
+    );
 
-                let safe_target_sha256sum = complete_dir
-                    .join(scoped_resolve(complete_dir, &sha256tonames.sha256sum).unwrap());
+    let manifest = match manifest {
+        true => match write_transfer_manifest(controller, &transfer_dir, &manifest_entries) {
+            Ok(path) => path
+                .strip_prefix(controller.get_transfers_dir())
+                .ok()
+                .map(|p| p.to_string_lossy().into_owned()),
+            Err(e) => {
+                warn!(
+                    "couldn't write manifest of {}: {}",
+                    transfer_dir.display(),
+                    e
+                );
+                None
+            }
+        },
+        false => None,
+    };
+
+    Ok((AssignNamesResponse { statuses, manifest }, transfer_dir))
+}
 
-                let safe_target_link_dir =
-                    transfer_dir.join(scoped_resolve(&transfer_dir, dir).unwrap());
-                let safe_target_link =
-                    safe_target_link_dir.join(scoped_resolve(&safe_target_link_dir, file).unwrap());
+/// Write the manifest of a transfer next to its dir, as `<dir>.<hash>`, so
+/// it can be checked from inside the dir with e.g. `sha256sum -c`.
+fn write_transfer_manifest(
+    controller: &controller::RaptorBoostController,
+    transfer_dir: &Path,
+    entries: &[(String, String)],
+) -> io::Result<PathBuf> {
+    let mut file_name = transfer_dir.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", controller.hash_algo()));
+    let path = transfer_dir.with_file_name(file_name);
+    let f = io::BufWriter::new(File::create(&path)?);
+    write_manifest(f, ManifestFormat::Text, controller.hash_algo(), entries)?;
+    Ok(path)
+}
 
-                symlink(safe_target_sha256sum, safe_target_link).unwrap();
+/// Create `name` under `parent`, or the first of `name_2`, `name_3`, ... that
+/// doesn't exist yet.
+fn create_unique_dir(parent: &Path, name: &str) -> io::Result<PathBuf> {
+    let mut dir = parent.join(name);
+    let mut n = 1;
+    loop {
+        match create_dir(&dir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                n += 1;
+                dir = parent.join(format!("{}_{}", name, n));
             }
+            r => return r.map(|()| dir),
+        }
+    }
+}
+
+/// Create an (empty) directory named by the client under the transfer dir.
+fn create_name_dir(transfer_dir: &Path, name: &str) -> Result<(), String> {
+    // a name like `.` sanitizes to the transfer dir itself
+    let dir = scoped_join(transfer_dir, sanitize_name(name)?).map_err(|e| e.to_string())?;
+    create_dir_all(&dir).map_err(|e| format!("couldn't create directory: {}", e))
+}
+
+/// Create a symlink the client preserved from its tree, pointing where it
+/// did, as long as that's inside the transfer dir.
+fn create_symlink(
+    transfer_dir: &Path,
+    symlink: &proto::Symlink,
+    force: bool,
+) -> Result<AssignNameStatus, String> {
+    let path = sanitize_name(&symlink.name)?;
+    let (Some(dir), Some(file)) = (path.parent(), path.file_name()) else {
+        return Err("invalid name".to_string());
+    };
+
+    let link_dir = scoped_join(transfer_dir, dir).map_err(|e| e.to_string())?;
+    create_dir_all(&link_dir).map_err(|e| format!("couldn't create directory: {}", e))?;
+    // how deep the link really is, once any symlinks on the way there have
+    // been followed, is how far its target can climb
+    let depth = link_dir
+        .canonicalize()
+        .and_then(|dir| {
+            Ok(dir
+                .strip_prefix(transfer_dir.canonicalize()?)
+                .map(Path::to_path_buf))
+        })
+        .map_err(|e| e.to_string())?
+        .map_err(|_| "invalid name: escapes the transfer directory".to_string())?
+        .components()
+        .count();
+    check_symlink_target(&symlink.target, depth)?;
+
+    // joined as it is: resolving it would follow a link already there
+    let link = link_dir.join(file);
+    match symlink_file(Path::new(&symlink.target), &link) {
+        Ok(()) => Ok(AssignNameStatus::AssignnamestatusSuccess),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && force => {
+            remove_file(&link).map_err(|e| format!("couldn't replace: {}", e))?;
+            symlink_file(Path::new(&symlink.target), &link)
+                .map_err(|e| format!("couldn't create symlink: {}", e))?;
+            Ok(AssignNameStatus::AssignnamestatusSuccess)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            Ok(AssignNameStatus::AssignnamestatusAlreadyExists)
+        }
+        Err(e) => Err(format!("couldn't create symlink: {}", e)),
+    }
+}
+
+/// Check a symlink `depth` directories into the transfer dir can't point
+/// out of it. Only leading `..`s may climb, since one after a name would
+/// climb from wherever that name leads if it's a symlink itself. Every other
+/// symlink in the transfer dir is held to the same rule, so following the
+/// names after them can't leave it either.
+fn check_symlink_target(target: &str, depth: usize) -> Result<(), String> {
+    if target.is_empty() || target.contains('\0') {
+        return Err("invalid symlink target".to_string());
+    }
+    let escapes = || Err("symlink target escapes the transfer directory".to_string());
+
+    let mut climbed = 0;
+    let mut climbing = true;
+    for c in Path::new(target).components() {
+        match c {
+            Component::ParentDir if climbing => climbed += 1,
+            Component::CurDir => {}
+            Component::Normal(_) => climbing = false,
+            _ => return escapes(),
+        }
+    }
+    match climbed > depth {
+        true => escapes(),
+        false => Ok(()),
+    }
+}
+
+fn assign_name(
+    transfer_dir: &Path,
+    complete_path: &Path,
+    name: &str,
+    attributes: Option<&FileAttributes>,
+    force: bool,
+    link_mode: LinkMode,
+) -> Result<(AssignNameStatus, Option<LinkMode>, Option<String>), String> {
+    let path = sanitize_name(name)?;
+    let (Some(dir), Some(file)) = (path.parent(), path.file_name()) else {
+        return Err("invalid name".to_string());
+    };
+
+    let safe_target_link_dir = scoped_join(transfer_dir, dir).map_err(|e| e.to_string())?;
+    create_dir_all(&safe_target_link_dir)
+        .map_err(|e| format!("couldn't create directory: {}", e))?;
+
+    let safe_target_link = scoped_join(&safe_target_link_dir, file).map_err(|e| e.to_string())?;
+
+    let used_mode = match link_file(complete_path, &safe_target_link, link_mode) {
+        Ok(used_mode) => used_mode,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && force => {
+            remove_file(&safe_target_link).map_err(|e| format!("couldn't replace: {}", e))?;
+            link_file(complete_path, &safe_target_link, link_mode)
+                .map_err(|e| format!("couldn't link: {}", e))?
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return Ok((AssignNameStatus::AssignnamestatusAlreadyExists, None, None));
         }
+        Err(e) => return Err(format!("couldn't link: {}", e)),
+    };
+
+    // the name exists at this point, so failing to apply attributes is only
+    // worth a warning
+    let warning = attributes
+        .and_then(|attrs| apply_attributes(&safe_target_link, attrs, used_mode).err())
+        .map(|e| format!("couldn't set attributes: {}", e));
+
+    Ok((
+        AssignNameStatus::AssignnamestatusSuccess,
+        Some(used_mode),
+        warning,
+    ))
+}
+
+/// Apply a name's mode and mtime. The mode is only applied to a copy: on a
+/// symlink or hardlink it would land on the complete file, which every name
+/// with the same content shares and the server itself has to keep reading.
+/// The mtime is set on the link itself.
+fn apply_attributes(
+    link: &Path,
+    attributes: &FileAttributes,
+    link_mode: LinkMode,
+) -> io::Result<()> {
+    if let Some(mode) = attributes.mode
+        && link_mode == LinkMode::Copy
+    {
+        // never let a client set setuid/setgid/sticky bits
+        set_mode(link, mode & 0o777)?;
+    }
+
+    if let Some(mtime) = attributes.mtime {
+        let mtime = FileTime::from_unix_time(mtime, attributes.mtime_nsec.unwrap_or(0));
+        filetime::set_symlink_file_times(link, mtime, mtime)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes_are_only_applied_to_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let complete_path = dir.path().join("complete");
+        fs::write(&complete_path, b"content").unwrap();
+        set_mode(&complete_path, 0o644).unwrap();
+
+        let assign = |transfer: &str, mode: u32, link_mode: LinkMode| {
+            let transfer_dir = dir.path().join(transfer);
+            fs::create_dir(&transfer_dir).unwrap();
+            let attributes = FileAttributes {
+                mode: Some(mode),
+                ..Default::default()
+            };
+            let (status, used_mode, warning) = assign_name(
+                &transfer_dir,
+                &complete_path,
+                "name",
+                Some(&attributes),
+                false,
+                link_mode,
+            )
+            .unwrap();
+            assert_eq!(status, AssignNameStatus::AssignnamestatusSuccess);
+            assert_eq!(used_mode, Some(link_mode));
+            assert_eq!(warning, None);
+            transfer_dir.join("name")
+        };
+
+        // the same content named twice with modes that would lock the
+        // server out of its own file
+        assign("symlinked", 0o000, LinkMode::Symlink);
+        assign("hardlinked", 0o200, LinkMode::Hardlink);
+        assert_eq!(mode(&complete_path), 0o644);
+        assert_eq!(fs::read(&complete_path).unwrap(), b"content");
+
+        let copy = assign("copied", 0o600, LinkMode::Copy);
+        assert_eq!(mode(&copy), 0o600);
+        assert_eq!(mode(&complete_path), 0o644);
+    }
+
+    #[test]
+    fn sanitize_name_rejects_escapes() {
+        assert!(sanitize_name("a/../../b").is_err());
+        assert!(sanitize_name("a/b/../../../c").is_err());
+    }
+
+    #[test]
+    fn sanitize_name_rejects_nul() {
+        assert!(sanitize_name("a\0b").is_err());
+    }
+
+    #[test]
+    fn sanitize_name_strips_leading_root_and_parents() {
+        assert_eq!(sanitize_name("/a/b").unwrap(), PathBuf::from("a/b"));
+        assert_eq!(sanitize_name("../../a").unwrap(), PathBuf::from("a"));
+        assert_eq!(
+            sanitize_name("/../a/./b/../c").unwrap(),
+            PathBuf::from("a/c")
+        );
+    }
 
-        Ok(Response::new(AssignNamesResponse { statuses: vec![] }))
+    #[test]
+    fn sanitize_name_of_dot_is_empty() {
+        assert_eq!(sanitize_name(".").unwrap(), PathBuf::new());
+        assert_eq!(sanitize_name("a/..").unwrap(), PathBuf::new());
     }
 }
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+/// Parse a duration such as `30`, `30s`, `500ms`, `5m`, `2h` or `7d`. A bare
+/// number is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let num: u64 = num
+        .parse()
+        .map_err(|e| format!("invalid duration '{}': {}", s, e))?;
+
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(num)),
+        "" | "s" => Some(num),
+        "m" => num.checked_mul(60),
+        "h" => num.checked_mul(60 * 60),
+        "d" => num.checked_mul(24 * 60 * 60),
+        _ => return Err(format!("invalid duration unit '{}' in '{}'", unit, s)),
+    };
+
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", s))
+}
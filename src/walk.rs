@@ -0,0 +1,252 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use tracing::warn;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::platform::device;
+
+/// Decides which entries of a directory walk are skipped. Excluded
+/// directories are pruned during the walk rather than filtered afterwards.
+pub struct WalkFilter {
+    excludes: GlobSet,
+    use_gitignore: bool,
+    follow_symlinks: bool,
+    preserve_symlinks: bool,
+    max_depth: Option<usize>,
+    one_file_system: bool,
+    store_dirs: Vec<PathBuf>,
+}
+
+impl WalkFilter {
+    pub fn new(excludes: &[String], use_gitignore: bool) -> Result<WalkFilter, String> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in excludes {
+            let glob = Glob::new(pattern)
+                .map_err(|e| format!("invalid exclude pattern '{}': {}", pattern, e))?;
+            builder.add(glob);
+        }
+        let excludes = builder
+            .build()
+            .map_err(|e| format!("invalid exclude patterns: {}", e))?;
+
+        Ok(WalkFilter {
+            excludes,
+            use_gitignore,
+            follow_symlinks: false,
+            preserve_symlinks: false,
+            max_depth: None,
+            one_file_system: false,
+            store_dirs: Vec::new(),
+        })
+    }
+
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Pass symlinks to the caller as they are, rather than skipping them.
+    pub fn with_preserve_symlinks(mut self, preserve_symlinks: bool) -> Self {
+        self.preserve_symlinks = preserve_symlinks;
+        self
+    }
+
+    /// Descend at most `max_depth` directories below the root. Directories
+    /// at the limit are still visited, and end up empty.
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Don't descend into directories on another filesystem than the root,
+    /// where the platform can tell. Each one met is skipped with a warning
+    /// and ends up empty, like the mount point it usually is.
+    pub fn with_one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Never descend into the directories a server on this host keeps its
+    /// store in, given as canonical paths, so the store isn't sent back into
+    /// itself. Each one met is skipped with a warning.
+    pub fn with_store_dirs(mut self, store_dirs: Vec<PathBuf>) -> Self {
+        self.store_dirs = store_dirs;
+        self
+    }
+
+    /// The store directory `path` is in, if any.
+    pub fn store_dir(&self, path: &Path) -> Option<&Path> {
+        if self.store_dirs.is_empty() {
+            return None;
+        }
+        let path = path.canonicalize().ok()?;
+        self.store_dirs
+            .iter()
+            .find(|dir| path.starts_with(dir))
+            .map(PathBuf::as_path)
+    }
+
+    /// Patterns match either the entry's name (`node_modules`) or its whole
+    /// path (`src/**/*.o`).
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.excludes.is_match(path) || path.file_name().is_some_and(|n| self.excludes.is_match(n))
+    }
+
+    /// Walk `root`, calling `f` for every regular file that isn't excluded.
+    /// Symlinks are skipped unless following or preserving them was asked
+    /// for. Each
+    /// directory's entries are visited in name order.
+    pub fn walk(&self, root: &str, f: impl FnMut(DirEntry)) {
+        self.walk_with_empty_dirs(root, f, |_| {}, |_, _| {});
+    }
+
+    /// Like `walk`, but also calls `on_empty_dir` for every directory that
+    /// ends up with nothing in it: no files and no subdirectories once
+    /// exclusions are applied, and `on_error` for every path that couldn't
+    /// be read, which is skipped.
+    pub fn walk_with_empty_dirs(
+        &self,
+        root: &str,
+        mut f: impl FnMut(DirEntry),
+        mut on_empty_dir: impl FnMut(PathBuf),
+        mut on_error: impl FnMut(PathBuf, io::Error),
+    ) {
+        // .gitignore matchers of the directories we're currently inside,
+        // tagged with their depth so they can be dropped on the way back out
+        let mut gitignores: Vec<(usize, Gitignore)> = Vec::new();
+
+        // the directories we're currently inside, and whether anything
+        // has been found in each yet
+        let mut dirs: Vec<(usize, PathBuf, bool)> = Vec::new();
+
+        let root_device = if self.one_file_system {
+            fs::metadata(root).ok().as_ref().and_then(device)
+        } else {
+            None
+        };
+
+        // walkdir tracks the directories above each entry when following
+        // links and reports a loop as an error instead of descending.
+        // Sorting makes the order independent of the filesystem's
+        let mut it = WalkDir::new(root)
+            .follow_links(self.follow_symlinks)
+            .max_depth(self.max_depth.unwrap_or(usize::MAX))
+            .sort_by_file_name()
+            .into_iter();
+        while let Some(entry) = it.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.loop_ancestor().is_some() => {
+                    warn!("skipping symlink loop: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    let path = e.path().unwrap_or(Path::new(root)).to_path_buf();
+                    let e = match e.into_io_error() {
+                        Some(e) => e,
+                        None => io::Error::other("filesystem loop"),
+                    };
+                    on_error(path, e);
+                    continue;
+                }
+            };
+            let is_dir = entry.file_type().is_dir();
+
+            while let Some((_, path, has_entries)) =
+                dirs.pop_if(|(depth, _, _)| *depth >= entry.depth())
+            {
+                if !has_entries {
+                    on_empty_dir(path);
+                }
+            }
+
+            // the root was asked for explicitly, so it's never filtered
+            if entry.depth() > 0 {
+                gitignores.retain(|(depth, _)| *depth < entry.depth());
+
+                if self.is_excluded(entry.path()) || self.is_gitignored(&gitignores, &entry) {
+                    if is_dir {
+                        it.skip_current_dir();
+                    }
+                    continue;
+                }
+            }
+
+            if is_dir {
+                if let Some(store_dir) = self.store_dir(entry.path()) {
+                    warn!(
+                        "skipping '{}': it's in a server's store ({})",
+                        entry.path().display(),
+                        store_dir.display()
+                    );
+                    it.skip_current_dir();
+                    continue;
+                }
+                // metadata follows a symlink, so a followed link to another
+                // filesystem is caught too
+                let other_device = entry.depth() > 0
+                    && root_device.is_some_and(|root_device| {
+                        entry
+                            .metadata()
+                            .ok()
+                            .as_ref()
+                            .and_then(device)
+                            .is_some_and(|device| device != root_device)
+                    });
+                if other_device {
+                    warn!(
+                        "skipping '{}': it's on another filesystem",
+                        entry.path().display()
+                    );
+                    it.skip_current_dir();
+                } else if self.use_gitignore {
+                    let path = entry.path().join(".gitignore");
+                    if path.is_file() {
+                        let (gitignore, err) = Gitignore::new(&path);
+                        if let Some(e) = err {
+                            warn!("{}: {}", path.display(), e);
+                        }
+                        gitignores.push((entry.depth(), gitignore));
+                    }
+                }
+                if let Some((_, _, has_entries)) = dirs.last_mut() {
+                    *has_entries = true;
+                }
+                dirs.push((entry.depth(), entry.into_path(), false));
+                continue;
+            }
+
+            if !entry.file_type().is_symlink() || self.preserve_symlinks {
+                if let Some((_, _, has_entries)) = dirs.last_mut() {
+                    *has_entries = true;
+                }
+                f(entry);
+            }
+        }
+
+        for (_, path, has_entries) in dirs.into_iter().rev() {
+            if !has_entries {
+                on_empty_dir(path);
+            }
+        }
+    }
+
+    fn is_gitignored(&self, gitignores: &[(usize, Gitignore)], entry: &DirEntry) -> bool {
+        // the innermost .gitignore with an opinion wins, so a negated pattern
+        // in a subdirectory can re-include something a parent ignored
+        for (_, gitignore) in gitignores.iter().rev() {
+            let m = gitignore.matched(entry.path(), entry.file_type().is_dir());
+            if m.is_ignore() {
+                return true;
+            }
+            if m.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+}